@@ -0,0 +1,86 @@
+// Compares how long it takes to get a component instance ready to handle a request when it is
+// linked and instantiated from scratch, with the ProxyPre kept by the component registry.
+//
+//     cargo bench -p raikiri --bench invoke
+//     cargo bench -p raikiri --bench invoke -- --pooling-allocator
+use std::{future::Future, time::{Duration, Instant}};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use raikiri::{domain::{raikiri_env_component::{new_linker, RaikiriComponentStorage}, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_invoke::RaikiriEnvironmentInvoke}, raikiri_env::RaikiriEnvironment, ComponentImports, EngineSettings, Wasi};
+use wasmtime::Store;
+use wasmtime_wasi_http::bindings::Proxy;
+
+const ITERATIONS: u32 = 200;
+
+async fn bench<F, Fut>(name: &str, mut f: F) -> Duration
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = ()>
+{
+    // warm up caches and lazily initialized state before measuring
+    for _ in 0..10 {
+        f().await;
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f().await;
+    }
+    let per_iteration = start.elapsed() / ITERATIONS;
+    println!("{name:<32} {per_iteration:>12.2?} per iteration");
+    per_iteration
+}
+
+#[tokio::main]
+async fn main() {
+    let fs_root = format!("/tmp/raikiri-bench-{}", uuid::Uuid::new_v4());
+    let engine_settings = EngineSettings {
+        pooling_allocator: std::env::args().any(|arg| arg == "--pooling-allocator"),
+        ..Default::default()
+    };
+    let environment = RaikiriEnvironment::new()
+        .with_engine_settings(engine_settings)
+        .with_username("bench".to_string())
+        .with_fs_root(fs_root.clone());
+    environment.setup_fs().await.unwrap();
+    let component_bytes = tokio::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).await.unwrap();
+    environment.add_component("bench".to_string(), "hello".to_string(), component_bytes).await.unwrap();
+    let registered_component = environment.component_registry.get_entry(&"bench.hello".to_string()).await.unwrap();
+    let registered_component = registered_component.read().await;
+    let component = registered_component.component.clone();
+
+    let component_imports = ComponentImports {
+        call_stack: Vec::new(),
+        environment: environment.clone(),
+        db_connections: Default::default(),
+    };
+    let new_store = || {
+        let mut store = Store::new(&environment.wasm_engine, Wasi::new(component_imports.clone(), Vec::new()));
+        store.epoch_deadline_async_yield_and_update(1);
+        store
+    };
+
+    let from_scratch = bench("link and instantiate", || async {
+        let mut store = new_store();
+        let linker = new_linker(&environment.wasm_engine);
+        Proxy::instantiate_async(&mut store, &component, &linker).await.unwrap();
+    }).await;
+
+    let pre_instantiated = bench("instantiate ProxyPre", || async {
+        let mut store = new_store();
+        registered_component.proxy_pre.instantiate_async(&mut store).await.unwrap();
+    }).await;
+
+    bench("invoke_component", || async {
+        let request = http::Request::builder()
+            .uri("http://raikiri.components/bench.hello")
+            .body(Full::new(Bytes::new()).map_err(|never| match never {}))
+            .unwrap();
+        let wasi = Wasi::new(component_imports.clone(), Vec::new());
+        let response = environment.invoke_component("bench.hello".to_string(), request, wasi).await.unwrap();
+        response.resp.into_body().collect().await.unwrap();
+    }).await;
+
+    println!("ProxyPre is {:.1}x faster to instantiate", from_scratch.as_secs_f64() / pre_instantiated.as_secs_f64());
+    _ = tokio::fs::remove_dir_all(fs_root).await;
+}
@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// Builds the proxy variant of the WASI preview1 adapter, which is embedded in raikiri to turn
+// core wasm32-wasip1 modules into components when they are uploaded
+fn main() {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-changed=../wasi-preview1-component-adapter");
+
+    let mut cmd = cargo();
+    cmd.arg("build")
+        .arg("--release")
+        .arg("--package=wasi-preview1-component-adapter")
+        .arg("--target=wasm32-unknown-unknown")
+        .arg("--no-default-features")
+        .arg("--features=proxy")
+        .env("CARGO_TARGET_DIR", &out_dir)
+        .env_remove("CARGO_ENCODED_RUSTFLAGS");
+    eprintln!("running: {cmd:?}");
+    let status = cmd.status().unwrap();
+    assert!(status.success());
+
+    let artifact = out_dir
+        .join("wasm32-unknown-unknown")
+        .join("release")
+        .join("wasi_snapshot_preview1.wasm");
+    std::fs::copy(&artifact, out_dir.join("wasi_snapshot_preview1.proxy.wasm")).unwrap();
+}
+
+fn cargo() -> Command {
+    // Miri configures its own sysroot which we don't want to use, so remove
+    // miri's own wrappers around rustc to ensure that we're using the real
+    // rustc to build the adapter.
+    let mut cargo = Command::new("cargo");
+    if std::env::var("CARGO_CFG_MIRI").is_ok() {
+        cargo.env_remove("RUSTC");
+    }
+    // Wrappers such as clippy-driver under cargo clippy would lint the adapter with our lints,
+    // and fail the build on its warnings
+    cargo.env_remove("RUSTC_WRAPPER").env_remove("RUSTC_WORKSPACE_WRAPPER");
+    for (name, _) in std::env::vars_os() {
+        if name.to_str().is_some_and(|name| name.starts_with("CLIPPY_")) {
+            cargo.env_remove(name);
+        }
+    }
+    cargo
+}
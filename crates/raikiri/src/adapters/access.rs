@@ -0,0 +1,123 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+// Who can reach a component. Public components answer routed HTTP requests, internal ones are only
+// called by other components through raikiri.components and owner components only by components
+// of their own tenant. The owner can always run them with Invoke-Component.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Internal,
+    Owner,
+}
+
+impl Display for Visibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Visibility::Public => write!(f, "public"),
+            Visibility::Internal => write!(f, "internal"),
+            Visibility::Owner => write!(f, "owner"),
+        }
+    }
+}
+
+// The access file of a component:
+//
+//     visibility: internal
+//     allowed_callers:
+//       - acme.frontend
+//       - billing.*
+//
+// When allowed_callers is set, only the components it names, or every component of a tenant
+// with {tenant}.*, can call the component.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ComponentAccess {
+    pub visibility: Visibility,
+    pub allowed_callers: Vec<String>,
+}
+
+impl ComponentAccess {
+    pub fn from_yaml(yml_bytes: &[u8]) -> Result<ComponentAccess, ThreadSafeError> {
+        let content = YamlLoader::load_from_str(std::str::from_utf8(yml_bytes)?)?;
+        let Some(content) = content.first() else { return Ok(ComponentAccess::default()) };
+
+        let visibility = match &content["visibility"] {
+            Yaml::BadValue | Yaml::Null => Visibility::default(),
+            visibility => match visibility.as_str() {
+                Some("public") => Visibility::Public,
+                Some("internal") => Visibility::Internal,
+                Some("owner") => Visibility::Owner,
+                _ => return Err("visibility must be public, internal or owner".into())
+            }
+        };
+        let mut allowed_callers = Vec::new();
+        if let Some(callers) = content["allowed_callers"].as_vec() {
+            for caller in callers {
+                let caller = caller.as_str().ok_or("allowed_callers must be named user.component or user.*")?;
+                if caller.split_once('.').is_none_or(|(user, name)| user.is_empty() || name.is_empty()) {
+                    return Err(format!("invalid caller {caller}, expected user.component or user.*").into())
+                }
+                allowed_callers.push(caller.to_string());
+            }
+        }
+        Ok(ComponentAccess { visibility, allowed_callers })
+    }
+
+    // Routed requests come from outside of Raikiri
+    pub fn allows_http(&self) -> Result<(), String> {
+        match self.visibility {
+            Visibility::Public => Ok(()),
+            visibility => Err(format!("{visibility} components can't be reached over HTTP")),
+        }
+    }
+
+    // caller is the component calling this one through raikiri.components
+    pub fn allows_caller(&self, username_component_name: &str, caller: &str) -> Result<(), String> {
+        let tenant = |name: &str| name.split_once('.').map(|(user, _)| user.to_string());
+        if self.visibility == Visibility::Owner && tenant(caller) != tenant(username_component_name) {
+            return Err(format!("{username_component_name} can only be called by components of its owner"))
+        }
+        let allowed = self.allowed_callers.is_empty() || self.allowed_callers.iter().any(|allowed| match allowed.strip_suffix(".*") {
+            Some(user) => tenant(caller).as_deref() == Some(user),
+            None => allowed == caller,
+        });
+        match allowed {
+            true => Ok(()),
+            false => Err(format!("{caller} is not an allowed caller of {username_component_name}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ComponentAccess, Visibility};
+
+    #[test]
+    fn test_component_access() {
+        let access = ComponentAccess::from_yaml(b"visibility: owner\nallowed_callers: [acme.frontend, billing.*]").unwrap();
+        assert_eq!(access.visibility, Visibility::Owner);
+        assert!(access.allows_http().is_err());
+        assert!(access.allows_caller("acme.users", "acme.frontend").is_ok());
+        assert!(access.allows_caller("acme.users", "acme.reports").is_err());
+        // owner components are only called by their tenant, even when allowed
+        assert!(access.allows_caller("acme.users", "billing.invoices").is_err());
+
+        let access = ComponentAccess::from_yaml(b"visibility: internal\nallowed_callers: [billing.*]").unwrap();
+        assert!(access.allows_caller("acme.users", "billing.invoices").is_ok());
+        assert!(access.allows_caller("acme.users", "billingx.invoices").is_err());
+
+        let access = ComponentAccess::from_yaml(b"").unwrap();
+        assert_eq!(access, ComponentAccess::default());
+        assert!(access.allows_http().is_ok());
+        assert!(access.allows_caller("acme.users", "other.component").is_ok());
+
+        assert!(ComponentAccess::from_yaml(b"visibility: secret").is_err());
+        assert!(ComponentAccess::from_yaml(b"allowed_callers: [frontend]").is_err());
+    }
+}
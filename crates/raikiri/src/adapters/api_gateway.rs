@@ -0,0 +1,240 @@
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+use super::{conf_file::RouteConf, router::Router};
+
+// An API gateway definition, the same YAML file that is uploaded with `cloud create-api-gateway`.
+//
+// version is not part of the file, it is given when the gateway is created so the same file
+// can be promoted between versions.
+#[derive(Clone, Debug)]
+pub struct ApiGateway {
+    pub version: i32,
+    pub router: Router,
+    pub routes: Vec<ApiGatewayRoute>,
+    pub cors: Option<CorsConf>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ApiGatewayRoute {
+    pub request_headers: HeaderRewrite,
+    pub response_headers: HeaderRewrite,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRewrite {
+    pub set: Vec<(HeaderName, HeaderValue)>,
+    pub remove: Vec<HeaderName>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CorsConf {
+    pub allow_origins: Vec<String>,
+    pub allow_methods: Vec<String>,
+    pub allow_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<i64>,
+}
+
+impl ApiGateway {
+    pub fn from_yaml(version: i32, yml_bytes: &[u8]) -> Result<ApiGateway, ThreadSafeError> {
+        let content = YamlLoader::load_from_str(std::str::from_utf8(yml_bytes)?)?;
+        let content = content.first().ok_or("api gateway file is empty")?;
+
+        let mut route_confs = Vec::new();
+        let mut routes = Vec::new();
+        for route in content["routes"].as_vec().ok_or("api gateway is missing routes")? {
+            route_confs.push(RouteConf::from_yaml(route)?);
+            routes.push(ApiGatewayRoute {
+                request_headers: HeaderRewrite::from_yaml(&route["request_headers"])?,
+                response_headers: HeaderRewrite::from_yaml(&route["response_headers"])?,
+            });
+        }
+
+        let cors = match &content["cors"] {
+            Yaml::BadValue | Yaml::Null => None,
+            cors => Some(CorsConf::from_yaml(cors)),
+        };
+
+        Ok(ApiGateway {
+            version,
+            router: Router::new(&route_confs)?,
+            routes,
+            cors,
+        })
+    }
+}
+
+impl HeaderRewrite {
+    pub fn from_yaml(yaml: &Yaml) -> Result<HeaderRewrite, ThreadSafeError> {
+        let mut rewrite = HeaderRewrite::default();
+        if let Some(set) = yaml["set"].as_hash() {
+            for (k, v) in set {
+                let value = match v {
+                    Yaml::Integer(i) => i.to_string(),
+                    v => v.as_str().ok_or("header values must be strings")?.to_string(),
+                };
+                rewrite.set.push((
+                    HeaderName::from_bytes(k.as_str().ok_or("header names must be strings")?.as_bytes())?,
+                    HeaderValue::from_str(&value)?,
+                ));
+            }
+        }
+        for name in yaml_strings(&yaml["remove"]) {
+            rewrite.remove.push(HeaderName::from_bytes(name.as_bytes())?);
+        }
+        Ok(rewrite)
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+impl CorsConf {
+    pub fn from_yaml(yaml: &Yaml) -> CorsConf {
+        CorsConf {
+            allow_origins: yaml_strings(&yaml["allow_origins"]),
+            allow_methods: yaml_strings(&yaml["allow_methods"]),
+            allow_headers: yaml_strings(&yaml["allow_headers"]),
+            expose_headers: yaml_strings(&yaml["expose_headers"]),
+            allow_credentials: yaml["allow_credentials"].as_bool().unwrap_or(false),
+            max_age: yaml["max_age"].as_i64(),
+        }
+    }
+
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.allow_origins.iter().any(|o| o == origin) {
+            Some(origin.to_string())
+        }
+        else if self.allow_origins.iter().any(|o| o == "*") {
+            // credentials can't be combined with a wildcard origin, so echo the caller back
+            Some(if self.allow_credentials { origin.to_string() } else { "*".to_string() })
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS
+            && headers.contains_key(header::ORIGIN)
+            && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    // Adds the headers shared by preflight and actual responses. Returns false when the origin is not allowed.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) -> bool {
+        let Some(origin) = origin.and_then(|origin| origin.to_str().ok()) else { return true };
+        let Some(allowed_origin) = self.allowed_origin(origin) else { return false };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_str(&allowed_origin).unwrap());
+        if allowed_origin != "*" {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.allow_credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if !self.expose_headers.is_empty() {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_str(&self.expose_headers.join(", ")).unwrap());
+        }
+        true
+    }
+
+    pub fn apply_preflight(&self, request_headers: &HeaderMap, headers: &mut HeaderMap) -> bool {
+        if !self.apply(request_headers.get(header::ORIGIN), headers) { return false }
+        let allow_methods = match self.allow_methods.is_empty() {
+            true => "GET, HEAD, PUT, PATCH, POST, DELETE".to_string(),
+            false => self.allow_methods.join(", ")
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_str(&allow_methods).unwrap());
+        if !self.allow_headers.is_empty() {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_str(&self.allow_headers.join(", ")).unwrap());
+        }
+        else if let Some(requested) = request_headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        true
+    }
+}
+
+fn yaml_strings(yaml: &Yaml) -> Vec<String> {
+    match yaml {
+        Yaml::Array(values) => values.iter().filter_map(|v| v.as_str().map(|v| v.to_string())).collect(),
+        Yaml::String(value) => vec![value.clone()],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, HeaderMap, HeaderValue, Method};
+
+    use super::{ApiGateway, CorsConf};
+
+    static GATEWAY_YAML: &str = "
+cors:
+  allow_origins: [https://app.example.com]
+  allow_methods: [GET, POST]
+  allow_credentials: true
+  max_age: 600
+routes:
+  - path: /users/:id
+    methods: [GET, POST]
+    component: test.users
+    request_headers:
+      set:
+        X-Gateway-Version: 3
+      remove: [Cookie]
+    response_headers:
+      remove: [Server]
+";
+
+    #[test]
+    fn test_parse_api_gateway() {
+        let api_gateway = ApiGateway::from_yaml(3, GATEWAY_YAML.as_bytes()).unwrap();
+        assert_eq!(api_gateway.version, 3);
+        assert_eq!(api_gateway.routes.len(), 1);
+
+        let found = api_gateway.router.match_request(&Method::POST, &"/users/1".parse().unwrap(), None).unwrap();
+        assert_eq!(found.username_component_name, "test.users");
+        assert!(api_gateway.router.match_request(&Method::DELETE, &"/users/1".parse().unwrap(), None).is_none());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("session=1"));
+        api_gateway.routes[found.index].request_headers.apply(&mut headers);
+        assert!(headers.get(header::COOKIE).is_none());
+        assert_eq!(headers.get("x-gateway-version").unwrap(), "3");
+    }
+
+    #[test]
+    fn test_cors() {
+        let api_gateway = ApiGateway::from_yaml(1, GATEWAY_YAML.as_bytes()).unwrap();
+        let cors = api_gateway.cors.unwrap();
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::ORIGIN, HeaderValue::from_static("https://app.example.com"));
+        request_headers.insert(header::ACCESS_CONTROL_REQUEST_METHOD, HeaderValue::from_static("POST"));
+        assert!(CorsConf::is_preflight(&Method::OPTIONS, &request_headers));
+
+        let mut headers = HeaderMap::new();
+        assert!(cors.apply_preflight(&request_headers, &mut headers));
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET, POST");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+        let mut headers = HeaderMap::new();
+        assert!(!cors.apply(Some(&HeaderValue::from_static("https://evil.example.com")), &mut headers));
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+}
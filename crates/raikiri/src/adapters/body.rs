@@ -0,0 +1,115 @@
+use std::{pin::Pin, task::{Context, Poll}};
+
+use http::{header, HeaderMap};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use yaml_rust2::Yaml;
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+// Maximum sizes in bytes for the bodies streamed to and from components, None means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BodyLimits {
+    pub max_request_body_size: Option<u64>,
+    pub max_response_body_size: Option<u64>,
+}
+
+impl BodyLimits {
+    pub fn new(max_request_body_size: Option<u64>, max_response_body_size: Option<u64>) -> BodyLimits {
+        BodyLimits { max_request_body_size, max_response_body_size }
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> Result<BodyLimits, ThreadSafeError> {
+        let size = |name: &str| match &yaml[name] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::Integer(size) if *size >= 0 => Ok(Some(*size as u64)),
+            _ => Err(format!("{name} must be a positive number of bytes")),
+        };
+        Ok(BodyLimits {
+            max_request_body_size: size("max_request_body_size")?,
+            max_response_body_size: size("max_response_body_size")?,
+        })
+    }
+
+    // Lets a request be rejected before it is streamed when it announces a body over the limit
+    pub fn request_too_large(&self, headers: &HeaderMap) -> bool {
+        let Some(limit) = self.max_request_body_size else { return false };
+        headers.get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok())
+            .is_some_and(|length| length > limit)
+    }
+}
+
+// Passes frames through as they are polled, so the reader's pace is what pulls data from the
+// writer. Fails with the given error code as soon as more than limit bytes went through.
+pub struct LimitedBody<B> {
+    inner: B,
+    limit: Option<u64>,
+    read: u64,
+    error: fn(Option<u64>) -> ErrorCode,
+}
+
+impl<B> LimitedBody<B> {
+    pub fn new(inner: B, limit: Option<u64>, error: fn(Option<u64>) -> ErrorCode) -> LimitedBody<B> {
+        LimitedBody { inner, limit, read: 0, error }
+    }
+}
+
+impl<B> Body for LimitedBody<B>
+    where
+        B: Body<Data = Bytes, Error = ErrorCode> + Unpin
+{
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, ErrorCode>>> {
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            other => return other,
+        };
+        if let (Some(data), Some(limit)) = (frame.data_ref(), self.limit) {
+            self.read += data.len() as u64;
+            if self.read > limit {
+                return Poll::Ready(Some(Err((self.error)(Some(limit)))))
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, HeaderMap, HeaderValue};
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+    use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+
+    use super::{BodyLimits, LimitedBody};
+
+    #[tokio::test]
+    async fn test_limited_body() {
+        let body = || Full::new(Bytes::from_static(b"hello, world!")).map_err(|never| match never {});
+
+        let collected = LimitedBody::new(body(), Some(13), ErrorCode::HttpResponseBodySize).collect().await;
+        assert_eq!(collected.unwrap().to_bytes(), "hello, world!");
+
+        let collected = LimitedBody::new(body(), Some(12), ErrorCode::HttpResponseBodySize).collect().await;
+        assert!(matches!(collected, Err(ErrorCode::HttpResponseBodySize(Some(12)))));
+
+        let limits = BodyLimits::new(Some(12), None);
+        let mut headers = HeaderMap::new();
+        assert!(!limits.request_too_large(&headers));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(13));
+        assert!(limits.request_too_large(&headers));
+        assert!(!BodyLimits::default().request_too_large(&headers));
+    }
+}
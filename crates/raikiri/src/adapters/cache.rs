@@ -64,6 +64,32 @@ impl<K, V> Cache<K, V>
         }
     }
 
+    // Points key to a new entry. Threads already holding the previous entry keep using it
+    // until they drop it, the next lookups get the new one.
+    #[allow(dead_code)]
+    pub async fn replace_entry(&self, key: K, value: V) -> Arc<RwLock<V>> {
+        let entry = Arc::new(RwLock::new(value));
+        let mut write_hs = self.hashes.write().await;
+        (*write_hs).insert(key, entry.clone());
+        entry
+    }
+
+    // Drops key without waiting for the threads holding its entry, the next lookup builds it again
+    #[allow(dead_code)]
+    pub async fn invalidate(&self, key: &K) {
+        self.hashes.write().await.remove(key);
+    }
+
+    #[allow(dead_code)]
+    pub async fn get_entry(&self, key: &K) -> Option<Arc<RwLock<V>>> {
+        self.hashes.read().await.get(key).cloned()
+    }
+
+    #[allow(dead_code)]
+    pub async fn keys(&self) -> Vec<K> where K: Clone {
+        self.hashes.read().await.keys().cloned().collect()
+    }
+
     // It's Gracefully because if other thread still awaits for entry's write/read,
     // the entry neither the inside value are disposed, and the dispose only happens when the last thread disposes (Arc behavior).
 
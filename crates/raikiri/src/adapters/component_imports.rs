@@ -1,13 +1,11 @@
 use std::{collections::HashMap, sync::Arc};
 
-use futures::stream;
 use http::HeaderValue;
-use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
-use hyper::body::{Bytes, Frame};
+use http_body_util::BodyExt;
 use tokio::sync::RwLock;
 use wasmtime_wasi_http::types::HostFutureIncomingResponse;
 
-use crate::domain::{raikiri_env::RaikiriEnvironment, raikiri_env_db::{RaikiriDBConnection, RaikiriDBConnectionKind, RaikiriEnvironmentDB}, raikiri_env_invoke::{build_response, RaikiriEnvironmentInvoke}, raikiri_env_secrets::RaikiriEnvironmentSecrets};
+use crate::domain::{raikiri_env::RaikiriEnvironment, raikiri_env_access::RaikiriEnvironmentAccess, raikiri_env_db::{RaikiriDBConnection, RaikiriDBConnectionKind, RaikiriEnvironmentDB}, raikiri_env_invoke::{build_response, RaikiriEnvironmentInvoke}, raikiri_env_secrets::RaikiriEnvironmentSecrets};
 
 use super::{context::RaikiriContext, wasi_view::Wasi};
 
@@ -34,24 +32,21 @@ impl RaikiriContext for ComponentImports {
             "raikiri.components" => {
                 let data = self.clone();
                 let username_component_name = request.uri().path().replace("/", "");
+                // the outgoing body is handed to the callee as it is written, without buffering it
                 let future_handle = wasmtime_wasi::runtime::spawn(async move {
-                    let mut request_builder = hyper::Request::builder()
-                        .uri(request.uri());
-                    for (key, value) in request.headers() {
-                        request_builder = request_builder.header(key, value);
+                    let caller = data.call_stack().last().cloned().unwrap_or_default();
+                    if let Err(reason) = data.environment.check_component_access(&username_component_name, Some(&caller)).await {
+                        return Ok(Ok(build_response(403, &reason).await))
                     }
-                    let body = request.into_body().collect().await.unwrap().to_bytes().to_vec();
-                    let request = request_builder.body(BoxBody::new(StreamBody::new(stream::iter(
-                        body.chunks(16 * 1024)
-                            .map(|chunk| Ok::<_, hyper::Error>(Frame::data(Bytes::copy_from_slice(chunk))))
-                            .collect::<Vec<_>>()
-                    )))).unwrap();
                     let secrets_entry = &data.environment.secrets_cache.get_entry_by_key_async_build(username_component_name.clone(), async {
                         let (username, component_name) = username_component_name.split_once('.').unwrap();
                         data.environment.get_component_secrets(username.to_string(), component_name.to_string()).await.unwrap_or_else(|_| Vec::new())
                     }).await;
                     let secrets = secrets_entry.read().await;
-                    let wasi = Wasi::new(data.clone(), secrets.to_vec());
+                    // the callee sees itself on top of the stack, so its own calls are checked against it
+                    let mut call_stack = data.call_stack.clone();
+                    call_stack.push(username_component_name.clone());
+                    let wasi = Wasi::new(ComponentImports { call_stack, ..data.clone() }, secrets.to_vec());
                     Ok(data.environment.invoke_component(username_component_name, request, wasi).await)
                 });
                 Ok(HostFutureIncomingResponse::Pending(future_handle))
@@ -0,0 +1,20 @@
+use wasmparser::Parser;
+use wit_component::ComponentEncoder;
+
+use super::validation::ComponentValidationError;
+
+// The proxy variant of the WASI preview1 adapter, built from crates/wasi-preview1-component-adapter
+pub const PROXY_ADAPTER: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/wasi_snapshot_preview1.proxy.wasm"));
+
+// Components are returned as they are. Core modules, as built for wasm32-wasip1 without
+// cargo component, are wrapped into a component with the proxy adapter providing their WASI imports.
+pub fn componentize(wasm: Vec<u8>) -> Result<Vec<u8>, ComponentValidationError> {
+    if !Parser::is_core_wasm(&wasm) {
+        return Ok(wasm)
+    }
+    ComponentEncoder::default()
+        .module(&wasm)
+        .and_then(|encoder| encoder.validate(true).adapter("wasi_snapshot_preview1", PROXY_ADAPTER))
+        .and_then(|mut encoder| encoder.encode())
+        .map_err(|e| ComponentValidationError::Invalid(format!("could not turn the core module into a component: {e:#}")))
+}
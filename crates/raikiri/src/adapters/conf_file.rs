@@ -5,12 +5,21 @@ use yaml_rust2::Yaml;
 
 use crate::domain::raikiri_env::ThreadSafeError;
 
+use super::{body::BodyLimits, crypto::SecretsConf, engine::EngineSettings, limits::LimitsConf, storage::StorageConf, tls::TlsConf};
+
 static CONF_FILE_PATH: &str = "raikiri.yaml";
 
 #[derive(Clone)]
 pub struct ConfFile {
     pub components: HashMap<String, String>,
     pub run_confs: HashMap<String, RunConf>,
+    pub routes: Vec<RouteConf>,
+    pub tls: Option<TlsConf>,
+    pub body_limits: BodyLimits,
+    pub limits: LimitsConf,
+    pub engine: EngineSettings,
+    pub storage: StorageConf,
+    pub secrets: SecretsConf,
 }
 
 impl ConfFile {
@@ -23,6 +32,13 @@ impl ConfFile {
             Err(_) => return Ok(Self {
                 components: HashMap::new(),
                 run_confs: HashMap::new(),
+                routes: Vec::new(),
+                tls: None,
+                body_limits: BodyLimits::default(),
+                limits: LimitsConf::default(),
+                engine: EngineSettings::default(),
+                storage: StorageConf::default(),
+                secrets: SecretsConf::default(),
             })
         };
         let content = yaml_rust2::YamlLoader::load_from_str(&content)?;
@@ -32,8 +48,15 @@ impl ConfFile {
 
         let yaml_str = |arg: &'static str| Yaml::String(arg.to_string());
 
-        let file_components = content.get(&yaml_str("components")).unwrap().as_hash().unwrap();
-        let file_run_confs = content.get(&yaml_str("run")).unwrap().as_hash().unwrap();
+        let empty_hash = Default::default();
+        let file_components = content.get(&yaml_str("components")).and_then(|v| v.as_hash()).unwrap_or(&empty_hash);
+        let file_run_confs = content.get(&yaml_str("run")).and_then(|v| v.as_hash()).unwrap_or(&empty_hash);
+        let file_routes = content.get(&yaml_str("routes")).and_then(|v| v.as_vec()).cloned().unwrap_or_default();
+        let file_server = content.get(&yaml_str("server")).cloned().unwrap_or(Yaml::Null);
+        let file_limits = content.get(&yaml_str("limits")).cloned().unwrap_or(Yaml::Null);
+        let file_engine = content.get(&yaml_str("engine")).cloned().unwrap_or(Yaml::Null);
+        let file_storage = content.get(&yaml_str("storage")).cloned().unwrap_or(Yaml::Null);
+        let file_secrets = content.get(&yaml_str("secrets")).cloned().unwrap_or(Yaml::Null);
 
         let mut components = HashMap::new();
         for (k, v) in file_components.iter() {
@@ -52,9 +75,21 @@ impl ConfFile {
             });
         }
 
+        let mut routes = Vec::new();
+        for route in file_routes.iter() {
+            routes.push(RouteConf::from_yaml(route)?);
+        }
+
         Ok(ConfFile {
             components,
             run_confs,
+            routes,
+            tls: TlsConf::from_yaml(&file_server["tls"])?,
+            body_limits: BodyLimits::from_yaml(&file_server)?,
+            limits: LimitsConf::from_yaml(&file_limits)?,
+            engine: EngineSettings::from_yaml(&file_engine)?,
+            storage: StorageConf::from_yaml(&file_storage)?,
+            secrets: SecretsConf::from_yaml(&file_secrets)?,
         })
     }
 }
@@ -66,3 +101,27 @@ pub struct RunConf {
     pub headers: LinkedHashMap<Yaml, Yaml>,
     pub body: String
 }
+
+#[derive(Clone, Debug)]
+pub struct RouteConf {
+    pub path: String,
+    pub component: String,
+    pub methods: Vec<String>,
+    pub host: Option<String>,
+}
+
+impl RouteConf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<RouteConf, ThreadSafeError> {
+        let field = |name: &str| yaml[name].as_str().map(|v| v.to_string());
+        let methods = match yaml["methods"].as_vec() {
+            Some(methods) => methods.iter().filter_map(|method| method.as_str().map(|m| m.to_string())).collect(),
+            None => field("method").into_iter().collect()
+        };
+        Ok(RouteConf {
+            path: field("path").ok_or("route is missing path")?,
+            component: field("component").ok_or("route is missing component")?,
+            methods,
+            host: field("host"),
+        })
+    }
+}
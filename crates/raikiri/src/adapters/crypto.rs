@@ -0,0 +1,167 @@
+use std::{fmt::Display, str::FromStr};
+
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use yaml_rust2::Yaml;
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+use super::keys::KeyProviderConf;
+
+// Encrypted secrets start with a header naming how they were encrypted:
+//
+//     RKS | version (1) | cipher suite | nonce (12 bytes) | ciphertext | tag (16 bytes)
+//
+// The header and the name of what is encrypted, such as user.component, are authenticated
+// along with the ciphertext, so a file can't be altered or swapped for another one.
+// Files without the header are the AES-256-CBC files written by earlier versions.
+static MAGIC: &[u8] = b"RKS";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CipherSuite {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    fn id(&self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<CipherSuite, ThreadSafeError> {
+        match id {
+            1 => Ok(CipherSuite::Aes256Gcm),
+            2 => Ok(CipherSuite::ChaCha20Poly1305),
+            _ => Err(format!("unknown cipher suite {id}").into())
+        }
+    }
+
+    fn cipher(&self) -> Cipher {
+        match self {
+            CipherSuite::Aes256Gcm => Cipher::aes_256_gcm(),
+            CipherSuite::ChaCha20Poly1305 => Cipher::chacha20_poly1305(),
+        }
+    }
+}
+
+impl Display for CipherSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherSuite::Aes256Gcm => write!(f, "aes-256-gcm"),
+            CipherSuite::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+impl FromStr for CipherSuite {
+    type Err = ThreadSafeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(CipherSuite::Aes256Gcm),
+            "chacha20-poly1305" => Ok(CipherSuite::ChaCha20Poly1305),
+            _ => Err(format!("unknown cipher suite {s}, expected aes-256-gcm or chacha20-poly1305").into())
+        }
+    }
+}
+
+// The secrets section of raikiri.yaml
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SecretsConf {
+    pub cipher: CipherSuite,
+    pub keys: KeyProviderConf,
+}
+
+impl SecretsConf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<SecretsConf, ThreadSafeError> {
+        let cipher = match &yaml["cipher"] {
+            Yaml::BadValue | Yaml::Null => CipherSuite::default(),
+            cipher => cipher.as_str().ok_or("secrets cipher must be a string")?.parse()?,
+        };
+        Ok(SecretsConf { cipher, keys: KeyProviderConf::from_yaml(&yaml["keys"])? })
+    }
+}
+
+pub fn encrypt(suite: CipherSuite, key: &[u8], context: &str, plaintext: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+    let mut nonce = [0; NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce)?;
+
+    let mut encrypted = Vec::with_capacity(HEADER_LEN + NONCE_LEN + plaintext.len() + TAG_LEN);
+    encrypted.extend_from_slice(MAGIC);
+    encrypted.extend_from_slice(&[VERSION, suite.id()]);
+    encrypted.extend_from_slice(&nonce);
+
+    let aad = [&encrypted[..HEADER_LEN], context.as_bytes()].concat();
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(suite.cipher(), key, Some(&nonce), &aad, plaintext, &mut tag)?;
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted.extend_from_slice(&tag);
+    Ok(encrypted)
+}
+
+// Returns the plaintext and the suite it was encrypted with, None for the legacy CBC files
+pub fn decrypt(key: &[u8], context: &str, encrypted: &[u8]) -> Result<(Vec<u8>, Option<CipherSuite>), ThreadSafeError> {
+    if !encrypted.starts_with(MAGIC) {
+        let decrypted = openssl::symm::decrypt(Cipher::aes_256_cbc(), key, None, encrypted)?;
+        return Ok((decrypted, None))
+    }
+    if encrypted.len() < HEADER_LEN + NONCE_LEN + TAG_LEN {
+        return Err("encrypted secrets are truncated".into())
+    }
+    if encrypted[MAGIC.len()] != VERSION {
+        return Err(format!("unsupported encrypted secrets version {}", encrypted[MAGIC.len()]).into())
+    }
+    let suite = CipherSuite::from_id(encrypted[MAGIC.len() + 1])?;
+    let (header, rest) = encrypted.split_at(HEADER_LEN);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    let aad = [header, context.as_bytes()].concat();
+    let decrypted = decrypt_aead(suite.cipher(), key, Some(nonce), &aad, ciphertext, tag)
+        .map_err(|_| "could not decrypt secrets, the key is wrong or they were altered")?;
+    Ok((decrypted, Some(suite)))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::{decrypt, encrypt, CipherSuite, SecretsConf};
+
+    #[test]
+    fn test_encrypt_secrets() {
+        let key = [7; 32];
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305] {
+            let encrypted = encrypt(suite, &key, "test.hello", b"KEY: value").unwrap();
+            assert_eq!(decrypt(&key, "test.hello", &encrypted).unwrap(), (b"KEY: value".to_vec(), Some(suite)));
+
+            // a random nonce per file
+            assert_ne!(encrypted, encrypt(suite, &key, "test.hello", b"KEY: value").unwrap());
+
+            let mut altered = encrypted.clone();
+            *altered.last_mut().unwrap() ^= 1;
+            assert!(decrypt(&key, "test.hello", &altered).is_err());
+            assert!(decrypt(&key, "test.other", &encrypted).is_err());
+            assert!(decrypt(&[8; 32], "test.hello", &encrypted).is_err());
+        }
+
+        let legacy = openssl::symm::encrypt(Cipher::aes_256_cbc(), &key, None, b"KEY: value").unwrap();
+        assert_eq!(decrypt(&key, "test.hello", &legacy).unwrap(), (b"KEY: value".to_vec(), None));
+    }
+
+    #[test]
+    fn test_parse_secrets_conf() {
+        let yaml = &yaml_rust2::YamlLoader::load_from_str("cipher: chacha20-poly1305").unwrap()[0];
+        assert_eq!(SecretsConf::from_yaml(yaml).unwrap().cipher, CipherSuite::ChaCha20Poly1305);
+        assert_eq!(SecretsConf::from_yaml(&yaml_rust2::Yaml::Null).unwrap(), SecretsConf::default());
+        let yaml = &yaml_rust2::YamlLoader::load_from_str("cipher: aes-256-cbc").unwrap()[0];
+        assert!(SecretsConf::from_yaml(yaml).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::hash::{Hash, Hasher};
+
+use openssl::sha::Sha256;
+use wasmtime::{component::Component, Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig, WasmBacktraceDetails};
+use yaml_rust2::Yaml;
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+use super::limits::spawn_epoch_ticker;
+
+// Everything the shared Engine is configured with. Components are compiled and deserialized with
+// this engine, and code is kept per engine fingerprint: after a setting that affects code generation
+// changes, components are compiled again from blobs/{sha256}/source.wasm when they are next loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EngineSettings {
+    pub wasm_backtraces: bool,
+    pub simd: bool,
+    pub cache: bool,
+    // wasmtime cache config file, where the cache directory is set. The default location is used when None
+    pub cache_config: Option<String>,
+    pub pooling_allocator: bool,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        EngineSettings {
+            wasm_backtraces: true,
+            simd: true,
+            cache: true,
+            cache_config: None,
+            pooling_allocator: false,
+        }
+    }
+}
+
+impl EngineSettings {
+    pub fn from_yaml(yaml: &Yaml) -> Result<EngineSettings, ThreadSafeError> {
+        let defaults = EngineSettings::default();
+        let flag = |name: &str, default: bool| match &yaml[name] {
+            Yaml::BadValue | Yaml::Null => Ok(default),
+            Yaml::Boolean(value) => Ok(*value),
+            _ => Err(format!("engine setting {name} must be true or false")),
+        };
+        Ok(EngineSettings {
+            wasm_backtraces: flag("wasm_backtraces", defaults.wasm_backtraces)?,
+            simd: flag("simd", defaults.simd)?,
+            cache: flag("cache", defaults.cache)?,
+            cache_config: yaml["cache_config"].as_str().map(|path| path.to_string()),
+            pooling_allocator: flag("pooling_allocator", defaults.pooling_allocator)?,
+        })
+    }
+
+    pub fn config(&self) -> Result<Config, ThreadSafeError> {
+        let mut config = Config::new();
+        match (&self.cache_config, self.cache) {
+            (Some(cache_config), true) => { config.cache_config_load(cache_config)?; }
+            (None, true) => { config.cache_config_load_default()?; }
+            (_, false) => (),
+        }
+        config.wasm_backtrace_details(match self.wasm_backtraces {
+            true => WasmBacktraceDetails::Enable,
+            false => WasmBacktraceDetails::Disable,
+        });
+        config.wasm_simd(self.simd);
+        config.wasm_relaxed_simd(self.simd);
+        config.wasm_component_model(true);
+        config.async_support(true);
+        config.epoch_interruption(true);
+        // instances are then taken from slots reserved up front instead of being allocated per request
+        if self.pooling_allocator {
+            config.allocation_strategy(InstanceAllocationStrategy::Pooling(PoolingAllocationConfig::default()));
+        }
+        Ok(config)
+    }
+
+    pub fn build_engine(&self) -> Result<Engine, ThreadSafeError> {
+        let engine = Engine::new(&self.config()?)?;
+        spawn_epoch_ticker(&engine);
+        Ok(engine)
+    }
+}
+
+// Compiling takes from milliseconds to seconds of CPU, it runs on the blocking pool so that
+// requests handled by the same runtime are not held up meanwhile
+pub async fn compile_component(engine: &Engine, component_bytes: Vec<u8>) -> wasmtime::Result<Component> {
+    let engine = engine.clone();
+    tokio::task::spawn_blocking(move || Component::from_binary(&engine, &component_bytes)).await?
+}
+
+// Engines with the same fingerprint run each other's compiled code. It changes with the wasmtime
+// version and with any setting that affects code generation.
+pub fn engine_fingerprint(engine: &Engine) -> String {
+    let mut hasher = Sha256Hasher(Sha256::new());
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hasher.0.finish()[..8].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    // only the SHA-256 is used
+    fn finish(&self) -> u64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yaml_rust2::YamlLoader;
+
+    use super::{engine_fingerprint, EngineSettings};
+
+    #[test]
+    fn test_parse_engine_settings() {
+        let yaml = YamlLoader::load_from_str("
+simd: false
+cache: false
+pooling_allocator: true
+").unwrap();
+        let settings = EngineSettings::from_yaml(&yaml[0]).unwrap();
+
+        assert_eq!(settings, EngineSettings {
+            simd: false,
+            cache: false,
+            pooling_allocator: true,
+            ..Default::default()
+        });
+        assert!(settings.build_engine().is_ok());
+        assert_eq!(EngineSettings::from_yaml(&yaml_rust2::Yaml::Null).unwrap(), EngineSettings::default());
+
+        let engine = settings.build_engine().unwrap();
+        let simd_engine = EngineSettings { simd: true, ..settings.clone() }.build_engine().unwrap();
+        assert_eq!(engine_fingerprint(&engine), engine_fingerprint(&settings.build_engine().unwrap()));
+        assert_ne!(engine_fingerprint(&engine), engine_fingerprint(&simd_engine));
+    }
+}
@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use openssl::base64;
+use yaml_rust2::Yaml;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_secrets::RaikiriKeyProvider};
+
+use super::{wrapped_key, wrapped_payload, KMS_PROVIDER};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KmsConf {
+    pub endpoint: String,
+    // the master key data keys are wrapped with, it never leaves the KMS
+    pub key_id: String,
+    pub token: Option<String>,
+}
+
+impl KmsConf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<KmsConf, ThreadSafeError> {
+        let field = |name: &str| yaml[name].as_str().map(|v| v.to_string());
+        Ok(KmsConf {
+            endpoint: field("endpoint").ok_or("kms key provider is missing endpoint")?.trim_end_matches('/').to_string(),
+            key_id: field("key_id").ok_or("kms key provider is missing key_id")?,
+            token: field("token").or_else(|| std::env::var("RAIKIRI_KMS_TOKEN").ok()),
+        })
+    }
+}
+
+// Data keys are wrapped by a KMS reached over HTTP, with JSON bodies and base64 keys:
+//
+//     POST {endpoint}/encrypt  {"key_id": ..., "plaintext": ...}   ->  {"ciphertext": ...}
+//     POST {endpoint}/decrypt  {"key_id": ..., "ciphertext": ...}  ->  {"plaintext": ...}
+//
+// The token, when set, is sent as Authorization: Bearer {token}.
+pub struct KmsKeyProvider {
+    conf: KmsConf,
+    client: reqwest::Client,
+}
+
+impl KmsKeyProvider {
+    pub fn new(conf: KmsConf) -> KmsKeyProvider {
+        KmsKeyProvider { conf, client: reqwest::Client::new() }
+    }
+
+    async fn call(&self, operation: &str, field: &str, value: &[u8], answer: &str) -> Result<Vec<u8>, ThreadSafeError> {
+        let mut request = self.client.post(format!("{}/{operation}", self.conf.endpoint))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&serde_json::json!({ "key_id": self.conf.key_id, field: base64::encode_block(value) }))?);
+        if let Some(token) = &self.conf.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(format!("kms {operation} failed with {}: {}", response.status(), response.text().await.unwrap_or_default()).into())
+        }
+        let body: serde_json::Value = serde_json::from_slice(&response.bytes().await?)?;
+        let answer = body[answer].as_str().ok_or(format!("kms {operation} answered without {answer}"))?;
+        Ok(base64::decode_block(answer)?)
+    }
+}
+
+#[async_trait]
+impl RaikiriKeyProvider for KmsKeyProvider {
+    async fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        let ciphertext = self.call("encrypt", "plaintext", data_key, "ciphertext").await?;
+        Ok(wrapped_key(KMS_PROVIDER, &ciphertext))
+    }
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        let ciphertext = wrapped_payload(wrapped, KMS_PROVIDER)?;
+        self.call("decrypt", "ciphertext", ciphertext, "plaintext").await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::{body::{Bytes, Incoming}, service::service_fn, Request, Response, StatusCode};
+    use openssl::base64;
+    use wasmtime_wasi_http::io::TokioIo;
+
+    use crate::{adapters::crypto::{self, CipherSuite}, domain::raikiri_env_secrets::RaikiriKeyProvider};
+
+    use super::{KmsConf, KmsKeyProvider};
+
+    static MASTER_KEY: [u8; 32] = [42; 32];
+
+    impl KmsConf {
+        pub fn for_test(endpoint: &str) -> KmsConf {
+            KmsConf { endpoint: endpoint.to_string(), key_id: "raikiri".to_string(), token: Some("kms-token".to_string()) }
+        }
+    }
+
+    // Stands in for a KMS until the test ends, returning its endpoint. The raikiri key is the only one it holds.
+    pub async fn start_kms() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(handle)));
+            }
+        });
+        endpoint
+    }
+
+    fn respond(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+        Response::builder().status(status).body(Full::new(Bytes::from(body.to_string()))).unwrap()
+    }
+
+    async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+        if req.headers().get("authorization").is_none_or(|authorization| authorization != "Bearer kms-token") {
+            return Ok(respond(StatusCode::UNAUTHORIZED, serde_json::json!({ "error": "invalid token" })))
+        }
+        let path = req.uri().path().to_string();
+        let body: serde_json::Value = serde_json::from_slice(&req.into_body().collect().await.unwrap().to_bytes()).unwrap();
+        if body["key_id"] != "raikiri" {
+            return Ok(respond(StatusCode::NOT_FOUND, serde_json::json!({ "error": "unknown key" })))
+        }
+        let value = |field: &str| base64::decode_block(body[field].as_str().unwrap()).unwrap();
+        Ok(match path.as_str() {
+            "/encrypt" => {
+                let ciphertext = crypto::encrypt(CipherSuite::Aes256Gcm, &MASTER_KEY, "raikiri", &value("plaintext")).unwrap();
+                respond(StatusCode::OK, serde_json::json!({ "ciphertext": base64::encode_block(&ciphertext) }))
+            }
+            "/decrypt" => match crypto::decrypt(&MASTER_KEY, "raikiri", &value("ciphertext")) {
+                Ok((plaintext, _)) => respond(StatusCode::OK, serde_json::json!({ "plaintext": base64::encode_block(&plaintext) })),
+                Err(_) => respond(StatusCode::BAD_REQUEST, serde_json::json!({ "error": "invalid ciphertext" })),
+            }
+            _ => respond(StatusCode::NOT_FOUND, serde_json::json!({ "error": "unknown operation" })),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_kms_key_provider() {
+        let endpoint = start_kms().await;
+        let provider = KmsKeyProvider::new(KmsConf::for_test(&endpoint));
+        let data_key = [7; 32];
+        let wrapped = provider.wrap_key(&data_key).await.unwrap();
+        assert!(!wrapped.windows(data_key.len()).any(|window| window == data_key));
+        assert_eq!(provider.unwrap_key(&wrapped).await.unwrap(), data_key);
+
+        let unauthorized = KmsKeyProvider::new(KmsConf { token: None, ..KmsConf::for_test(&endpoint) });
+        assert!(unauthorized.unwrap_key(&wrapped).await.is_err());
+        let other_key = KmsKeyProvider::new(KmsConf { key_id: "other".to_string(), ..KmsConf::for_test(&endpoint) });
+        assert!(other_key.unwrap_key(&wrapped).await.is_err());
+    }
+}
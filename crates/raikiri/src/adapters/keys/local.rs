@@ -0,0 +1,19 @@
+use async_trait::async_trait;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_secrets::RaikiriKeyProvider};
+
+use super::is_wrapped_key;
+
+// Data keys are stored as they are, next to the secrets they encrypt
+pub struct LocalKeyProvider;
+
+#[async_trait]
+impl RaikiriKeyProvider for LocalKeyProvider {
+    async fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        Ok(data_key.to_vec())
+    }
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        if is_wrapped_key(wrapped) { return Err("data key is wrapped, the key provider that wrapped it must be configured".into()) }
+        Ok(wrapped.to_vec())
+    }
+}
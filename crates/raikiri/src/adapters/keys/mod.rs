@@ -0,0 +1,117 @@
+pub mod kms;
+pub mod local;
+pub mod passphrase;
+
+use std::sync::Arc;
+
+use yaml_rust2::Yaml;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_secrets::KeyProvider};
+
+use self::{kms::{KmsConf, KmsKeyProvider}, local::LocalKeyProvider, passphrase::PassphraseKeyProvider};
+
+// The keys part of the secrets section of raikiri.yaml. Data keys are kept as they are unless a
+// provider that wraps them with a master key is set:
+//
+//     secrets:
+//       keys:
+//         provider: passphrase
+//         passphrase_env: RAIKIRI_PASSPHRASE
+//
+//     secrets:
+//       keys:
+//         provider: kms
+//         endpoint: http://localhost:8200
+//         key_id: raikiri
+//
+// The token of the kms provider is read from token, or from RAIKIRI_KMS_TOKEN when it is not set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum KeyProviderConf {
+    #[default]
+    Local,
+    // the environment variable holding the passphrase, never the passphrase itself
+    Passphrase { passphrase_env: String },
+    Kms(KmsConf),
+}
+
+impl KeyProviderConf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<KeyProviderConf, ThreadSafeError> {
+        if yaml.is_badvalue() || yaml.is_null() { return Ok(KeyProviderConf::Local) }
+        match yaml["provider"].as_str().unwrap_or("local") {
+            "local" => Ok(KeyProviderConf::Local),
+            "passphrase" => Ok(KeyProviderConf::Passphrase {
+                passphrase_env: yaml["passphrase_env"].as_str().unwrap_or("RAIKIRI_PASSPHRASE").to_string()
+            }),
+            "kms" => Ok(KeyProviderConf::Kms(KmsConf::from_yaml(yaml)?)),
+            provider => Err(format!("unknown key provider {provider}, expected local, passphrase or kms").into())
+        }
+    }
+
+    pub fn build(&self) -> Result<KeyProvider, ThreadSafeError> {
+        match self {
+            KeyProviderConf::Local => Ok(Arc::new(LocalKeyProvider)),
+            KeyProviderConf::Passphrase { passphrase_env } => {
+                let passphrase = std::env::var(passphrase_env).map_err(|_| format!("passphrase key provider needs {passphrase_env} to be set"))?;
+                Ok(Arc::new(PassphraseKeyProvider::new(&passphrase)?))
+            }
+            KeyProviderConf::Kms(conf) => Ok(Arc::new(KmsKeyProvider::new(conf.clone()))),
+        }
+    }
+}
+
+// Wrapped data keys start with a header naming the provider that wrapped them:
+//
+//     RKK | version (1) | provider | what the provider returned
+static MAGIC: &[u8] = b"RKK";
+const VERSION: u8 = 1;
+const PASSPHRASE_PROVIDER: u8 = 1;
+const KMS_PROVIDER: u8 = 2;
+
+pub fn is_wrapped_key(stored: &[u8]) -> bool {
+    stored.starts_with(MAGIC) && stored.len() > MAGIC.len() + 2
+}
+
+fn wrapped_key(provider: u8, payload: &[u8]) -> Vec<u8> {
+    [MAGIC, &[VERSION, provider], payload].concat()
+}
+
+fn provider_name(provider: u8) -> &'static str {
+    match provider {
+        PASSPHRASE_PROVIDER => "passphrase",
+        KMS_PROVIDER => "kms",
+        _ => "unknown",
+    }
+}
+
+// What the provider returned when it wrapped the key
+fn wrapped_payload(wrapped: &[u8], provider: u8) -> Result<&[u8], ThreadSafeError> {
+    if !is_wrapped_key(wrapped) { return Err("data key is not wrapped".into()) }
+    let (version, wrapped_by) = (wrapped[MAGIC.len()], wrapped[MAGIC.len() + 1]);
+    if version != VERSION { return Err(format!("unsupported wrapped key version {version}").into()) }
+    if wrapped_by != provider {
+        return Err(format!("data key was wrapped by the {} key provider, not the {} one", provider_name(wrapped_by), provider_name(provider)).into())
+    }
+    Ok(&wrapped[MAGIC.len() + 2..])
+}
+
+#[cfg(test)]
+mod tests {
+    use yaml_rust2::YamlLoader;
+
+    use super::{kms::KmsConf, KeyProviderConf};
+
+    #[test]
+    fn test_parse_key_provider_conf() {
+        let parse = |yaml: &str| KeyProviderConf::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]);
+        assert_eq!(parse("provider: local").unwrap(), KeyProviderConf::Local);
+        assert_eq!(parse("provider: passphrase").unwrap(), KeyProviderConf::Passphrase { passphrase_env: "RAIKIRI_PASSPHRASE".to_string() });
+        assert_eq!(parse("provider: kms\nendpoint: http://localhost:8200/\nkey_id: raikiri\ntoken: secret").unwrap(), KeyProviderConf::Kms(KmsConf {
+            endpoint: "http://localhost:8200".to_string(),
+            key_id: "raikiri".to_string(),
+            token: Some("secret".to_string()),
+        }));
+        assert!(parse("provider: kms").is_err());
+        assert!(parse("provider: vault").is_err());
+        assert!(KeyProviderConf::Passphrase { passphrase_env: "RAIKIRI_UNSET_PASSPHRASE".to_string() }.build().is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+
+use crate::{adapters::crypto::{self, CipherSuite}, domain::{raikiri_env::ThreadSafeError, raikiri_env_secrets::RaikiriKeyProvider}};
+
+use super::{wrapped_key, wrapped_payload, PASSPHRASE_PROVIDER};
+
+const SALT_LEN: usize = 16;
+// scrypt parameters, about 100ms and 32MiB for each master key derived
+const SCRYPT_N: u64 = 1 << 15;
+const SCRYPT_R: u64 = 8;
+const SCRYPT_P: u64 = 1;
+const SCRYPT_MAX_MEM: u64 = 64 << 20;
+
+// Data keys are wrapped with a master key derived from a passphrase with scrypt. Wrapped keys carry
+// the salt their master key was derived with, the salt of this process is used to wrap new ones.
+pub struct PassphraseKeyProvider {
+    passphrase: String,
+    salt: [u8; SALT_LEN],
+    master_keys: Mutex<HashMap<[u8; SALT_LEN], Vec<u8>>>,
+}
+
+impl PassphraseKeyProvider {
+    pub fn new(passphrase: &str) -> Result<PassphraseKeyProvider, ThreadSafeError> {
+        if passphrase.is_empty() { return Err("passphrase is empty".into()) }
+        let mut salt = [0; SALT_LEN];
+        openssl::rand::rand_bytes(&mut salt)?;
+        Ok(PassphraseKeyProvider { passphrase: passphrase.to_string(), salt, master_keys: Mutex::new(HashMap::new()) })
+    }
+
+    async fn master_key(&self, salt: [u8; SALT_LEN]) -> Result<Vec<u8>, ThreadSafeError> {
+        if let Some(master_key) = self.master_keys.lock().unwrap().get(&salt) { return Ok(master_key.clone()) }
+        let passphrase = self.passphrase.clone();
+        let master_key = tokio::task::spawn_blocking(move || {
+            let mut master_key = vec![0; 32];
+            openssl::pkcs5::scrypt(passphrase.as_bytes(), &salt, SCRYPT_N, SCRYPT_R, SCRYPT_P, SCRYPT_MAX_MEM, &mut master_key)
+                .map(|_| master_key)
+        }).await??;
+        self.master_keys.lock().unwrap().insert(salt, master_key.clone());
+        Ok(master_key)
+    }
+}
+
+#[async_trait]
+impl RaikiriKeyProvider for PassphraseKeyProvider {
+    async fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        let master_key = self.master_key(self.salt).await?;
+        let encrypted = crypto::encrypt(CipherSuite::Aes256Gcm, &master_key, "data key", data_key)?;
+        Ok(wrapped_key(PASSPHRASE_PROVIDER, &[&self.salt[..], &encrypted].concat()))
+    }
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        let payload = wrapped_payload(wrapped, PASSPHRASE_PROVIDER)?;
+        if payload.len() < SALT_LEN { return Err("wrapped data key is truncated".into()) }
+        let (salt, encrypted) = payload.split_at(SALT_LEN);
+        let master_key = self.master_key(salt.try_into()?).await?;
+        let (data_key, _) = crypto::decrypt(&master_key, "data key", encrypted)
+            .map_err(|_| "could not unwrap data key, the passphrase is wrong")?;
+        Ok(data_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::raikiri_env_secrets::RaikiriKeyProvider;
+
+    use super::PassphraseKeyProvider;
+
+    #[tokio::test]
+    async fn test_passphrase_key_provider() {
+        let provider = PassphraseKeyProvider::new("correct horse battery staple").unwrap();
+        let data_key = [7; 32];
+        let wrapped = provider.wrap_key(&data_key).await.unwrap();
+        assert!(!wrapped.windows(data_key.len()).any(|window| window == data_key));
+        assert_eq!(provider.unwrap_key(&wrapped).await.unwrap(), data_key);
+
+        // another process derives the same master key from the salt of the wrapped key
+        let restarted = PassphraseKeyProvider::new("correct horse battery staple").unwrap();
+        assert_eq!(restarted.unwrap_key(&wrapped).await.unwrap(), data_key);
+
+        let wrong = PassphraseKeyProvider::new("wrong passphrase").unwrap();
+        assert!(wrong.unwrap_key(&wrapped).await.is_err());
+        assert!(PassphraseKeyProvider::new("").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use wasmtime::{Engine, ResourceLimiter, UpdateDeadline, DEFAULT_INSTANCE_LIMIT, DEFAULT_MEMORY_LIMIT, DEFAULT_TABLE_LIMIT};
+use yaml_rust2::Yaml;
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+// Epochs are the unit CPU time is measured in, so this is also the granularity of cpu_time_ms
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+// Limits applied to the store of every component invocation, None means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ComponentLimits {
+    pub memory_size: Option<usize>,
+    pub table_elements: Option<usize>,
+    pub instances: Option<usize>,
+    pub tables: Option<usize>,
+    pub memories: Option<usize>,
+    pub cpu_time: Option<Duration>,
+}
+
+// The limits section of raikiri.yaml, defaults plus overrides by user.component name
+#[derive(Clone, Debug, Default)]
+pub struct LimitsConf {
+    pub default: ComponentLimits,
+    pub components: HashMap<String, ComponentLimits>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResourceLimit {
+    Memory,
+    TableElements,
+    Instances,
+    Tables,
+    Memories,
+    CpuTime,
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded(pub ResourceLimit);
+
+pub struct ComponentLimiter {
+    limits: ComponentLimits,
+    epochs: u32,
+}
+
+impl ComponentLimits {
+    pub fn from_yaml(yaml: &Yaml) -> Result<ComponentLimits, ThreadSafeError> {
+        let size = |name: &str| match &yaml[name] {
+            Yaml::BadValue | Yaml::Null => Ok(None),
+            Yaml::Integer(size) if *size >= 0 => Ok(Some(*size as usize)),
+            _ => Err(format!("limit {name} must be a positive number")),
+        };
+        Ok(ComponentLimits {
+            memory_size: size("memory_size")?,
+            table_elements: size("table_elements")?,
+            instances: size("instances")?,
+            tables: size("tables")?,
+            memories: size("memories")?,
+            cpu_time: size("cpu_time_ms")?.map(|ms| Duration::from_millis(ms as u64)),
+        })
+    }
+
+    // Limits set here win, the ones left unset are taken from defaults
+    pub fn or(&self, defaults: &ComponentLimits) -> ComponentLimits {
+        ComponentLimits {
+            memory_size: self.memory_size.or(defaults.memory_size),
+            table_elements: self.table_elements.or(defaults.table_elements),
+            instances: self.instances.or(defaults.instances),
+            tables: self.tables.or(defaults.tables),
+            memories: self.memories.or(defaults.memories),
+            cpu_time: self.cpu_time.or(defaults.cpu_time),
+        }
+    }
+}
+
+impl LimitsConf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<LimitsConf, ThreadSafeError> {
+        let mut components = HashMap::new();
+        if let Some(file_components) = yaml["components"].as_hash() {
+            for (k, v) in file_components {
+                let username_component_name = k.as_str().ok_or("limits components must be named user.component")?;
+                components.insert(username_component_name.to_string(), ComponentLimits::from_yaml(v)?);
+            }
+        }
+        Ok(LimitsConf {
+            default: ComponentLimits::from_yaml(&yaml["default"])?,
+            components,
+        })
+    }
+
+    pub fn for_component(&self, username_component_name: &str) -> ComponentLimits {
+        match self.components.get(username_component_name) {
+            Some(limits) => limits.or(&self.default),
+            None => self.default,
+        }
+    }
+}
+
+impl ResourceLimit {
+    // Running out of CPU time is reported like a timeout, running out of memory or tables as a lack of resources
+    pub fn status(&self) -> u16 {
+        match self {
+            ResourceLimit::CpuTime => 504,
+            _ => 507,
+        }
+    }
+
+    // Finds the limit behind a trap, either raised by ComponentLimiter or by wasmtime itself
+    // when an instance, table or memory count goes over what ComponentLimiter allows
+    pub fn from_error(error: &wasmtime::Error) -> Option<ResourceLimit> {
+        if let Some(LimitExceeded(limit)) = error.downcast_ref::<LimitExceeded>() {
+            return Some(*limit)
+        }
+        let message = error.to_string();
+        match message.strip_prefix("resource limit exceeded: ")?.split(' ').next()? {
+            "instance" => Some(ResourceLimit::Instances),
+            "table" => Some(ResourceLimit::Tables),
+            "memory" => Some(ResourceLimit::Memories),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResourceLimit::Memory => "memory_size",
+            ResourceLimit::TableElements => "table_elements",
+            ResourceLimit::Instances => "instances",
+            ResourceLimit::Tables => "tables",
+            ResourceLimit::Memories => "memories",
+            ResourceLimit::CpuTime => "cpu_time_ms",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "component exceeded its {} limit", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl ComponentLimiter {
+    pub fn new(limits: ComponentLimits) -> ComponentLimiter {
+        ComponentLimiter { limits, epochs: 0 }
+    }
+
+    // Called each time the component is found running past its epoch deadline, which is one
+    // tick away. Ticks spent waiting on the host are never counted, only the ones spent in wasm.
+    pub fn epoch_tick(&mut self) -> wasmtime::Result<UpdateDeadline> {
+        self.epochs += 1;
+        if self.limits.cpu_time.is_some_and(|cpu_time| EPOCH_TICK * self.epochs > cpu_time) {
+            return Err(LimitExceeded(ResourceLimit::CpuTime).into())
+        }
+        Ok(UpdateDeadline::Yield(1))
+    }
+}
+
+impl Default for ComponentLimiter {
+    fn default() -> Self {
+        ComponentLimiter::new(ComponentLimits::default())
+    }
+}
+
+impl ResourceLimiter for ComponentLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> wasmtime::Result<bool> {
+        if self.limits.memory_size.is_some_and(|memory_size| desired > memory_size) {
+            return Err(LimitExceeded(ResourceLimit::Memory).into())
+        }
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> wasmtime::Result<bool> {
+        if self.limits.table_elements.is_some_and(|table_elements| desired > table_elements) {
+            return Err(LimitExceeded(ResourceLimit::TableElements).into())
+        }
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances.unwrap_or(DEFAULT_INSTANCE_LIMIT)
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables.unwrap_or(DEFAULT_TABLE_LIMIT)
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories.unwrap_or(DEFAULT_MEMORY_LIMIT)
+    }
+}
+
+// Advances the epoch of engine until it is dropped, which is what makes epoch deadlines expire
+pub fn spawn_epoch_ticker(engine: &Engine) {
+    let engine = engine.weak();
+    std::thread::spawn(move || {
+        while let Some(engine) = engine.upgrade() {
+            engine.increment_epoch();
+            drop(engine);
+            std::thread::sleep(EPOCH_TICK);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use yaml_rust2::YamlLoader;
+
+    use super::{ComponentLimits, LimitsConf};
+
+    #[test]
+    fn test_parse_limits() {
+        let yaml = YamlLoader::load_from_str("
+default:
+  memory_size: 1048576
+  cpu_time_ms: 100
+components:
+  test.big:
+    memory_size: 4194304
+").unwrap();
+        let limits = LimitsConf::from_yaml(&yaml[0]).unwrap();
+
+        assert_eq!(limits.for_component("test.small"), ComponentLimits {
+            memory_size: Some(1048576),
+            cpu_time: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        assert_eq!(limits.for_component("test.big").memory_size, Some(4194304));
+        assert_eq!(limits.for_component("test.big").cpu_time, Some(Duration::from_millis(100)));
+    }
+}
@@ -1,8 +1,20 @@
+pub mod access;
+pub mod api_gateway;
+pub mod body;
 pub mod cache;
+pub mod componentize;
 pub mod component_imports;
 pub mod context;
+pub mod crypto;
 pub mod wasi_http_view;
 pub mod wasi_view;
 pub mod wit;
 pub mod conf_file;
-pub mod db;
\ No newline at end of file
+pub mod db;
+pub mod engine;
+pub mod keys;
+pub mod limits;
+pub mod router;
+pub mod storage;
+pub mod tls;
+pub mod validation;
\ No newline at end of file
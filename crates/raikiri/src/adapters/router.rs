@@ -0,0 +1,206 @@
+use http::{Method, Uri};
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+use super::conf_file::RouteConf;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(Option<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub username_component_name: String,
+    methods: Vec<Method>,
+    host: Option<String>,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteMatch {
+    pub index: usize,
+    pub username_component_name: String,
+    pub params: Vec<(String, String)>,
+    pub forwarded_path: String,
+}
+
+// Routes are matched in declaration order, the first match wins.
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    pub routes: Vec<Route>,
+}
+
+impl Route {
+    pub fn new(path: &str, username_component_name: String, methods: &[String], host: Option<&str>) -> Result<Route, ThreadSafeError> {
+        if !username_component_name.contains('.') {
+            return Err(format!("route {path} must target a component in the user.component format").into())
+        }
+        let mut route_methods = Vec::new();
+        for method in methods.iter().filter(|method| *method != "*") {
+            route_methods.push(Method::from_bytes(method.to_uppercase().as_bytes()).map_err(|_| format!("invalid method {method} in route {path}"))?);
+        }
+        let mut segments = Vec::new();
+        let parts = split_path(path);
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            }
+            else if let Some(name) = part.strip_prefix('*') {
+                if i != parts.len() - 1 {
+                    return Err(format!("wildcard must be the last segment of route {path}").into())
+                }
+                Segment::Wildcard(if name.is_empty() { None } else { Some(name.to_string()) })
+            }
+            else {
+                Segment::Literal(part.to_string())
+            };
+            segments.push(segment);
+        }
+        Ok(Route {
+            username_component_name,
+            methods: route_methods,
+            host: host.map(|host| host.to_lowercase()),
+            segments,
+        })
+    }
+
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        let Some(route_host) = &self.host else { return true };
+        let Some(host) = host else { return false };
+        let host = host.split(':').next().unwrap_or(host).to_lowercase();
+        match route_host.strip_prefix("*.") {
+            Some(suffix) => host.ends_with(&format!(".{suffix}")),
+            None => *route_host == host,
+        }
+    }
+
+    pub fn match_request(&self, index: usize, method: &Method, host: Option<&str>, path: &str) -> Option<RouteMatch> {
+        if !self.methods.is_empty() && !self.methods.contains(method) { return None }
+        if !self.matches_host(host) { return None }
+
+        let parts = split_path(path);
+        let mut params = Vec::new();
+        let mut forwarded_path = String::from("/");
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.get(i) != Some(&literal.as_str()) { return None }
+                }
+                Segment::Param(name) => {
+                    params.push((name.clone(), parts.get(i)?.to_string()));
+                }
+                Segment::Wildcard(name) => {
+                    let rest = parts[i..].join("/");
+                    if let Some(name) = name {
+                        params.push((name.clone(), rest.clone()));
+                    }
+                    forwarded_path.push_str(&rest);
+                    return Some(RouteMatch { index, username_component_name: self.username_component_name.clone(), params, forwarded_path })
+                }
+            }
+        }
+        if parts.len() != self.segments.len() { return None }
+        Some(RouteMatch { index, username_component_name: self.username_component_name.clone(), params, forwarded_path })
+    }
+}
+
+impl Router {
+    pub fn new(routes: &[RouteConf]) -> Result<Router, ThreadSafeError> {
+        let mut result = Vec::new();
+        for route in routes {
+            result.push(Route::new(&route.path, route.component.clone(), &route.methods, route.host.as_deref())?);
+        }
+        Ok(Router { routes: result })
+    }
+
+    pub fn match_request(&self, method: &Method, uri: &Uri, host: Option<&str>) -> Option<RouteMatch> {
+        let host = uri.host().or(host);
+        self.routes.iter().enumerate().find_map(|(index, route)| route.match_request(index, method, host, uri.path()))
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, Uri};
+
+    use crate::adapters::conf_file::RouteConf;
+
+    use super::{Route, Router};
+
+    fn router(routes: Vec<Route>) -> Router {
+        Router { routes }
+    }
+
+    #[test]
+    fn test_match_literal_and_params() {
+        let router = router(vec![
+            Route::new("/users/:id", "test.users".to_string(), &["GET".to_string()], None).unwrap(),
+        ]);
+
+        let uri: Uri = "/users/42".parse().unwrap();
+        let found = router.match_request(&Method::GET, &uri, None).unwrap();
+        assert_eq!(found.username_component_name, "test.users");
+        assert_eq!(found.params, vec![("id".to_string(), "42".to_string())]);
+        assert_eq!(found.forwarded_path, "/");
+
+        assert!(router.match_request(&Method::POST, &uri, None).is_none());
+        assert!(router.match_request(&Method::GET, &"/users/42/posts".parse().unwrap(), None).is_none());
+        assert!(router.match_request(&Method::GET, &"/users".parse().unwrap(), None).is_none());
+    }
+
+    #[test]
+    fn test_match_wildcard_forwards_rest() {
+        let router = router(vec![
+            Route::new("/static/*", "test.assets".to_string(), &[], None).unwrap(),
+            Route::new("/api/:version/*path", "test.api".to_string(), &[], None).unwrap(),
+        ]);
+
+        let found = router.match_request(&Method::GET, &"/static/css/main.css".parse().unwrap(), None).unwrap();
+        assert_eq!(found.username_component_name, "test.assets");
+        assert_eq!(found.forwarded_path, "/css/main.css");
+
+        let found = router.match_request(&Method::DELETE, &"/api/v1/orders/7".parse().unwrap(), None).unwrap();
+        assert_eq!(found.username_component_name, "test.api");
+        assert_eq!(found.forwarded_path, "/orders/7");
+        assert_eq!(found.params, vec![
+            ("version".to_string(), "v1".to_string()),
+            ("path".to_string(), "orders/7".to_string()),
+        ]);
+
+        let found = router.match_request(&Method::GET, &"/static".parse().unwrap(), None).unwrap();
+        assert_eq!(found.forwarded_path, "/");
+    }
+
+    #[test]
+    fn test_match_host() {
+        let router = router(vec![
+            Route::new("/*", "test.admin".to_string(), &[], Some("admin.example.com")).unwrap(),
+            Route::new("/*", "test.tenants".to_string(), &[], Some("*.example.com")).unwrap(),
+            Route::new("/*", "test.fallback".to_string(), &[], None).unwrap(),
+        ]);
+
+        let uri: Uri = "/".parse().unwrap();
+        let matched = |host| router.match_request(&Method::GET, &uri, host).unwrap().username_component_name;
+        assert_eq!(matched(Some("admin.example.com:8080")), "test.admin");
+        assert_eq!(matched(Some("acme.example.com")), "test.tenants");
+        assert_eq!(matched(Some("localhost")), "test.fallback");
+        assert_eq!(matched(None), "test.fallback");
+    }
+
+    #[test]
+    fn test_invalid_routes() {
+        assert!(Route::new("/*/users", "test.users".to_string(), &[], None).is_err());
+        assert!(Route::new("/users", "users".to_string(), &[], None).is_err());
+
+        // the route is named in the error, which is returned when the environment is created
+        let routes = [RouteConf { path: "/users".to_string(), component: "test.users".to_string(), methods: vec!["G T".to_string()], host: None }];
+        assert_eq!(Router::new(&routes).err().unwrap().to_string(), "invalid method G T in route /users");
+    }
+}
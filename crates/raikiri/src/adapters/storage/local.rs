@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_fs::{FileMetadata, RaikiriStorage}};
+
+// Files under a directory on local disk, fs_root by default
+pub struct LocalStorage {
+    root: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> LocalStorage {
+        LocalStorage { root: root.to_string() }
+    }
+
+    fn path(&self, path: &str) -> String {
+        format!("{}/{path}", self.root)
+    }
+}
+
+#[async_trait]
+impl RaikiriStorage for LocalStorage {
+    async fn read(&self, path: &str) -> Result<Vec<u8>, ThreadSafeError> {
+        Ok(tokio::fs::read(self.path(path)).await?)
+    }
+    async fn write(&self, path: &str, content: Vec<u8>) -> Result<(), ThreadSafeError> {
+        Ok(tokio::fs::write(self.path(path), content).await?)
+    }
+    async fn remove(&self, path: &str) -> Result<(), ThreadSafeError> {
+        Ok(tokio::fs::remove_file(self.path(path)).await?)
+    }
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ThreadSafeError> {
+        Ok(tokio::fs::rename(self.path(from), self.path(to)).await?)
+    }
+    async fn remove_dir(&self, path: &str) -> Result<(), ThreadSafeError> {
+        Ok(tokio::fs::remove_dir_all(self.path(path)).await?)
+    }
+    async fn exists(&self, path: &str) -> bool {
+        tokio::fs::metadata(self.path(path)).await.is_ok()
+    }
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, ThreadSafeError> {
+        let metadata = tokio::fs::metadata(self.path(path)).await?;
+        Ok(FileMetadata { len: metadata.len(), modified: metadata.modified()? })
+    }
+    async fn create_dir(&self, path: &str) -> Result<(), ThreadSafeError> {
+        Ok(tokio::fs::create_dir_all(self.path(path)).await?)
+    }
+    async fn read_dir(&self, path: &str) -> Result<Vec<String>, ThreadSafeError> {
+        let mut entries = tokio::fs::read_dir(self.path(path)).await?;
+        let mut result = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            result.push(entry.file_name().into_string().unwrap());
+        }
+        Ok(result)
+    }
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(self.path(path)))
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::{BTreeMap, BTreeSet}, sync::RwLock, time::SystemTime};
+
+use async_trait::async_trait;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_fs::{FileMetadata, RaikiriStorage}};
+
+use super::not_found;
+
+// Files kept in memory, with the same rules as local disk: files are written into existing
+// directories and reading or removing what is not there fails
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: RwLock<BTreeMap<String, (Vec<u8>, SystemTime)>>,
+    dirs: RwLock<BTreeSet<String>>,
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+// the root is the empty path, everything else is under it
+fn is_under(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl MemoryStorage {
+    fn is_dir(&self, path: &str) -> bool {
+        path.is_empty() || self.dirs.read().unwrap().contains(path)
+    }
+}
+
+#[async_trait]
+impl RaikiriStorage for MemoryStorage {
+    async fn read(&self, path: &str) -> Result<Vec<u8>, ThreadSafeError> {
+        let files = self.files.read().unwrap();
+        Ok(files.get(path).ok_or_else(|| not_found(path))?.0.clone())
+    }
+    async fn write(&self, path: &str, content: Vec<u8>) -> Result<(), ThreadSafeError> {
+        if !self.is_dir(parent(path)) { return Err(not_found(parent(path))) }
+        self.files.write().unwrap().insert(path.to_string(), (content, SystemTime::now()));
+        Ok(())
+    }
+    async fn remove(&self, path: &str) -> Result<(), ThreadSafeError> {
+        self.files.write().unwrap().remove(path).ok_or_else(|| not_found(path))?;
+        Ok(())
+    }
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ThreadSafeError> {
+        if !self.is_dir(parent(to)) { return Err(not_found(parent(to))) }
+        let mut files = self.files.write().unwrap();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+    async fn remove_dir(&self, path: &str) -> Result<(), ThreadSafeError> {
+        if !self.is_dir(path) { return Err(not_found(path)) }
+        self.files.write().unwrap().retain(|file, _| !is_under(file, path));
+        self.dirs.write().unwrap().retain(|dir| dir != path && !is_under(dir, path));
+        Ok(())
+    }
+    async fn exists(&self, path: &str) -> bool {
+        self.is_dir(path) || self.files.read().unwrap().contains_key(path)
+    }
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, ThreadSafeError> {
+        let files = self.files.read().unwrap();
+        let (content, modified) = files.get(path).ok_or_else(|| not_found(path))?;
+        Ok(FileMetadata { len: content.len() as u64, modified: *modified })
+    }
+    async fn create_dir(&self, path: &str) -> Result<(), ThreadSafeError> {
+        let mut dirs = self.dirs.write().unwrap();
+        let mut dir = path;
+        while !dir.is_empty() {
+            dirs.insert(dir.to_string());
+            dir = parent(dir);
+        }
+        Ok(())
+    }
+    async fn read_dir(&self, path: &str) -> Result<Vec<String>, ThreadSafeError> {
+        if !self.is_dir(path) { return Err(not_found(path)) }
+        let dirs = self.dirs.read().unwrap();
+        let files = self.files.read().unwrap();
+        Ok(dirs.iter().chain(files.keys())
+            .filter(|entry| parent(entry) == path)
+            .map(|entry| entry.rsplit('/').next().unwrap().to_string())
+            .collect())
+    }
+}
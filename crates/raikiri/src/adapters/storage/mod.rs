@@ -0,0 +1,157 @@
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+use std::sync::Arc;
+
+use yaml_rust2::Yaml;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_fs::Storage};
+
+use self::{local::LocalStorage, memory::MemoryStorage, s3::{S3Conf, S3Storage}};
+
+// The storage section of raikiri.yaml. Files are kept under fs_root on local disk unless a backend is set:
+//
+//     storage:
+//       backend: s3
+//       endpoint: http://localhost:9000
+//       bucket: raikiri
+//       region: us-east-1
+//       prefix: production
+//
+// The access keys of the s3 backend are read from access_key and secret_key, or from the
+// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables when they are not set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum StorageConf {
+    #[default]
+    Local,
+    // nothing is kept after the process exits
+    Memory,
+    S3(S3Conf),
+}
+
+impl StorageConf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<StorageConf, ThreadSafeError> {
+        if yaml.is_badvalue() || yaml.is_null() { return Ok(StorageConf::Local) }
+        match yaml["backend"].as_str().unwrap_or("local") {
+            "local" => Ok(StorageConf::Local),
+            "memory" => Ok(StorageConf::Memory),
+            "s3" => Ok(StorageConf::S3(S3Conf::from_yaml(yaml)?)),
+            backend => Err(format!("unknown storage backend {backend}, expected local, memory or s3").into())
+        }
+    }
+
+    pub fn build(&self, fs_root: &str) -> Storage {
+        match self {
+            StorageConf::Local => Arc::new(LocalStorage::new(fs_root)),
+            StorageConf::Memory => Arc::new(MemoryStorage::default()),
+            StorageConf::S3(conf) => Arc::new(S3Storage::new(conf.clone())),
+        }
+    }
+}
+
+fn not_found(path: &str) -> ThreadSafeError {
+    Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{path} not found")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use yaml_rust2::YamlLoader;
+
+    use crate::domain::{raikiri_env_fs::{RaikiriEnvironmentFS, Storage}, raikiri_env_component::RaikiriComponentStorage, raikiri_env_secrets::RaikiriEnvironmentSecrets, tests::{create_test_dir, create_test_env}};
+
+    use super::{local::LocalStorage, memory::MemoryStorage, s3::{tests::start_object_store, S3Conf}, StorageConf};
+
+    #[test]
+    fn test_parse_storage_conf() {
+        let parse = |yaml: &str| StorageConf::from_yaml(&YamlLoader::load_from_str(yaml).unwrap()[0]);
+
+        assert_eq!(parse("backend: memory").unwrap(), StorageConf::Memory);
+        assert_eq!(parse("
+backend: s3
+endpoint: http://localhost:9000
+bucket: raikiri
+access_key: key
+secret_key: secret
+").unwrap(), StorageConf::S3(S3Conf {
+            endpoint: "http://localhost:9000".to_string(),
+            bucket: "raikiri".to_string(),
+            region: "us-east-1".to_string(),
+            prefix: String::new(),
+            access_key: "key".to_string(),
+            secret_key: "secret".to_string(),
+        }));
+        assert!(parse("backend: s3").is_err());
+        assert!(parse("backend: ftp").is_err());
+    }
+
+    // Every backend has to behave like local disk for the paths the environment uses
+    async fn exercise_storage(storage: Storage) {
+        storage.create_dir("").await.unwrap();
+        storage.create_dir("components/test.hello").await.unwrap();
+        assert!(storage.exists("components").await);
+        assert!(!storage.exists("components/test.hello/current").await);
+        assert!(storage.read("components/test.hello/current").await.is_err());
+
+        storage.write("components/test.hello/current.tmp", b"2".to_vec()).await.unwrap();
+        storage.rename("components/test.hello/current.tmp", "components/test.hello/current").await.unwrap();
+        assert_eq!(storage.read("components/test.hello/current").await.unwrap(), b"2");
+        assert!(!storage.exists("components/test.hello/current.tmp").await);
+        assert_eq!(storage.metadata("components/test.hello/current").await.unwrap().len, 1);
+
+        storage.write("components/test.hello/1-abc", Vec::new()).await.unwrap();
+        storage.write("components/test.hello/current", b"1".to_vec()).await.unwrap();
+        let mut entries = storage.read_dir("components/test.hello").await.unwrap();
+        entries.sort();
+        assert_eq!(entries, vec!["1-abc", "current"]);
+        assert_eq!(storage.read_dir("components").await.unwrap(), vec!["test.hello"]);
+
+        storage.remove("components/test.hello/1-abc").await.unwrap();
+        assert_eq!(storage.read_dir("components/test.hello").await.unwrap(), vec!["current"]);
+
+        storage.remove_dir("components/test.hello").await.unwrap();
+        assert!(!storage.exists("components/test.hello").await);
+        assert!(!storage.exists("components/test.hello/current").await);
+    }
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = create_test_dir();
+        exercise_storage(Arc::new(LocalStorage::new(&dir))).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        exercise_storage(Arc::new(MemoryStorage::default())).await;
+    }
+
+    #[tokio::test]
+    async fn test_s3_storage() {
+        let endpoint = start_object_store().await;
+        exercise_storage(StorageConf::S3(S3Conf::for_test(&endpoint, "raikiri")).build("")).await;
+    }
+
+    // Nodes pointed at the same object store serve each other's components
+    #[tokio::test]
+    async fn test_nodes_share_storage() {
+        let endpoint = start_object_store().await;
+        let storage = StorageConf::S3(S3Conf::for_test(&endpoint, "shared")).build("");
+        let node = |storage: Storage| create_test_env().with_storage(storage);
+
+        let first = node(storage.clone());
+        first.setup_fs().await.unwrap();
+        let component = tokio::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).await.unwrap();
+        first.add_component("test".to_string(), "hello".to_string(), component).await.unwrap();
+        first.update_component_secrets("test".to_string(), "hello".to_string(), b"KEY: value".to_vec()).await.unwrap();
+
+        let second = node(storage);
+        assert_eq!(second.get_current_revision("test".to_string(), "hello".to_string()).await.unwrap(), Some(1));
+        assert!(second.get_component_revision("test".to_string(), "hello".to_string(), 1).await.is_ok());
+        assert_eq!(second.list_components().await.unwrap().len(), 1);
+        // secrets are encrypted with a key kept in the same storage
+        assert_eq!(second.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(), vec![("KEY".to_string(), "value".to_string())]);
+    }
+}
@@ -0,0 +1,421 @@
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use reqwest::{Method, StatusCode, Url};
+use yaml_rust2::Yaml;
+
+use crate::domain::{raikiri_env::ThreadSafeError, raikiri_env_blobs::sha256_hex, raikiri_env_fs::{FileMetadata, RaikiriStorage}};
+
+use super::not_found;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct S3Conf {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    // objects are stored under {prefix}/, so several deployments can share a bucket
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl S3Conf {
+    pub fn from_yaml(yaml: &Yaml) -> Result<S3Conf, ThreadSafeError> {
+        let field = |name: &str| yaml[name].as_str().map(|v| v.to_string());
+        let env = |name: &str| std::env::var(name).ok();
+        Ok(S3Conf {
+            endpoint: field("endpoint").ok_or("s3 storage is missing endpoint")?.trim_end_matches('/').to_string(),
+            bucket: field("bucket").ok_or("s3 storage is missing bucket")?,
+            region: field("region").unwrap_or("us-east-1".to_string()),
+            prefix: field("prefix").unwrap_or_default().trim_matches('/').to_string(),
+            access_key: field("access_key").or_else(|| env("AWS_ACCESS_KEY_ID")).ok_or("s3 storage is missing access_key")?,
+            secret_key: field("secret_key").or_else(|| env("AWS_SECRET_ACCESS_KEY")).ok_or("s3 storage is missing secret_key")?,
+        })
+    }
+}
+
+// Objects in a bucket of an S3-compatible store, addressed path-style as {endpoint}/{bucket}/{key}
+// and signed with AWS Signature Version 4. Object stores have no directories, so create_dir writes
+// an empty {path}/ marker object, and a directory exists while any object is stored under it.
+pub struct S3Storage {
+    conf: S3Conf,
+    client: reqwest::Client,
+}
+
+struct Listing {
+    keys: Vec<String>,
+    prefixes: Vec<String>,
+}
+
+impl S3Storage {
+    pub fn new(conf: S3Conf) -> S3Storage {
+        S3Storage { conf, client: reqwest::Client::new() }
+    }
+
+    fn key(&self, path: &str) -> String {
+        match (self.conf.prefix.as_str(), path) {
+            ("", path) => path.to_string(),
+            (prefix, "") => prefix.to_string(),
+            (prefix, path) => format!("{prefix}/{path}"),
+        }
+    }
+
+    // what is listed under a directory starts with this
+    fn dir_prefix(&self, path: &str) -> String {
+        match self.key(path).as_str() {
+            "" => String::new(),
+            key => format!("{key}/"),
+        }
+    }
+
+    async fn send(&self, method: Method, key: &str, query: Vec<(&str, String)>, body: Vec<u8>) -> Result<reqwest::Response, ThreadSafeError> {
+        let endpoint = Url::parse(&self.conf.endpoint)?;
+        let host = match endpoint.port() {
+            Some(port) => format!("{}:{port}", endpoint.host_str().ok_or("s3 endpoint has no host")?),
+            None => endpoint.host_str().ok_or("s3 endpoint has no host")?.to_string(),
+        };
+        let uri = match key {
+            "" => format!("/{}", self.conf.bucket),
+            key => format!("/{}/{}", self.conf.bucket, uri_encode(key, false)),
+        };
+        let query = canonical_query(query);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = sha256_hex(&body);
+        let authorization = authorization(&self.conf, &SignedRequest {
+            method: method.as_str(),
+            uri: &uri,
+            query: &query,
+            host: &host,
+            amz_date: &amz_date,
+            payload_hash: &payload_hash,
+        })?;
+
+        let url = match query.as_str() {
+            "" => format!("{}{uri}", self.conf.endpoint),
+            query => format!("{}{uri}?{query}", self.conf.endpoint),
+        };
+        Ok(self.client.request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?)
+    }
+
+    async fn expect_success(response: reqwest::Response, path: &str) -> Result<reqwest::Response, ThreadSafeError> {
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(not_found(path)),
+            status => Err(format!("s3 request for {path} failed with {status}: {}", response.text().await.unwrap_or_default()).into())
+        }
+    }
+
+    // Objects directly under prefix when delimited, otherwise every object under it
+    async fn list(&self, prefix: &str, delimited: bool, max_keys: Option<usize>) -> Result<Listing, ThreadSafeError> {
+        let mut listing = Listing { keys: Vec::new(), prefixes: Vec::new() };
+        let mut continuation_token = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.to_string())];
+            if delimited { query.push(("delimiter", "/".to_string())) }
+            if let Some(max_keys) = max_keys { query.push(("max-keys", max_keys.to_string())) }
+            if let Some(token) = continuation_token.take() { query.push(("continuation-token", token)) }
+            let response = self.send(Method::GET, "", query, Vec::new()).await?;
+            let xml = Self::expect_success(response, prefix).await?.text().await?;
+
+            for contents in xml_elements(&xml, "Contents") {
+                listing.keys.extend(xml_elements(contents, "Key").first().map(|key| xml_unescape(key)));
+            }
+            for common_prefix in xml_elements(&xml, "CommonPrefixes") {
+                listing.prefixes.extend(xml_elements(common_prefix, "Prefix").first().map(|prefix| xml_unescape(prefix)));
+            }
+            let truncated = xml_elements(&xml, "IsTruncated").first() == Some(&"true");
+            continuation_token = xml_elements(&xml, "NextContinuationToken").first().map(|token| xml_unescape(token));
+            if !truncated || continuation_token.is_none() || max_keys.is_some() { return Ok(listing) }
+        }
+    }
+}
+
+#[async_trait]
+impl RaikiriStorage for S3Storage {
+    async fn read(&self, path: &str) -> Result<Vec<u8>, ThreadSafeError> {
+        let response = self.send(Method::GET, &self.key(path), Vec::new(), Vec::new()).await?;
+        Ok(Self::expect_success(response, path).await?.bytes().await?.to_vec())
+    }
+    async fn write(&self, path: &str, content: Vec<u8>) -> Result<(), ThreadSafeError> {
+        let response = self.send(Method::PUT, &self.key(path), Vec::new(), content).await?;
+        Self::expect_success(response, path).await?;
+        Ok(())
+    }
+    async fn remove(&self, path: &str) -> Result<(), ThreadSafeError> {
+        let response = self.send(Method::DELETE, &self.key(path), Vec::new(), Vec::new()).await?;
+        Self::expect_success(response, path).await?;
+        Ok(())
+    }
+    // Objects can't be renamed, they are read, written to the destination and the original removed.
+    // Unlike a rename on local disk this is not atomic: the destination is replaced in one step, but
+    // a failure before the removal leaves both objects, so callers must be able to rename again.
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ThreadSafeError> {
+        let content = self.read(from).await?;
+        self.write(to, content).await?;
+        self.remove(from).await
+    }
+    async fn remove_dir(&self, path: &str) -> Result<(), ThreadSafeError> {
+        for key in self.list(&self.dir_prefix(path), false, None).await?.keys {
+            let response = self.send(Method::DELETE, &key, Vec::new(), Vec::new()).await?;
+            Self::expect_success(response, &key).await?;
+        }
+        Ok(())
+    }
+    async fn exists(&self, path: &str) -> bool {
+        if let Ok(response) = self.send(Method::HEAD, &self.key(path), Vec::new(), Vec::new()).await {
+            if response.status().is_success() { return true }
+        }
+        match self.list(&self.dir_prefix(path), false, Some(1)).await {
+            Ok(listing) => !listing.keys.is_empty(),
+            Err(_) => false
+        }
+    }
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, ThreadSafeError> {
+        let response = self.send(Method::HEAD, &self.key(path), Vec::new(), Vec::new()).await?;
+        let response = Self::expect_success(response, path).await?;
+        let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+        let len = header("content-length").ok_or("s3 object has no content-length")?.parse()?;
+        let modified = match header("last-modified") {
+            Some(modified) => SystemTime::from(chrono::DateTime::parse_from_rfc2822(&modified)?),
+            None => SystemTime::now()
+        };
+        Ok(FileMetadata { len, modified })
+    }
+    async fn create_dir(&self, path: &str) -> Result<(), ThreadSafeError> {
+        let marker = self.dir_prefix(path);
+        if marker.is_empty() { return Ok(()) }
+        let response = self.send(Method::PUT, &marker, Vec::new(), Vec::new()).await?;
+        Self::expect_success(response, path).await?;
+        Ok(())
+    }
+    async fn read_dir(&self, path: &str) -> Result<Vec<String>, ThreadSafeError> {
+        let prefix = self.dir_prefix(path);
+        let listing = self.list(&prefix, true, None).await?;
+        let files = listing.keys.iter().filter_map(|key| key.strip_prefix(&prefix));
+        let dirs = listing.prefixes.iter().filter_map(|dir| dir.strip_prefix(&prefix)?.strip_suffix('/'));
+        // the marker of the directory itself is listed with an empty name
+        Ok(files.chain(dirs).filter(|name| !name.is_empty()).map(|name| name.to_string()).collect())
+    }
+}
+
+// What an S3 request is signed over, see
+// https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub query: &'a str,
+    pub host: &'a str,
+    pub amz_date: &'a str,
+    pub payload_hash: &'a str,
+}
+
+pub fn authorization(conf: &S3Conf, request: &SignedRequest) -> Result<String, ThreadSafeError> {
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{signed_headers}\n{}",
+        request.method, request.uri, request.query, request.host, request.payload_hash, request.amz_date, request.payload_hash
+    );
+    let date = request.amz_date.get(..8).ok_or("invalid x-amz-date")?;
+    let scope = format!("{date}/{}/s3/aws4_request", conf.region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{scope}\n{}", request.amz_date, sha256_hex(canonical_request.as_bytes()));
+
+    let mut key = hmac(format!("AWS4{}", conf.secret_key).as_bytes(), date)?;
+    for part in [conf.region.as_str(), "s3", "aws4_request"] {
+        key = hmac(&key, part)?;
+    }
+    let signature: String = hmac(&key, &string_to_sign)?.iter().map(|byte| format!("{byte:02x}")).collect();
+    Ok(format!("AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}", conf.access_key))
+}
+
+fn hmac(key: &[u8], data: &str) -> Result<Vec<u8>, ThreadSafeError> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data.as_bytes())?;
+    Ok(signer.sign_to_vec()?)
+}
+
+// Query parameters sorted by name, with names and values encoded
+pub fn canonical_query(mut query: Vec<(&str, String)>) -> String {
+    query.sort();
+    query.iter()
+        .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// Every byte but unreserved characters is percent-encoded, and / too unless it separates a path
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        byte => format!("%{byte:02X}"),
+    }).collect()
+}
+
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after_open = &rest[start + open.len()..];
+        let Some(end) = after_open.find(&close) else { break };
+        elements.push(&after_open[..end]);
+        rest = &after_open[end + close.len()..];
+    }
+    elements
+}
+
+fn xml_unescape(value: &str) -> String {
+    value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// A stand-in for MinIO, enough of the S3 API for S3Storage: objects are put, read, checked with
+// HEAD, deleted and listed with ListObjectsV2 after checking the signature of the request
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::BTreeMap, convert::Infallible, sync::{Arc, Mutex}, time::SystemTime};
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::{body::{Bytes, Incoming}, service::service_fn, Request, Response, StatusCode};
+    use wasmtime_wasi_http::io::TokioIo;
+
+    use crate::domain::raikiri_env_blobs::sha256_hex;
+
+    use super::{authorization, canonical_query, S3Conf, SignedRequest};
+
+    type Objects = Arc<Mutex<BTreeMap<String, (Vec<u8>, SystemTime)>>>;
+
+    impl S3Conf {
+        pub fn for_test(endpoint: &str, bucket: &str) -> S3Conf {
+            S3Conf {
+                endpoint: endpoint.to_string(),
+                bucket: bucket.to_string(),
+                region: "us-east-1".to_string(),
+                prefix: "raikiri".to_string(),
+                access_key: "raikiri".to_string(),
+                secret_key: "raikiri-secret".to_string(),
+            }
+        }
+    }
+
+    // Serves until the test ends, returning its endpoint
+    pub async fn start_object_store() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let objects = objects.clone();
+                let service = service_fn(move |req| handle(objects.clone(), req));
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        endpoint
+    }
+
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' && i + 2 < bytes.len() {
+                decoded.push(u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            } else {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    fn respond(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+        Response::builder().status(status).body(Full::new(body.into())).unwrap()
+    }
+
+    async fn handle(objects: Objects, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+        let (parts, body) = req.into_parts();
+        let body = body.collect().await.unwrap().to_bytes().to_vec();
+        let header = |name: &str| parts.headers.get(name).map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+
+        let query: Vec<(String, String)> = parts.uri.query().unwrap_or("").split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+        let expected = authorization(&S3Conf::for_test("", ""), &SignedRequest {
+            method: parts.method.as_str(),
+            uri: parts.uri.path(),
+            query: &canonical_query(query.iter().map(|(name, value)| (name.as_str(), value.clone())).collect()),
+            host: &header("host"),
+            amz_date: &header("x-amz-date"),
+            payload_hash: &sha256_hex(&body),
+        }).unwrap();
+        if header("authorization") != expected || header("x-amz-content-sha256") != sha256_hex(&body) {
+            return Ok(respond(StatusCode::FORBIDDEN, "SignatureDoesNotMatch"))
+        }
+
+        let path = percent_decode(parts.uri.path());
+        let (bucket, key) = path.trim_start_matches('/').split_once('/').unwrap_or((path.trim_start_matches('/'), ""));
+        let object = format!("{bucket}/{key}");
+        let mut objects = objects.lock().unwrap();
+        let response = match (parts.method.as_str(), key) {
+            ("GET", "") => {
+                let param = |name: &str| query.iter().find(|(found, _)| found == name).map(|(_, value)| value.clone());
+                let prefix = format!("{bucket}/{}", param("prefix").unwrap_or_default());
+                let max_keys = param("max-keys").map(|max| max.parse().unwrap()).unwrap_or(usize::MAX);
+                let mut contents = Vec::new();
+                let mut prefixes = Vec::new();
+                for name in objects.keys().filter(|name| name.starts_with(&prefix)).take(max_keys) {
+                    let rest = &name[prefix.len()..];
+                    let key = &name[bucket.len() + 1..];
+                    match (param("delimiter"), rest.find('/')) {
+                        (Some(_), Some(end)) => {
+                            let common = format!("{}{}", &prefix[bucket.len() + 1..], &rest[..=end]);
+                            if !prefixes.contains(&common) { prefixes.push(common) }
+                        }
+                        _ => contents.push(key.to_string()),
+                    }
+                }
+                let xml = format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>{bucket}</Name><IsTruncated>false</IsTruncated>{}{}</ListBucketResult>",
+                    contents.iter().map(|key| format!("<Contents><Key>{key}</Key></Contents>")).collect::<String>(),
+                    prefixes.iter().map(|prefix| format!("<CommonPrefixes><Prefix>{prefix}</Prefix></CommonPrefixes>")).collect::<String>(),
+                );
+                respond(StatusCode::OK, xml)
+            }
+            ("GET", _) => match objects.get(&object) {
+                Some((content, _)) => respond(StatusCode::OK, content.clone()),
+                None => respond(StatusCode::NOT_FOUND, "NoSuchKey"),
+            },
+            ("HEAD", _) => match objects.get(&object) {
+                Some((content, modified)) => Response::builder()
+                    .header("content-length", content.len())
+                    .header("last-modified", chrono::DateTime::<chrono::Utc>::from(*modified).format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+                    .body(Full::new(Bytes::new()))
+                    .unwrap(),
+                None => respond(StatusCode::NOT_FOUND, ""),
+            },
+            ("PUT", _) => {
+                objects.insert(object, (body, SystemTime::now()));
+                respond(StatusCode::OK, "")
+            }
+            ("DELETE", _) => {
+                objects.remove(&object);
+                respond(StatusCode::NO_CONTENT, "")
+            }
+            _ => respond(StatusCode::METHOD_NOT_ALLOWED, ""),
+        };
+        Ok(response)
+    }
+
+}
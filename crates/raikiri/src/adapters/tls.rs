@@ -0,0 +1,68 @@
+use std::{pin::Pin, time::Duration};
+
+use openssl::ssl::{select_next_proto, AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslStream;
+use yaml_rust2::Yaml;
+
+use crate::domain::raikiri_env::ThreadSafeError;
+
+// h2 is preferred over http/1.1 when the client offers both
+static ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+// Clients that connect and never finish the handshake are dropped after this long
+static DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConf {
+    pub cert_path: String,
+    pub key_path: String,
+    pub handshake_timeout: Duration,
+}
+
+impl TlsConf {
+    pub fn new(cert_path: String, key_path: String) -> TlsConf {
+        TlsConf { cert_path, key_path, handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT }
+    }
+
+    pub fn with_handshake_timeout(&mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self.clone()
+    }
+
+    // handshake_timeout is in seconds
+    pub fn from_yaml(yaml: &Yaml) -> Result<Option<TlsConf>, ThreadSafeError> {
+        if yaml.is_badvalue() || yaml.is_null() { return Ok(None) }
+        let handshake_timeout = match &yaml["handshake_timeout"] {
+            Yaml::BadValue | Yaml::Null => DEFAULT_HANDSHAKE_TIMEOUT,
+            timeout => Duration::from_secs(timeout.as_i64().and_then(|timeout| u64::try_from(timeout).ok()).ok_or("tls handshake_timeout must be a number of seconds")?),
+        };
+        Ok(Some(TlsConf {
+            cert_path: yaml["cert"].as_str().ok_or("tls is missing cert")?.to_string(),
+            key_path: yaml["key"].as_str().ok_or("tls is missing key")?.to_string(),
+            handshake_timeout,
+        }))
+    }
+
+    pub fn build_acceptor(&self) -> Result<SslAcceptor, ThreadSafeError> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        builder.set_certificate_chain_file(&self.cert_path)?;
+        builder.set_private_key_file(&self.key_path, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        builder.set_alpn_select_callback(|_, client_protocols| {
+            select_next_proto(ALPN_PROTOCOLS, client_protocols).ok_or(AlpnError::NOACK)
+        });
+        Ok(builder.build())
+    }
+}
+
+pub async fn tls_accept<S>(acceptor: &SslAcceptor, stream: S, timeout: Duration) -> Result<SslStream<S>, ThreadSafeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin
+{
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, stream)?;
+    tokio::time::timeout(timeout, Pin::new(&mut stream).accept()).await
+        .map_err(|_| format!("TLS handshake did not finish within {timeout:?}"))??;
+    Ok(stream)
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+use wasmtime::component::{types::ComponentItem, Component, LinkerInstance, ResourceType};
+use wasmtime_wasi_http::bindings::ProxyPre;
+
+use crate::domain::raikiri_env_component::ComponentLinker;
+
+// The interface a component has to export to be invoked, any 0.2 version is accepted
+pub const INCOMING_HANDLER: &str = "wasi:http/incoming-handler@0.2";
+
+// Why an upload was refused. These are errors in the uploaded component, so they are answered with a 4xx
+#[derive(Debug, PartialEq)]
+pub enum ComponentValidationError {
+    // not a wasm component, or one that fails to compile
+    Invalid(String),
+    // imports the linker has no implementation for, or implements with another type
+    MissingImports(Vec<String>),
+    MissingExport(String),
+}
+
+impl ComponentValidationError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ComponentValidationError::Invalid(_) => 400,
+            _ => 422,
+        }
+    }
+}
+
+impl fmt::Display for ComponentValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComponentValidationError::Invalid(error) => write!(f, "invalid component: {error}"),
+            ComponentValidationError::MissingImports(imports) => write!(
+                f,
+                "component is not a wasi:http/proxy component, these imports are not provided: {}",
+                imports.join(", ")
+            ),
+            ComponentValidationError::MissingExport(export) => write!(
+                f,
+                "component is not a wasi:http/proxy component, it must export {export}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ComponentValidationError {}
+
+// Checks that component targets the wasi:http/proxy world: every import is provided by linker
+// and wasi:http/incoming-handler is exported. All missing imports are reported at once, by
+// stubbing each one the linker stops at and type checking again.
+pub fn validate_component(linker: &ComponentLinker, component: &Component) -> Result<(), ComponentValidationError> {
+    let engine = linker.engine();
+    let mut probe = linker.clone();
+    probe.allow_shadowing(true);
+    let mut missing = Vec::new();
+    loop {
+        let error = match probe.instantiate_pre(component) {
+            Ok(_) => break,
+            Err(error) => error,
+        };
+        let import = missing_import_name(&error.to_string())
+            .filter(|import| !missing.contains(import))
+            .ok_or_else(|| ComponentValidationError::Invalid(format!("{error:#}")))?;
+        let Some((_, item)) = component.component_type().imports(engine).find(|(name, _)| *name == import) else {
+            return Err(ComponentValidationError::Invalid(format!("{error:#}")))
+        };
+        stub_import(&mut probe.root(), &import, &item, engine)
+            .map_err(|e| ComponentValidationError::Invalid(format!("{e:#}")))?;
+        missing.push(import);
+    }
+    if !missing.is_empty() {
+        return Err(ComponentValidationError::MissingImports(missing))
+    }
+
+    let exports_handler = component.component_type().exports(engine)
+        .any(|(name, _)| name.starts_with(INCOMING_HANDLER));
+    if !exports_handler {
+        return Err(ComponentValidationError::MissingExport(INCOMING_HANDLER.to_string()))
+    }
+    let instance_pre = linker.instantiate_pre(component).map_err(|e| ComponentValidationError::Invalid(format!("{e:#}")))?;
+    ProxyPre::new(instance_pre).map_err(|e| ComponentValidationError::Invalid(format!("{e:#}")))?;
+    Ok(())
+}
+
+// wasmtime names the first import it cannot resolve as in
+// "component imports instance `my:pkg/api`, but a matching implementation was not found in the linker"
+fn missing_import_name(message: &str) -> Option<String> {
+    let (_, rest) = message.strip_prefix("component imports ")?.split_once('`')?;
+    let (name, _) = rest.split_once('`')?;
+    Some(name.to_string())
+}
+
+fn stub_import<T: 'static>(linker: &mut LinkerInstance<'_, T>, name: &str, item: &ComponentItem, engine: &wasmtime::Engine) -> wasmtime::Result<()> {
+    match item {
+        ComponentItem::ComponentFunc(_) => {
+            linker.func_new(name, |_, _, _| Err(wasmtime::Error::msg("import not provided")))?;
+        }
+        ComponentItem::Resource(_) => {
+            linker.resource(name, ResourceType::host::<()>(), |_, _| Ok(()))?;
+        }
+        ComponentItem::ComponentInstance(instance) => {
+            let mut linker = linker.instance(name)?;
+            for (export_name, export) in instance.exports(engine) {
+                stub_import(&mut linker, export_name, &export, engine)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
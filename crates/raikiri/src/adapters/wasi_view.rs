@@ -1,14 +1,15 @@
 use wasmtime_wasi::{pipe::MemoryOutputPipe, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime_wasi_http::WasiHttpCtx;
 
-use super::context::RaikiriContext;
+use super::{context::RaikiriContext, limits::ComponentLimiter};
 
 pub struct Wasi<T: Send + Clone> {
     pub data: T,
     pub table: ResourceTable,
     pub ctx: WasiCtx,
     pub http_ctx: WasiHttpCtx,
-    pub stdout: MemoryOutputPipe
+    pub stdout: MemoryOutputPipe,
+    pub limiter: ComponentLimiter
 }
 
 impl <T> Wasi<T> where T: Send + Clone + RaikiriContext {
@@ -22,7 +23,7 @@ impl <T> Wasi<T> where T: Send + Clone + RaikiriContext {
             .build();
         let table = ResourceTable::new();
         let http_ctx = WasiHttpCtx::new();
-        Self { data, table, ctx, http_ctx, stdout }
+        Self { data, table, ctx, http_ctx, stdout, limiter: ComponentLimiter::default() }
     }
 }
 
@@ -1,10 +1,15 @@
 pub mod raikiri_env;
 pub mod raikiri_env_fs;
 pub mod raikiri_env_component;
+pub mod raikiri_env_blobs;
 pub mod raikiri_env_secrets;
 pub mod raikiri_env_invoke;
 pub mod raikiri_env_server;
 pub mod raikiri_env_db;
+pub mod raikiri_env_api_gateway;
+pub mod raikiri_env_tenants;
+pub mod raikiri_env_access;
+pub mod raikiri_env_secret_groups;
 
 #[cfg(test)]
 pub mod tests {
@@ -12,11 +17,18 @@ pub mod tests {
     use http_body_util::combinators::BoxBody;
     use hyper::body::Bytes;
 
-    use super::{raikiri_env::RaikiriEnvironment, raikiri_env_server::RaikiriEnvironmentServer};
+    use crate::adapters::tls::TlsConf;
+
+    use super::{raikiri_env::RaikiriEnvironment, raikiri_env_server::RaikiriEnvironmentServer, raikiri_env_tenants::{api_key_path, ApiKey, ApiKeyScope}};
+
+    pub const TEST_API_KEY: &str = "rk_test";
 
     impl Drop for RaikiriEnvironment {
         fn drop(&mut self) {
-            _ = std::fs::remove_dir_all(self.fs_root.clone());
+            // clones share the event receiver, only the last one removes the test dir
+            if std::sync::Arc::strong_count(&self.event_receiver) == 1 {
+                _ = std::fs::remove_dir_all(self.fs_root.clone());
+            }
         }
     }
 
@@ -27,10 +39,43 @@ pub mod tests {
         tmp_path
     }
 
+    pub fn create_test_certificate(dir: &str) -> TlsConf {
+        use openssl::{asn1::Asn1Time, bn::BigNum, ec::{EcGroup, EcKey}, hash::MessageDigest, nid::Nid, pkey::PKey, x509::{extension::SubjectAlternativeName, X509NameBuilder, X509}};
+
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost").build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let tls = TlsConf::new(format!("{dir}/cert.pem"), format!("{dir}/key.pem"));
+        std::fs::write(&tls.cert_path, builder.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&tls.key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        tls
+    }
+
+    // admin commands of the tests are sent with TEST_API_KEY, a platform key of the test tenant
     pub fn create_test_env() -> RaikiriEnvironment {
+        let fs_root = create_test_dir();
+        std::fs::create_dir_all(format!("{fs_root}/api_keys")).unwrap();
+        std::fs::create_dir_all(format!("{fs_root}/tenants")).unwrap();
+        std::fs::write(format!("{fs_root}/tenants/test"), "").unwrap();
+        let api_key = ApiKey::new(TEST_API_KEY, "test".to_string(), ApiKeyScope::Platform);
+        std::fs::write(format!("{fs_root}/{}", api_key_path(TEST_API_KEY)), serde_json::to_vec(&api_key).unwrap()).unwrap();
         RaikiriEnvironment::new()
             .with_username("test".to_string())
-            .with_fs_root(create_test_dir())
+            .with_fs_root(fs_root)
     }
 
     pub async fn make_put_component_request(path: &str, component_name: &str) -> Request<BoxBody<Bytes, hyper::Error>> {
@@ -39,6 +84,7 @@ pub mod tests {
         Request::builder()
             .uri("/")
             .method("POST")
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
             .header("Platform-Command", "Put-Component")
             .header("Component-Id", component_name)
             .body(body)
@@ -51,6 +97,7 @@ pub mod tests {
         Request::builder()
             .uri("https://localhost:8080")
             .method(method)
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
             .header("Platform-Command", "Invoke-Component")
             .header("Component-Id", component_name)
             .header("Host", "localhost:8080")
@@ -62,9 +109,21 @@ pub mod tests {
         Request::builder()
             .uri("/")
             .method("POST")
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
             .header("Platform-Command", "Update-Component-Secrets")
             .header("Component-Id", component_name)
             .body(RaikiriEnvironment::response_body_bytes(body).await)
             .unwrap()
     }
-}
\ No newline at end of file
+
+    pub async fn make_put_api_gateway_request(version: i32, body: &str) -> Request<BoxBody<Bytes, hyper::Error>> {
+        Request::builder()
+            .uri("/")
+            .method("POST")
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Put-Api-Gateway")
+            .header("Api-Gateway-Version", version.to_string())
+            .body(RaikiriEnvironment::response_body(body).await)
+            .unwrap()
+    }
+}
@@ -1,25 +1,44 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use chrono::DateTime;
-use tokio::sync::Mutex;
-use wasmtime::{Config, Engine};
+use tokio::sync::{Mutex, RwLock};
+use wasmtime::Engine;
 use wasmtime_wasi::pipe::MemoryOutputPipe;
 
-use crate::{adapters::{cache::Cache, conf_file::ConfFile}, domain::raikiri_env_component::RaikiriComponentStorage, new_empty_cache};
+use crate::{adapters::{access::ComponentAccess, api_gateway::ApiGateway, body::BodyLimits, cache::Cache, conf_file::ConfFile, crypto::CipherSuite, engine::EngineSettings, limits::{LimitsConf, ResourceLimit}, router::Router, tls::TlsConf}, domain::raikiri_env_component::RaikiriComponentStorage, new_empty_cache};
 
-use super::{raikiri_env_component::ComponentRegistry, raikiri_env_db::RaikiriDBConnection};
+use super::{raikiri_env_api_gateway::RaikiriEnvironmentApiGateway, raikiri_env_blobs::load_code_key, raikiri_env_secrets::{KeyProvider, RaikiriEnvironmentSecrets}, raikiri_env_component::{new_linker, ComponentLinker, ComponentRegistry}, raikiri_env_db::RaikiriDBConnection, raikiri_env_fs::Storage, raikiri_env_server::BindAddress};
 
 #[derive(Clone)]
 pub struct RaikiriEnvironment {
     pub fs_root: String,
+    pub storage: Storage,
     pub username: String,
+    pub engine_settings: EngineSettings,
     pub wasm_engine: Engine,
+    pub linker: Arc<ComponentLinker>,
     pub component_registry: ComponentRegistry,
     pub secrets_cache: Cache<String, Vec<(String, String)>>,
+    pub access_cache: Cache<String, ComponentAccess>,
+    // serializes changes made to the storage by this process, by path, where reading it first would race
+    pub storage_locks: Cache<String, ()>,
+    pub cipher_suite: CipherSuite,
+    pub key_provider: KeyProvider,
+    // signs the code compiled from blobs, never written to the storage
+    pub code_key: Arc<Vec<u8>>,
     pub port: u16,
+    pub bind_address: BindAddress,
+    pub drain_timeout: Duration,
+    pub tls: Option<TlsConf>,
+    pub body_limits: BodyLimits,
+    pub limits: LimitsConf,
     pub conf_file: ConfFile,
+    pub router: Router,
+    pub api_gateway: Arc<RwLock<Option<Arc<ApiGateway>>>>,
     pub event_sender: tokio::sync::mpsc::Sender<ComponentEvent>,
     pub event_receiver: Arc<Mutex<tokio::sync::mpsc::Receiver<ComponentEvent>>>,
+    // the receiver loop started by init, it holds the receiver while waiting for events
+    pub event_loop: Arc<std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>>,
     pub event_handler: Option<fn(ComponentEvent) -> ()>,
     pub db_connections: scc::HashMap<String, Arc<dyn RaikiriDBConnection + Send + Sync>>
 }
@@ -32,32 +51,56 @@ impl Default for RaikiriEnvironment {
 
 impl RaikiriEnvironment {
     pub fn new() -> Self {
-        let mut config = Config::new();
-        config.cache_config_load_default().expect("could not load default cache config");
-        config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
-        config.wasm_component_model(true);
-        config.async_support(true);
-        let wasm_engine = Engine::new(&config).expect("could not create engine");
+        Self::try_new().expect("could not create environment")
+    }
 
+    // Like new, with an error instead of a panic when raikiri.yaml is invalid
+    pub fn try_new() -> Result<Self, ThreadSafeError> {
         let fs_root = format!("/home/{}/.raikiri", whoami::username());
         let username = whoami::username();
 
+        let conf_file = ConfFile::build()?;
+        let router = Router::new(&conf_file.routes)?;
+
+        let storage = conf_file.storage.build(&fs_root);
+        let code_key = load_code_key(&fs_root);
+        let key_provider = conf_file.secrets.keys.build().map_err(|e| format!("could not create key provider: {e}"))?;
+        let engine_settings = conf_file.engine.clone();
+        let wasm_engine = engine_settings.build_engine().map_err(|e| format!("could not create engine: {e}"))?;
+        let linker = Arc::new(new_linker(&wasm_engine));
+
         let (event_sender, event_receiver) = tokio::sync::mpsc::channel(0xFFFF);
         let event_receiver = Arc::new(Mutex::new(event_receiver));
 
-        Self {
+        Ok(Self {
             fs_root,
+            storage,
             username,
+            engine_settings,
             wasm_engine,
+            linker,
             component_registry: new_empty_cache(),
             secrets_cache: new_empty_cache(),
+            access_cache: new_empty_cache(),
+            storage_locks: new_empty_cache(),
+            cipher_suite: conf_file.secrets.cipher,
+            key_provider,
+            code_key,
             port: 0,
-            conf_file: ConfFile::build().unwrap(),
+            bind_address: BindAddress::default(),
+            drain_timeout: Duration::from_secs(30),
+            tls: conf_file.tls.clone(),
+            body_limits: conf_file.body_limits,
+            limits: conf_file.limits.clone(),
+            conf_file,
+            router,
+            api_gateway: Arc::new(RwLock::new(None)),
             event_sender,
             event_receiver,
+            event_loop: Default::default(),
             event_handler: None,
             db_connections: scc::HashMap::default()
-        }
+        })
     }
 
     pub async fn init(&mut self) -> Result<&mut Self, ThreadSafeError> {
@@ -66,13 +109,24 @@ impl RaikiriEnvironment {
         self.component_registry = self.build_registry().await?;
         println!("Successfully registered components");
 
+        // a key rotation interrupted by a crash is rolled back or finished before secrets are read
+        self.recover_crypto_key_rotations().await?;
+
+        if let Some(version) = self.load_active_api_gateway().await? {
+            println!("Loaded api gateway version {version}");
+        }
+
         let _self = self.clone();
 
-        tokio::spawn(async move {
-            while let Some(message) = _self.event_receiver.lock().await.recv().await {
-                _self.event_handler.unwrap_or_else(|| default_event_handler)(message)
+        let event_loop = tokio::spawn(async move {
+            let mut event_receiver = _self.event_receiver.lock().await;
+            while let Some(message) = event_receiver.recv().await {
+                _self.event_handler.unwrap_or(default_event_handler)(message)
             }
         });
+        if let Some(previous) = self.event_loop.lock().unwrap().replace(event_loop) {
+            previous.abort();
+        }
 
         Ok(self)
     }
@@ -82,8 +136,27 @@ impl RaikiriEnvironment {
         self.clone()
     }
 
+    // The storage configured in raikiri.yaml is built again for the new root, which replaces one set by with_storage,
+    // and the code key is the one kept under the new root
     pub fn with_fs_root(&mut self, fs_root: String) -> Self {
         self.fs_root = fs_root;
+        self.storage = self.conf_file.storage.build(&self.fs_root);
+        self.code_key = load_code_key(&self.fs_root);
+        self.clone()
+    }
+
+    pub fn with_storage(&mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self.clone()
+    }
+
+    // Replaces the shared engine and the linker built for it. Components compiled by the previous
+    // engine can't be used with the new one, so this is meant to be called before init.
+    pub fn with_engine_settings(&mut self, engine_settings: EngineSettings) -> Self {
+        self.wasm_engine = engine_settings.build_engine().expect("could not create engine");
+        self.linker = Arc::new(new_linker(&self.wasm_engine));
+        self.engine_settings = engine_settings;
+        self.component_registry = new_empty_cache();
         self.clone()
     }
 
@@ -92,11 +165,71 @@ impl RaikiriEnvironment {
         self.clone()
     }
 
+    pub fn with_bind_address(&mut self, bind_address: BindAddress) -> Self {
+        self.bind_address = bind_address;
+        self.clone()
+    }
+
+    pub fn with_drain_timeout(&mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self.clone()
+    }
+
+    pub fn with_tls(&mut self, tls: Option<TlsConf>) -> Self {
+        self.tls = tls;
+        self.clone()
+    }
+
+    pub fn with_body_limits(&mut self, body_limits: BodyLimits) -> Self {
+        self.body_limits = body_limits;
+        self.clone()
+    }
+
+    pub fn with_limits(&mut self, limits: LimitsConf) -> Self {
+        self.limits = limits;
+        self.clone()
+    }
+
+    pub fn with_cipher_suite(&mut self, cipher_suite: CipherSuite) -> Self {
+        self.cipher_suite = cipher_suite;
+        self.clone()
+    }
+
+    pub fn with_key_provider(&mut self, key_provider: KeyProvider) -> Self {
+        self.key_provider = key_provider;
+        self.clone()
+    }
+
+    pub fn with_code_key(&mut self, code_key: Vec<u8>) -> Self {
+        self.code_key = Arc::new(code_key);
+        self.clone()
+    }
+
+    pub fn with_router(&mut self, router: Router) -> Self {
+        self.router = router;
+        self.clone()
+    }
+
     pub fn with_event_handler(&mut self, handler: fn(ComponentEvent) -> ()) -> &mut Self {
         self.event_handler = Some(handler);
         self
     }
 
+    // Handles the events still queued in the channel. The receiver loop started by init is stopped
+    // first, since it holds the receiver while waiting for events. Handlers are synchronous, so it
+    // can only be stopped between two messages and none is lost. Meant for shutdown.
+    pub async fn flush_events(&self) {
+        let event_loop = self.event_loop.lock().unwrap().take();
+        if let Some(event_loop) = event_loop {
+            event_loop.abort();
+            _ = event_loop.await;
+        }
+        let mut event_receiver = self.event_receiver.lock().await;
+        while let Ok(message) = event_receiver.try_recv() {
+            self.event_handler.unwrap_or(default_event_handler)(message)
+        }
+    }
+
 }
 
 pub enum ComponentEvent {
@@ -106,6 +239,26 @@ pub enum ComponentEvent {
         start: DateTime<chrono::Utc>,
         duration: i64,
         status: u16
+    },
+    LimitExceeded {
+        username_component_name: String,
+        limit: ResourceLimit,
+        start: DateTime<chrono::Utc>,
+        duration: i64
+    },
+    // One of the components compiled by another engine was compiled again, done out of total
+    Recompiled {
+        username_component_name: String,
+        done: usize,
+        total: usize,
+        duration: i64,
+        error: Option<String>
+    },
+    // A call to a component was refused by its access file, caller is None for routed HTTP requests
+    AccessDenied {
+        username_component_name: String,
+        caller: Option<String>,
+        reason: String
     }
 }
 
@@ -120,5 +273,19 @@ pub fn default_event_handler(message: ComponentEvent) {
             let start_text = start.to_rfc3339();
             println!("Started {username_component_name} at {start_text} and finished in {duration}ms. Status code: {status}");
         }
+        ComponentEvent::LimitExceeded { username_component_name, limit, start, duration } => {
+            let start_text = start.to_rfc3339();
+            println!("Started {username_component_name} at {start_text} and was stopped after {duration}ms for exceeding its {limit} limit");
+        }
+        ComponentEvent::Recompiled { username_component_name, done, total, duration, error } => {
+            match error {
+                None => println!("[{done}/{total}] Recompiled {username_component_name} in {duration}ms"),
+                Some(error) => eprintln!("[{done}/{total}] Could not recompile {username_component_name}: {error}"),
+            }
+        }
+        ComponentEvent::AccessDenied { username_component_name, caller, reason } => {
+            let caller = caller.unwrap_or_else(|| "HTTP request".to_string());
+            eprintln!("Denied {caller} access to {username_component_name}: {reason}");
+        }
     }
 }
\ No newline at end of file
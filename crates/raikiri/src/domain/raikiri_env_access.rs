@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+
+use crate::adapters::access::{ComponentAccess, Visibility};
+
+use super::{raikiri_env::{ComponentEvent, RaikiriEnvironment, ThreadSafeError}, raikiri_env_fs::RaikiriEnvironmentFS};
+
+// Components without an access file are public and can be called by every component.
+// Access files are kept in access/{user}.{name}.yaml.
+#[async_trait]
+pub trait RaikiriEnvironmentAccess {
+    async fn get_component_access(&self, user: String, name: String) -> Result<ComponentAccess, ThreadSafeError>;
+    async fn update_component_access(&self, user: String, name: String, yml_bytes: Vec<u8>) -> Result<(), ThreadSafeError>;
+    async fn remove_component_access(&self, user: String, name: String) -> Result<(), ThreadSafeError>;
+    async fn check_component_access(&self, username_component_name: &str, caller: Option<&str>) -> Result<(), String>;
+}
+
+#[async_trait]
+impl RaikiriEnvironmentAccess for RaikiriEnvironment {
+
+    async fn get_component_access(&self, user: String, name: String) -> Result<ComponentAccess, ThreadSafeError> {
+        let access_path = format!("access/{user}.{name}.yaml");
+        if !self.file_exists(&access_path).await { return Ok(ComponentAccess::default()) }
+        ComponentAccess::from_yaml(&self.read_file(access_path).await?)
+    }
+
+    async fn update_component_access(&self, user: String, name: String, yml_bytes: Vec<u8>) -> Result<(), ThreadSafeError> {
+        let access = ComponentAccess::from_yaml(&yml_bytes)?;
+        self.write_file(format!("access/{user}.{name}.yaml"), yml_bytes).await?;
+        self.access_cache.replace_entry(format!("{user}.{name}"), access).await;
+        Ok(())
+    }
+
+    async fn remove_component_access(&self, user: String, name: String) -> Result<(), ThreadSafeError> {
+        let access_path = format!("access/{user}.{name}.yaml");
+        if self.file_exists(&access_path).await {
+            self.remove_file(access_path).await?;
+        }
+        self.access_cache.destroy_gracefully_entry_by_key(format!("{user}.{name}")).await;
+        Ok(())
+    }
+
+    // caller is the component calling through raikiri.components, None for routed HTTP requests.
+    // Refused calls are reported with a ComponentEvent::AccessDenied.
+    async fn check_component_access(&self, username_component_name: &str, caller: Option<&str>) -> Result<(), String> {
+        let access = self.access_cache.get_entry_by_key_async_build(username_component_name.to_string(), async {
+            let (user, name) = username_component_name.split_once('.').unwrap_or((username_component_name, ""));
+            // an unreadable access file keeps the component to its owner
+            self.get_component_access(user.to_string(), name.to_string()).await.unwrap_or_else(|e| {
+                eprintln!("error reading access of {username_component_name}: {e}");
+                ComponentAccess { visibility: Visibility::Owner, allowed_callers: Vec::new() }
+            })
+        }).await;
+        let access = access.read().await.clone();
+        let allowed = match caller {
+            Some(caller) => access.allows_caller(username_component_name, caller),
+            None => access.allows_http(),
+        };
+        if let Err(reason) = &allowed {
+            _ = self.event_sender.send(ComponentEvent::AccessDenied {
+                username_component_name: username_component_name.to_string(),
+                caller: caller.map(str::to_string),
+                reason: reason.clone(),
+            }).await;
+        }
+        allowed
+    }
+}
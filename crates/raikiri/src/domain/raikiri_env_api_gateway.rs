@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::adapters::api_gateway::ApiGateway;
+
+use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_fs::RaikiriEnvironmentFS};
+
+#[async_trait]
+pub trait RaikiriEnvironmentApiGateway {
+    async fn put_api_gateway(&self, version: i32, yml_bytes: Vec<u8>) -> Result<(), ThreadSafeError>;
+    async fn activate_api_gateway(&self, version: i32) -> Result<(), ThreadSafeError>;
+    async fn load_active_api_gateway(&self) -> Result<Option<i32>, ThreadSafeError>;
+}
+
+#[async_trait]
+impl RaikiriEnvironmentApiGateway for RaikiriEnvironment {
+    async fn put_api_gateway(&self, version: i32, yml_bytes: Vec<u8>) -> Result<(), ThreadSafeError> {
+        ApiGateway::from_yaml(version, &yml_bytes)?;
+        self.write_file(format!("api_gateways/{version}.yaml"), yml_bytes).await
+    }
+
+    // Swaps the gateway served by run_server. Requests already being handled keep the previous version.
+    async fn activate_api_gateway(&self, version: i32) -> Result<(), ThreadSafeError> {
+        let yml_bytes = self.read_file(format!("api_gateways/{version}.yaml")).await
+            .map_err(|_| format!("api gateway version {version} not found"))?;
+        let api_gateway = ApiGateway::from_yaml(version, &yml_bytes)?;
+        self.write_file("api_gateways/current", version.to_string().into_bytes()).await?;
+        *self.api_gateway.write().await = Some(Arc::new(api_gateway));
+        Ok(())
+    }
+
+    async fn load_active_api_gateway(&self) -> Result<Option<i32>, ThreadSafeError> {
+        if !self.file_exists("api_gateways/current").await { return Ok(None) }
+        let version = String::from_utf8(self.read_file("api_gateways/current").await?)?.trim().parse::<i32>()?;
+        self.activate_api_gateway(version).await?;
+        Ok(Some(version))
+    }
+}
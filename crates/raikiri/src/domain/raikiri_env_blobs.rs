@@ -0,0 +1,336 @@
+use std::{collections::HashSet, io::Write, os::unix::fs::OpenOptionsExt, sync::Arc};
+
+use async_trait::async_trait;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use tokio::sync::RwLock;
+use wasmtime::component::Component;
+
+use crate::adapters::engine::{compile_component, engine_fingerprint};
+
+use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_component::RaikiriComponentStorage, raikiri_env_fs::RaikiriEnvironmentFS};
+
+// Components are stored once however many users or revisions point at them, in a directory named
+// after the SHA-256 of their wasm, with the code compiled by each engine next to it:
+//
+//     blobs/{sha256}/source.wasm
+//     blobs/{sha256}/{engine}.cwasm
+//     blobs/{sha256}/{engine}.mac
+//
+// The source is checked against the hash it is named after. Compiled code is only deserialized when
+// its HMAC, made with the code key of the environment, matches: the key is not kept in the storage,
+// so code written there by anyone else, or compiled by a process with another key, is compiled again
+// from the source. So is code missing for the engine, after a wasmtime upgrade or a change of settings.
+#[async_trait]
+pub trait RaikiriBlobStore {
+    async fn put_blob(&self, hash: &str, source: Vec<u8>, component: &Component) -> Result<(), ThreadSafeError>;
+    async fn is_compiled_blob(&self, hash: &str) -> bool;
+    async fn put_compiled_blob(&self, compiled: Vec<u8>) -> Result<String, ThreadSafeError>;
+    async fn read_compiled_blob(&self, hash: &str) -> Result<Vec<u8>, ThreadSafeError>;
+    async fn load_blob(&self, hash: &str) -> Result<Component, ThreadSafeError>;
+    async fn blob_size(&self, hash: &str) -> Result<u64, ThreadSafeError>;
+    async fn remove_unreferenced_blobs(&self) -> Result<(), ThreadSafeError>;
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    openssl::sha::sha256(bytes).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// The key compiled code is signed with. It is generated once and kept in {fs_root}/.code_key, on the
+// node and outside of the storage, so compiled code is reused across restarts and by the CLI. Nodes
+// sharing a storage reuse each other's code when they are given the same RAIKIRI_CODE_KEY instead.
+pub fn load_code_key(fs_root: &str) -> Arc<Vec<u8>> {
+    if let Ok(key) = std::env::var("RAIKIRI_CODE_KEY") {
+        if !key.is_empty() { return Arc::new(openssl::sha::sha256(key.as_bytes()).to_vec()) }
+    }
+    match read_or_create_code_key(&format!("{fs_root}/.code_key")) {
+        Ok(key) => Arc::new(key),
+        Err(e) => {
+            eprintln!("could not keep a code key in {fs_root}/.code_key, components will be compiled again on every start: {e}");
+            let mut key = vec![0; 32];
+            openssl::rand::rand_bytes(&mut key).expect("could not generate code key");
+            Arc::new(key)
+        }
+    }
+}
+
+// The key is written to a file of its own and linked into place, so processes starting together agree on one
+fn read_or_create_code_key(path: &str) -> Result<Vec<u8>, ThreadSafeError> {
+    if !std::path::Path::new(path).exists() {
+        let mut key = vec![0; 32];
+        openssl::rand::rand_bytes(&mut key)?;
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = format!("{path}.{}", uuid::Uuid::new_v4());
+        let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp_path)?;
+        file.write_all(&key)?;
+        file.sync_all()?;
+        let linked = std::fs::hard_link(&tmp_path, path);
+        std::fs::remove_file(&tmp_path)?;
+        match linked {
+            Ok(()) => return Ok(key),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let key = std::fs::read(path)?;
+    if key.len() != 32 { return Err(format!("{path} is not a code key").into()) }
+    Ok(key)
+}
+
+// Blobs are written before the revision pointing at them. Writers share this lock until their
+// revision is stored, the removal of unreferenced blobs holds it alone.
+pub async fn blobs_lock(environment: &RaikiriEnvironment) -> Arc<RwLock<()>> {
+    environment.storage_locks.get_entry_by_key("blobs".to_string(), || ()).await
+}
+
+#[async_trait]
+impl RaikiriBlobStore for RaikiriEnvironment {
+
+    // The source is written once, identical uploads only add code for engines that lack it
+    async fn put_blob(&self, hash: &str, source: Vec<u8>, component: &Component) -> Result<(), ThreadSafeError> {
+        self.create_dir(format!("blobs/{hash}")).await?;
+        if !self.file_exists(format!("blobs/{hash}/source.wasm")).await {
+            self.write_file(format!("blobs/{hash}/source.wasm"), source).await?;
+        }
+        if self.verified_compiled(hash).await.is_none() {
+            self.write_compiled(hash, component.serialize()?).await?;
+        }
+        Ok(())
+    }
+
+    // Whether this engine has compiled the blob already, loading it is then only a deserialization
+    async fn is_compiled_blob(&self, hash: &str) -> bool {
+        self.verified_compiled(hash).await.is_some()
+    }
+
+    // Components stored before sources were kept only have their compiled code, they are named after its hash.
+    // Nothing vouches for that code, so it is kept unsigned and never loaded: those components have to be uploaded again.
+    async fn put_compiled_blob(&self, compiled: Vec<u8>) -> Result<String, ThreadSafeError> {
+        let hash = sha256_hex(&compiled);
+        self.create_dir(format!("blobs/{hash}")).await?;
+        self.write_file(self.compiled_path(&hash), compiled).await?;
+        Ok(hash)
+    }
+
+    async fn read_compiled_blob(&self, hash: &str) -> Result<Vec<u8>, ThreadSafeError> {
+        if let Some(compiled) = self.verified_compiled(hash).await {
+            return Ok(compiled)
+        }
+
+        if !self.file_exists(format!("blobs/{hash}/source.wasm")).await {
+            return Err(format!("blob {hash} has no source to compile it from, its component has to be uploaded again").into())
+        }
+        let source = self.read_file(format!("blobs/{hash}/source.wasm")).await?;
+        if sha256_hex(&source) != hash {
+            return Err(format!("blob {hash} does not match its hash").into())
+        }
+        println!("compiling blob {hash} for engine {}", engine_fingerprint(&self.wasm_engine));
+        let compiled = compile_component(&self.wasm_engine, source).await?.serialize()?;
+        self.write_compiled(hash, compiled.clone()).await?;
+        Ok(compiled)
+    }
+
+    async fn load_blob(&self, hash: &str) -> Result<Component, ThreadSafeError> {
+        let compiled = self.read_compiled_blob(hash).await?;
+        // the code was either compiled from the source or signed by a process holding the code key
+        unsafe { Ok(Component::deserialize(&self.wasm_engine, &compiled)?) }
+    }
+
+    async fn blob_size(&self, hash: &str) -> Result<u64, ThreadSafeError> {
+        match self.file_metadata(format!("blobs/{hash}/source.wasm")).await {
+            Ok(metadata) => Ok(metadata.len),
+            Err(_) => Ok(self.file_metadata(self.compiled_path(hash)).await?.len)
+        }
+    }
+
+    async fn remove_unreferenced_blobs(&self) -> Result<(), ThreadSafeError> {
+        let lock = blobs_lock(self).await;
+        let _lock = lock.write().await;
+        let mut referenced = HashSet::new();
+        for username_component_name in self.read_dir("components").await? {
+            let Some((user, name)) = username_component_name.split_once('.') else { continue };
+            for revision in self.list_component_revisions(user.to_string(), name.to_string()).await? {
+                referenced.insert(revision.hash);
+            }
+        }
+        for hash in self.read_dir("blobs").await? {
+            if !referenced.contains(&hash) {
+                self.remove_dir(format!("blobs/{hash}")).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RaikiriEnvironment {
+    fn compiled_path(&self, hash: &str) -> String {
+        format!("blobs/{hash}/{}.cwasm", engine_fingerprint(&self.wasm_engine))
+    }
+
+    fn compiled_mac_path(&self, hash: &str) -> String {
+        format!("blobs/{hash}/{}.mac", engine_fingerprint(&self.wasm_engine))
+    }
+
+    // Covers the blob and the engine the code is for as well, so signed code can't be moved to another blob
+    fn compiled_mac(&self, hash: &str, compiled: &[u8]) -> Result<Vec<u8>, ThreadSafeError> {
+        let key = PKey::hmac(&self.code_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(hash.as_bytes())?;
+        signer.update(engine_fingerprint(&self.wasm_engine).as_bytes())?;
+        signer.update(compiled)?;
+        Ok(signer.sign_to_vec()?)
+    }
+
+    // The compiled code of the blob, when there is some signed with our code key
+    async fn verified_compiled(&self, hash: &str) -> Option<Vec<u8>> {
+        let mac = self.read_file(self.compiled_mac_path(hash)).await.ok()?;
+        let compiled = self.read_file(self.compiled_path(hash)).await.ok()?;
+        let expected = self.compiled_mac(hash, &compiled).ok()?;
+        if mac.len() == expected.len() && openssl::memcmp::eq(&mac, &expected) {
+            return Some(compiled)
+        }
+        eprintln!("compiled code of blob {hash} was not signed with our code key, compiling it again");
+        None
+    }
+
+    // The mac is written last, code without a valid one is compiled again
+    async fn write_compiled(&self, hash: &str, compiled: Vec<u8>) -> Result<(), ThreadSafeError> {
+        let mac = self.compiled_mac(hash, &compiled)?;
+        self.write_file(self.compiled_path(hash), compiled).await?;
+        self.write_file(self.compiled_mac_path(hash), mac).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{adapters::engine::{engine_fingerprint, EngineSettings}, domain::{raikiri_env::RaikiriEnvironment, raikiri_env_component::RaikiriComponentStorage, raikiri_env_fs::RaikiriEnvironmentFS, tests::create_test_env}};
+
+    use super::{sha256_hex, RaikiriBlobStore};
+
+    #[tokio::test]
+    async fn test_blobs_are_verified_and_recompiled() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let source = std::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).unwrap();
+        let hash = sha256_hex(&source);
+        let component = wasmtime::component::Component::from_binary(&environment.wasm_engine, &source).unwrap();
+        environment.put_blob(&hash, source.clone(), &component).await.unwrap();
+        assert!(environment.load_blob(&hash).await.is_ok());
+
+        // tampered code is compiled again from the source
+        environment.write_file(environment.compiled_path(&hash), b"not a component".to_vec()).await.unwrap();
+        assert!(environment.load_blob(&hash).await.is_ok());
+
+        // even when whoever changed it wrote a checksum of their own next to it
+        environment.write_file(environment.compiled_path(&hash), b"not a component".to_vec()).await.unwrap();
+        environment.write_file(environment.compiled_mac_path(&hash), openssl::sha::sha256(b"not a component").to_vec()).await.unwrap();
+        assert!(!environment.is_compiled_blob(&hash).await);
+        assert!(environment.load_blob(&hash).await.is_ok());
+
+        // as is code missing for the engine, like after an upgrade
+        environment.remove_file(environment.compiled_mac_path(&hash)).await.unwrap();
+        assert!(environment.load_blob(&hash).await.is_ok());
+        assert!(environment.is_compiled_blob(&hash).await);
+
+        // the key is kept on the node, so processes using the same root share it
+        let restarted = RaikiriEnvironment::new().with_fs_root(environment.fs_root.clone());
+        assert_eq!(restarted.code_key, environment.code_key);
+        assert!(restarted.is_compiled_blob(&hash).await);
+        let metadata = std::fs::metadata(format!("{}/.code_key", environment.fs_root)).unwrap();
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o777, 0o600);
+
+        // and code signed by a process holding another key
+        let other = RaikiriEnvironment::new().with_fs_root(environment.fs_root.clone()).with_code_key(b"other".to_vec());
+        assert!(!other.is_compiled_blob(&hash).await);
+        assert!(other.load_blob(&hash).await.is_ok());
+        assert!(other.is_compiled_blob(&hash).await);
+
+        // a source that does not match its hash is never compiled
+        environment.remove_file(environment.compiled_mac_path(&hash)).await.unwrap();
+        environment.write_file(format!("blobs/{hash}/source.wasm"), b"not a component".to_vec()).await.unwrap();
+        assert!(environment.load_blob(&hash).await.err().unwrap().to_string().contains("does not match its hash"));
+    }
+
+    #[tokio::test]
+    async fn test_identical_uploads_share_a_blob() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let source = std::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).unwrap();
+        environment.add_component("alice".to_string(), "hello".to_string(), source.clone()).await.unwrap();
+        environment.add_component("bob".to_string(), "greeter".to_string(), source.clone()).await.unwrap();
+        assert_eq!(environment.read_dir("blobs").await.unwrap(), vec![sha256_hex(&source)]);
+
+        environment.remove_component("alice".to_string(), "hello".to_string()).await.unwrap();
+        assert_eq!(environment.read_dir("blobs").await.unwrap().len(), 1);
+        environment.remove_component("bob".to_string(), "greeter".to_string()).await.unwrap();
+        assert!(environment.read_dir("blobs").await.unwrap().is_empty());
+
+        // the blob of an upload is kept while another user removes the last component pointing at it
+        for _ in 0..5 {
+            environment.add_component("alice".to_string(), "hello".to_string(), source.clone()).await.unwrap();
+            let (removed, added) = tokio::join!(
+                environment.remove_component("alice".to_string(), "hello".to_string()),
+                environment.add_component("bob".to_string(), "greeter".to_string(), source.clone()),
+            );
+            removed.unwrap();
+            let revision = added.unwrap();
+            assert!(environment.get_component_revision("bob".to_string(), "greeter".to_string(), revision).await.is_ok());
+            environment.remove_component("bob".to_string(), "greeter".to_string()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compiled_components_are_moved_into_blobs() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let source = std::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).unwrap();
+        let compiled = wasmtime::component::Component::from_binary(&environment.wasm_engine, &source).unwrap().serialize().unwrap();
+        environment.write_file("components/test.legacy.aot.wasm", compiled.clone()).await.unwrap();
+
+        let source = std::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).unwrap();
+        environment.add_component("test".to_string(), "hello".to_string(), source).await.unwrap();
+        environment.create_dir("components/test.broken").await.unwrap();
+        environment.write_file("components/test.broken/current", b"7".to_vec()).await.unwrap();
+
+        // nothing vouches for code compiled by earlier versions, it is kept but not loaded, and the rest still is
+        let registry = environment.build_registry().await.unwrap();
+        assert!(registry.get_entry(&"test.legacy".to_string()).await.is_none());
+        assert!(registry.get_entry(&"test.hello".to_string()).await.is_some());
+        assert!(registry.get_entry(&"test.broken".to_string()).await.is_none());
+        assert!(environment.read_dir("blobs").await.unwrap().contains(&sha256_hex(&compiled)));
+        assert!(!environment.is_compiled_blob(&sha256_hex(&compiled)).await);
+        assert_eq!(environment.list_component_revisions("test".to_string(), "legacy".to_string()).await.unwrap()[0].hash, sha256_hex(&compiled));
+    }
+
+    #[tokio::test]
+    async fn test_recompile_in_background_on_engine_change() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let source = std::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).unwrap();
+        environment.add_component("test".to_string(), "hello".to_string(), source.clone()).await.unwrap();
+
+        let upgraded = RaikiriEnvironment::new()
+            .with_engine_settings(EngineSettings { simd: false, ..Default::default() })
+            .with_fs_root(environment.fs_root.clone());
+        assert_ne!(engine_fingerprint(&upgraded.wasm_engine), engine_fingerprint(&environment.wasm_engine));
+
+        // nothing is compiled before build_registry returns
+        let registry = upgraded.build_registry().await.unwrap();
+        assert!(registry.get_entry(&"test.hello".to_string()).await.is_none());
+
+        for _ in 0..200 {
+            if registry.get_entry(&"test.hello".to_string()).await.is_some() { break }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(registry.get_entry(&"test.hello".to_string()).await.is_some());
+        assert!(upgraded.is_compiled_blob(&sha256_hex(&source)).await);
+    }
+}
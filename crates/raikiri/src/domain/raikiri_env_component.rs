@@ -1,61 +1,524 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+
 use async_trait::async_trait;
-use wasmtime::{component::Component, Config, Engine};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use object::{Object, ObjectSection};
+use serde::Serialize;
+use wasmtime::{component::{Component, Linker}, Engine};
+use wasmtime_wasi_http::bindings::ProxyPre;
+
+use crate::{adapters::{access::ComponentAccess, cache::Cache, componentize::componentize, engine::compile_component, validation::{validate_component, ComponentValidationError}}, new_empty_cache, ComponentImports, Wasi};
+
+use super::{raikiri_env::{ComponentEvent, RaikiriEnvironment, ThreadSafeError}, raikiri_env_access::RaikiriEnvironmentAccess, raikiri_env_blobs::{blobs_lock, sha256_hex, RaikiriBlobStore}, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_secret_groups::{RaikiriEnvironmentSecretGroups, ResolvedSecret}, raikiri_env_secrets::RaikiriEnvironmentSecrets};
 
-use crate::{adapters::cache::Cache, new_empty_cache};
+pub type ComponentLinker = Linker<Wasi<ComponentImports>>;
+pub type ComponentRegistry = Cache<String, RegisteredComponent>;
 
-use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_fs::RaikiriEnvironmentFS};
+// A compiled component with its imports already resolved against the shared linker,
+// so an invocation only has to create a store and instantiate it
+pub struct RegisteredComponent {
+    pub component: Component,
+    pub proxy_pre: ProxyPre<Wasi<ComponentImports>>,
+    pub revision: ComponentRevision,
+}
 
-pub type ComponentRegistry = Cache<String, Component>;
+// An immutable upload of a component. Revisions are numbered from 1 in upload order and
+// point at the blob holding their wasm, by its SHA-256.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComponentRevision {
+    pub revision: u32,
+    pub hash: String,
+}
+
+// What component list shows about the current revision of a component
+#[derive(Clone, Debug, Serialize)]
+pub struct ComponentInfo {
+    pub owner: String,
+    pub name: String,
+    pub revision: u32,
+    pub hash: String,
+    // size of the component wasm, in bytes
+    pub size: u64,
+    pub uploaded_at: String,
+    // why the current revision could not be read, the other fields are then left empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ComponentInspection {
+    #[serde(flatten)]
+    pub info: ComponentInfo,
+    pub revisions: Vec<u32>,
+    pub imports: Vec<String>,
+    pub exports: Vec<String>,
+    pub wasmtime_version: String,
+    pub has_secrets: bool,
+    // the groups secrets are taken from, in the order they were attached
+    pub secret_groups: Vec<String>,
+    pub secrets: Vec<ResolvedSecret>,
+    pub access: ComponentAccess,
+}
 
 #[async_trait]
 pub trait RaikiriComponentStorage {
-    async fn add_component(&self, user: String, name: String, component_bytes: Vec<u8>) -> Result<(), ThreadSafeError>;
+    async fn add_component(&self, user: String, name: String, component_bytes: Vec<u8>) -> Result<u32, ThreadSafeError>;
     async fn component_exists(&self, user: String, name: String) -> bool;
-    async fn get_component(&self, user: String, name: String) -> Result<Component, ThreadSafeError>;
+    async fn get_component_revision(&self, user: String, name: String, revision: u32) -> Result<Component, ThreadSafeError>;
+    async fn list_component_revisions(&self, user: String, name: String) -> Result<Vec<ComponentRevision>, ThreadSafeError>;
+    async fn get_current_revision(&self, user: String, name: String) -> Result<Option<u32>, ThreadSafeError>;
+    async fn set_current_revision(&self, user: String, name: String, revision: u32) -> Result<(), ThreadSafeError>;
     async fn remove_component(&self, user: String, name: String) -> Result<(), ThreadSafeError>;
+    async fn list_components(&self) -> Result<Vec<ComponentInfo>, ThreadSafeError>;
+    async fn inspect_component(&self, user: String, name: String) -> Result<ComponentInspection, ThreadSafeError>;
+    fn register_component(&self, component: Component, revision: ComponentRevision) -> Result<RegisteredComponent, ThreadSafeError>;
+    async fn reload_component(&self, user: String, name: String) -> Result<(), ThreadSafeError>;
     async fn build_registry(&self) -> Result<ComponentRegistry, ThreadSafeError>;
+    fn watch_components(&self) -> Result<ComponentWatcher, ThreadSafeError>;
+}
+
+// Reloads components while it is alive. Storage on local disk is watched for file events, other
+// backends are polled, since components changed there by other nodes raise no events here.
+pub struct ComponentWatcher {
+    _files: Option<RecommendedWatcher>,
+    poll: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for ComponentWatcher {
+    fn drop(&mut self) {
+        if let Some(poll) = &self.poll { poll.abort() }
+    }
+}
+
+static COMPONENTS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Built once per engine, the WASI and wasi:http host functions are the same for every component
+pub fn new_linker(engine: &Engine) -> ComponentLinker {
+    let mut linker = Linker::new(engine);
+    linker.allow_shadowing(true);
+    wasmtime_wasi::add_to_linker_async(&mut linker).unwrap();
+    wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).unwrap();
+    linker
+}
+
+// The current revision of a component is registered as user.name, pinned revisions as user.name@revision
+pub fn registry_key(username_component_name: &str, revision: u32) -> String {
+    format!("{username_component_name}@{revision}")
+}
+
+impl ComponentRevision {
+    fn filename(&self) -> String {
+        format!("{}-{}", self.revision, self.hash)
+    }
+
+    fn from_filename(filename: &str) -> Option<ComponentRevision> {
+        let (revision, hash) = filename.split_once('-')?;
+        if !hash.chars().all(|c| c.is_ascii_hexdigit()) { return None }
+        Some(ComponentRevision { revision: revision.parse().ok()?, hash: hash.to_string() })
+    }
 }
 
+// Each component is a directory of revisions plus a current file holding the revision being served.
+// Revisions are empty files naming the blob they point at, see RaikiriBlobStore:
+//
+//     components/{user}.{name}/current
+//     components/{user}.{name}/{revision}-{sha256}
 #[async_trait]
 impl RaikiriComponentStorage for RaikiriEnvironment {
-    async fn add_component(&self, user: String, name: String, component_bytes: Vec<u8>) -> Result<(), ThreadSafeError> {
-        let component = Component::from_binary(&self.wasm_engine, &component_bytes).expect("error compiling wasm component");
-        let component_bytes = component.serialize().expect("error serializing component to file");
-        self.write_file(format!("components/{user}.{name}.aot.wasm"), component_bytes).await
+    // Turns core modules into components and validates the upload against the proxy world, then
+    // stores it as a new revision and makes it current. Uploading the same wasm as the current
+    // revision again keeps it, instead of adding an identical revision.
+    async fn add_component(&self, user: String, name: String, component_bytes: Vec<u8>) -> Result<u32, ThreadSafeError> {
+        let component_bytes = componentize(component_bytes)?;
+        let hash = sha256_hex(&component_bytes);
+        // concurrent uploads of the component would otherwise number their revisions alike
+        let lock = self.storage_locks.get_entry_by_key(format!("components/{user}.{name}"), || ()).await;
+        let _lock = lock.write().await;
+        let revisions = self.list_component_revisions(user.clone(), name.clone()).await?;
+        let current = self.get_current_revision(user.clone(), name.clone()).await?;
+        if let Some(current) = revisions.iter().find(|revision| Some(revision.revision) == current) {
+            if current.hash == hash { return Ok(current.revision) }
+        }
+
+        let component = compile_component(&self.wasm_engine, component_bytes.clone()).await
+            .map_err(|e| ComponentValidationError::Invalid(format!("{e:#}")))?;
+        validate_component(&self.linker, &component)?;
+        let blobs = blobs_lock(self).await;
+        let _blobs = blobs.read().await;
+        self.put_blob(&hash, component_bytes, &component).await?;
+        let revision = ComponentRevision {
+            revision: revisions.iter().map(|revision| revision.revision).max().unwrap_or(0) + 1,
+            hash,
+        };
+        self.create_dir(format!("components/{user}.{name}")).await?;
+        self.write_file(format!("components/{user}.{name}/{}", revision.filename()), Vec::new()).await?;
+        self.write_current_revision(user, name, revision.revision).await?;
+        Ok(revision.revision)
     }
 
     async fn component_exists(&self, user: String, name: String) -> bool {
-        self.file_exists(format!("components/{user}.{name}.aot.wasm")).await
+        self.file_exists(format!("components/{user}.{name}/current")).await
+    }
+
+    async fn get_component_revision(&self, user: String, name: String, revision: u32) -> Result<Component, ThreadSafeError> {
+        let found = self.find_component_revision(&user, &name, revision).await?;
+        self.load_blob(&found.hash).await
+    }
+
+    async fn list_component_revisions(&self, user: String, name: String) -> Result<Vec<ComponentRevision>, ThreadSafeError> {
+        if !self.file_exists(format!("components/{user}.{name}")).await { return Ok(Vec::new()) }
+        let mut revisions = self.read_dir(format!("components/{user}.{name}")).await?
+            .iter()
+            .filter_map(|filename| ComponentRevision::from_filename(filename))
+            .collect::<Vec<_>>();
+        revisions.sort_by_key(|revision| revision.revision);
+        Ok(revisions)
+    }
+
+    async fn get_current_revision(&self, user: String, name: String) -> Result<Option<u32>, ThreadSafeError> {
+        if !self.component_exists(user.clone(), name.clone()).await { return Ok(None) }
+        let current = self.read_file(format!("components/{user}.{name}/current")).await?;
+        Ok(Some(String::from_utf8(current)?.trim().parse()?))
     }
 
-    async fn get_component(&self, user: String, name: String) -> Result<Component, ThreadSafeError> {
-        let component_bytes = self.read_file(format!("components/{user}.{name}.aot.wasm")).await?;
-        unsafe { Ok(Component::deserialize(&self.wasm_engine, &component_bytes).expect("error compiling wasm component")) }
+    // Also used to roll back, under the lock uploads take so both change and reload the component in turn
+    async fn set_current_revision(&self, user: String, name: String, revision: u32) -> Result<(), ThreadSafeError> {
+        let lock = self.storage_locks.get_entry_by_key(format!("components/{user}.{name}"), || ()).await;
+        let _lock = lock.write().await;
+        self.write_current_revision(user, name, revision).await
     }
 
+    // Blobs are removed with the last revision pointing at them
     async fn remove_component(&self, user: String, name: String) -> Result<(), ThreadSafeError> {
-        self.remove_file(format!("components/{user}.{name}.aot.wasm")).await
+        self.remove_dir(format!("components/{user}.{name}")).await?;
+        self.remove_component_access(user.clone(), name.clone()).await?;
+        self.remove_component_secrets(user.clone(), name.clone()).await?;
+        self.remove_attached_secret_groups(user.clone(), name.clone()).await?;
+        self.reload_component(user, name).await?;
+        self.remove_unreferenced_blobs().await
     }
 
+    // A component whose current revision can't be read is listed with the error, after the others
+    async fn list_components(&self) -> Result<Vec<ComponentInfo>, ThreadSafeError> {
+        let mut components = Vec::new();
+        for username_component_name in self.read_dir("components").await? {
+            let Some((user, name)) = username_component_name.split_once('.') else { continue };
+            if name.ends_with(".aot.wasm") { continue }
+            let info = async {
+                let Some(revision) = self.get_current_revision(user.to_string(), name.to_string()).await? else { return Ok(None) };
+                let revision = self.find_component_revision(user, name, revision).await?;
+                Ok::<_, ThreadSafeError>(Some(self.component_info(user, name, revision).await?))
+            };
+            match info.await {
+                Ok(Some(info)) => components.push(info),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("error reading {username_component_name}: {e}");
+                    components.push(ComponentInfo {
+                        owner: user.to_string(),
+                        name: name.to_string(),
+                        revision: 0,
+                        hash: String::new(),
+                        size: 0,
+                        uploaded_at: String::new(),
+                        error: Some(e.to_string()),
+                    });
+                }
+            }
+        }
+        components.sort_by(|a, b| (a.error.is_some(), &a.owner, &a.name).cmp(&(b.error.is_some(), &b.owner, &b.name)));
+        Ok(components)
+    }
+
+    async fn inspect_component(&self, user: String, name: String) -> Result<ComponentInspection, ThreadSafeError> {
+        let revision = self.get_current_revision(user.clone(), name.clone()).await?
+            .ok_or(format!("component {user}.{name} not found"))?;
+        let revision = self.find_component_revision(&user, &name, revision).await?;
+        let component_bytes = self.read_compiled_blob(&revision.hash).await?;
+        let component = unsafe { Component::deserialize(&self.wasm_engine, &component_bytes)? };
+        let component_type = component.component_type();
+        Ok(ComponentInspection {
+            info: self.component_info(&user, &name, revision).await?,
+            revisions: self.list_component_revisions(user.clone(), name.clone()).await?
+                .iter()
+                .map(|revision| revision.revision)
+                .collect(),
+            imports: component_type.imports(&self.wasm_engine).map(|(name, _)| name.to_string()).collect(),
+            exports: component_type.exports(&self.wasm_engine).map(|(name, _)| name.to_string()).collect(),
+            wasmtime_version: compiled_wasmtime_version(&component_bytes)?,
+            has_secrets: self.component_has_secrets(user.clone(), name.clone()).await,
+            secret_groups: self.get_attached_secret_groups(user.clone(), name.clone()).await?
+                .into_iter()
+                .map(|group| format!("{user}.{group}"))
+                .collect(),
+            secrets: self.resolve_component_secrets(user.clone(), name.clone()).await?,
+            access: self.get_component_access(user, name).await?,
+        })
+    }
+
+    fn register_component(&self, component: Component, revision: ComponentRevision) -> Result<RegisteredComponent, ThreadSafeError> {
+        let proxy_pre = ProxyPre::new(self.linker.instantiate_pre(&component)?)?;
+        Ok(RegisteredComponent { component, proxy_pre, revision })
+    }
+
+    // Brings the registry in line with what is stored for user.name. The current revision is compiled
+    // before it replaces the registered one, so requests never wait on it and the ones already running
+    // finish on the revision they started with. Pinned revisions that were removed or replaced are dropped.
+    async fn reload_component(&self, user: String, name: String) -> Result<(), ThreadSafeError> {
+        let username_component_name = format!("{user}.{name}");
+        let revisions = self.list_component_revisions(user.clone(), name.clone()).await?;
+
+        let pinned_prefix = format!("{username_component_name}@");
+        for key in self.component_registry.keys().await {
+            let Some(pinned) = key.strip_prefix(&pinned_prefix) else { continue };
+            let Some(entry) = self.component_registry.get_entry(&key).await else { continue };
+            let registered = entry.read().await.revision.clone();
+            if pinned.parse() != Ok(registered.revision) || !revisions.contains(&registered) {
+                self.component_registry.destroy_gracefully_entry_by_key(key).await;
+            }
+        }
+
+        let current = match self.get_current_revision(user.clone(), name.clone()).await? {
+            Some(current) => revisions.into_iter().find(|revision| revision.revision == current),
+            None => None
+        };
+        let Some(current) = current else {
+            self.component_registry.destroy_gracefully_entry_by_key(username_component_name).await;
+            return Ok(())
+        };
+        if let Some(entry) = self.component_registry.get_entry(&username_component_name).await {
+            if entry.read().await.revision == current { return Ok(()) }
+        }
+        let component = self.load_blob(&current.hash).await?;
+        self.component_registry.replace_entry(username_component_name.clone(), self.register_component(component, current.clone())?).await;
+        println!("reloaded {username_component_name} revision {}", current.revision);
+        Ok(())
+    }
+
+    // Registers the current revision of every component, after moving the compiled code of components
+    // stored by earlier versions, as {user}.{name}.aot.wasm or {revision}-{sha256}.aot.wasm files, into blobs.
+    // Components compiled by another engine, after a wasmtime upgrade, are compiled again in the background.
     async fn build_registry(&self) -> Result<ComponentRegistry, ThreadSafeError> {
         let component_registry = new_empty_cache();
-    
-        let entries = self.read_dir("components").await?;
-        let mut config = Config::new();
-        config.cache_config_load_default()?;
-        config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
-        config.wasm_component_model(true);
-        config.async_support(true);
-        let engine = Engine::new(&config)?;
-    
-        for filename in entries {
-            component_registry.get_entry_by_key(filename.clone(), || {
-                unsafe { Component::deserialize_file(&engine, format!("{}/components/{}", self.fs_root, filename.clone())).unwrap() }
-            }).await;
-            println!("successfully registered {filename}");
+        let mut pending = Vec::new();
+
+        for filename in self.read_dir("components").await? {
+            // a component that can't be loaded, like one whose blob is corrupt, does not keep the others from being served
+            if let Err(e) = self.register_stored_component(&filename, &component_registry, &mut pending).await {
+                eprintln!("error registering {filename}: {e}");
+            }
         }
-    
+
+        if !pending.is_empty() {
+            println!("{} components were compiled by another engine, recompiling them in the background", pending.len());
+            let environment = self.clone();
+            let component_registry = component_registry.clone();
+            tokio::spawn(async move { environment.recompile_components(component_registry, pending).await });
+        }
+
         Ok(component_registry)
     }
-    
-}
\ No newline at end of file
+
+    // Reloads a component whenever something under its directory changes, including changes made by
+    // other processes sharing the same storage. Components are watched until the watcher is dropped.
+    fn watch_components(&self) -> Result<ComponentWatcher, ThreadSafeError> {
+        let Some(components_path) = self.storage.local_path("components") else {
+            return Ok(ComponentWatcher { _files: None, poll: Some(self.poll_components()) })
+        };
+        let components_path = std::fs::canonicalize(components_path)?;
+        let access_path = self.storage.local_path("access").and_then(|access_path| std::fs::canonicalize(access_path).ok());
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                // files being read, by the reloads themselves too, change nothing
+                if event.kind.is_access() { return }
+                for path in event.paths { _ = sender.send(path); }
+            }
+        })?;
+        watcher.watch(&components_path, RecursiveMode::Recursive)?;
+        if let Some(access_path) = &access_path {
+            watcher.watch(access_path, RecursiveMode::NonRecursive)?;
+        }
+
+        let environment = self.clone();
+        tokio::spawn(async move {
+            while let Some(path) = receiver.recv().await {
+                // access files changed by other processes are read again on the next request
+                if let Some(access_path) = &access_path {
+                    let file_name = path.strip_prefix(access_path).ok().and_then(|file_name| file_name.to_str());
+                    if let Some(username_component_name) = file_name.and_then(|file_name| file_name.strip_suffix(".yaml")) {
+                        environment.access_cache.invalidate(&username_component_name.to_string()).await;
+                        continue
+                    }
+                }
+                let Some(username_component_name) = component_dir_name(&components_path, &path) else { continue };
+                let Some((user, name)) = username_component_name.split_once('.') else { continue };
+                if let Err(e) = environment.reload_component(user.to_string(), name.to_string()).await {
+                    eprintln!("error reloading {username_component_name}: {e}");
+                }
+            }
+        });
+        Ok(ComponentWatcher { _files: Some(watcher), poll: None })
+    }
+
+}
+
+// Compiled components are ELF files, wasmtime writes the version that compiled them
+// at the start of its engine section, after a format byte and the length of the version
+fn compiled_wasmtime_version(component_bytes: &[u8]) -> Result<String, ThreadSafeError> {
+    let file = object::File::parse(component_bytes)?;
+    let section = file.section_by_name(".wasmtime.engine").ok_or("compiled component has no engine section")?;
+    let data = section.data()?;
+    let len = *data.get(1).ok_or("invalid engine section")? as usize;
+    let version = data.get(2..2 + len).ok_or("invalid engine section")?;
+    Ok(String::from_utf8(version.to_vec())?)
+}
+
+fn component_dir_name(components_path: &Path, path: &Path) -> Option<String> {
+    let dir = path.strip_prefix(components_path).ok()?.components().next()?;
+    let dir = dir.as_os_str().to_str()?;
+    match dir.ends_with(".aot.wasm") {
+        true => None,
+        false => Some(dir.to_string())
+    }
+}
+
+impl RaikiriEnvironment {
+    // set_current_revision for callers holding the lock of the component. The pointer is replaced with
+    // a rename, so invocations always read a complete revision number.
+    async fn write_current_revision(&self, user: String, name: String, revision: u32) -> Result<(), ThreadSafeError> {
+        let exists = self.list_component_revisions(user.clone(), name.clone()).await?
+            .iter()
+            .any(|found| found.revision == revision);
+        if !exists { return Err(format!("component {user}.{name} has no revision {revision}").into()) }
+        self.write_file(format!("components/{user}.{name}/current.tmp"), revision.to_string().into_bytes()).await?;
+        self.rename_file(format!("components/{user}.{name}/current.tmp"), format!("components/{user}.{name}/current")).await?;
+        self.reload_component(user, name).await
+    }
+
+    async fn find_component_revision(&self, user: &str, name: &str, revision: u32) -> Result<ComponentRevision, ThreadSafeError> {
+        Ok(self.list_component_revisions(user.to_string(), name.to_string()).await?
+            .into_iter()
+            .find(|found| found.revision == revision)
+            .ok_or(format!("component {user}.{name} has no revision {revision}"))?)
+    }
+
+    async fn component_info(&self, user: &str, name: &str, revision: ComponentRevision) -> Result<ComponentInfo, ThreadSafeError> {
+        let metadata = self.file_metadata(format!("components/{user}.{name}/{}", revision.filename())).await?;
+        let uploaded_at: chrono::DateTime<chrono::Utc> = metadata.modified.into();
+        Ok(ComponentInfo {
+            owner: user.to_string(),
+            name: name.to_string(),
+            revision: revision.revision,
+            size: self.blob_size(&revision.hash).await?,
+            hash: revision.hash,
+            uploaded_at: uploaded_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            error: None,
+        })
+    }
+
+    // Components this engine has not compiled are left to the caller in pending
+    async fn register_stored_component(&self, filename: &str, component_registry: &ComponentRegistry, pending: &mut Vec<(String, ComponentRevision)>) -> Result<(), ThreadSafeError> {
+        let username_component_name = match filename.strip_suffix(".aot.wasm") {
+            Some(username_component_name) => {
+                self.migrate_legacy_component(username_component_name).await?;
+                username_component_name.to_string()
+            }
+            None => filename.to_string()
+        };
+        self.migrate_compiled_revisions(&username_component_name).await?;
+        let Some((user, name)) = username_component_name.split_once('.') else { return Ok(()) };
+        let Some(revision) = self.get_current_revision(user.to_string(), name.to_string()).await? else { return Ok(()) };
+        let revision = self.find_component_revision(user, name, revision).await?;
+        if !self.is_compiled_blob(&revision.hash).await {
+            pending.push((username_component_name, revision));
+            return Ok(())
+        }
+        let component = self.load_blob(&revision.hash).await?;
+        println!("successfully registered {username_component_name} revision {}", revision.revision);
+        component_registry.replace_entry(username_component_name, self.register_component(component, revision)?).await;
+        Ok(())
+    }
+
+    // Components are served while this runs, the ones not compiled yet are compiled on their first request
+    async fn recompile_components(&self, component_registry: ComponentRegistry, pending: Vec<(String, ComponentRevision)>) {
+        let total = pending.len();
+        for (done, (username_component_name, revision)) in pending.into_iter().enumerate() {
+            let start = chrono::Utc::now();
+            let result = match self.load_blob(&revision.hash).await {
+                Ok(component) => self.register_component(component, revision),
+                Err(e) => Err(e)
+            };
+            let error = match result {
+                Ok(registered) => {
+                    // the component may have been updated or loaded by a request in the meantime
+                    if component_registry.get_entry(&username_component_name).await.is_none() {
+                        component_registry.replace_entry(username_component_name.clone(), registered).await;
+                    }
+                    None
+                }
+                Err(e) => Some(e.to_string())
+            };
+            _ = self.event_sender.send(ComponentEvent::Recompiled {
+                username_component_name,
+                done: done + 1,
+                total,
+                duration: chrono::Utc::now().signed_duration_since(start).num_milliseconds(),
+                error,
+            }).await;
+        }
+    }
+
+    // Reloads every stored component and every registered one, which catches components removed
+    // from the storage too. Components whose current revision did not change are left as they are.
+    // Access is read again on the next request, since other nodes may have changed it.
+    fn poll_components(&self) -> tokio::task::JoinHandle<()> {
+        let environment = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(COMPONENTS_POLL_INTERVAL).await;
+                for key in environment.access_cache.keys().await {
+                    environment.access_cache.invalidate(&key).await;
+                }
+                let mut names = environment.read_dir("components").await.unwrap_or_default();
+                for key in environment.component_registry.keys().await {
+                    if !key.contains('@') && !names.contains(&key) { names.push(key) }
+                }
+                for username_component_name in names {
+                    let Some((user, name)) = username_component_name.split_once('.') else { continue };
+                    if name.ends_with(".aot.wasm") { continue }
+                    if let Err(e) = environment.reload_component(user.to_string(), name.to_string()).await {
+                        eprintln!("error reloading {username_component_name}: {e}");
+                    }
+                }
+            }
+        })
+    }
+
+    // Run again when the rename left the legacy file behind, which must not undo a later rollback or upload
+    async fn migrate_legacy_component(&self, username_component_name: &str) -> Result<(), ThreadSafeError> {
+        let legacy_path = format!("components/{username_component_name}.aot.wasm");
+        self.create_dir(format!("components/{username_component_name}")).await?;
+        if self.file_exists(format!("components/{username_component_name}/current")).await {
+            return self.remove_file(&legacy_path).await
+        }
+        self.rename_file(&legacy_path, format!("components/{username_component_name}/1-legacy.aot.wasm")).await?;
+        self.write_file(format!("components/{username_component_name}/current"), b"1".to_vec()).await
+    }
+
+    // The uploaded wasm was not kept for these, so their blob is named after the compiled code
+    async fn migrate_compiled_revisions(&self, username_component_name: &str) -> Result<(), ThreadSafeError> {
+        for filename in self.read_dir(format!("components/{username_component_name}")).await? {
+            let Some(revision) = filename.strip_suffix(".aot.wasm").and_then(|name| name.split_once('-')) else { continue };
+            let Ok(revision) = revision.0.parse::<u32>() else { continue };
+            let path = format!("components/{username_component_name}/{filename}");
+            let blobs = blobs_lock(self).await;
+            let _blobs = blobs.read().await;
+            let hash = self.put_compiled_blob(self.read_file(&path).await?).await?;
+            let revision = ComponentRevision { revision, hash };
+            self.write_file(format!("components/{username_component_name}/{}", revision.filename()), Vec::new()).await?;
+            self.remove_file(&path).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{path::{Path, PathBuf}, sync::Arc, time::SystemTime};
 
 use async_trait::async_trait;
 
@@ -6,58 +6,92 @@ use super::raikiri_env::{RaikiriEnvironment, ThreadSafeError};
 
 #[async_trait]
 pub trait RaikiriEnvironmentFS {
-    fn get_path(&self, path: impl AsRef<Path>) -> String;
     async fn setup_fs(&self) -> Result<(), ThreadSafeError>;
     async fn read_file(&self, path: impl AsRef<Path> + Send) -> Result<Vec<u8>, ThreadSafeError>;
     async fn write_file(&self, path: impl AsRef<Path> + Send, content: Vec<u8>) -> Result<(), ThreadSafeError>;
     async fn remove_file(&self, path: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError>;
+    async fn rename_file(&self, from: impl AsRef<Path> + Send, to: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError>;
+    async fn remove_dir(&self, path: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError>;
     async fn file_exists(&self, path: impl AsRef<Path> + Send) -> bool;
+    async fn file_metadata(&self, path: impl AsRef<Path> + Send) -> Result<FileMetadata, ThreadSafeError>;
     async fn create_dir(&self, path: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError>;
     async fn read_dir(&self, path: impl AsRef<Path> + Send) -> Result<Vec<String>, ThreadSafeError>;
 }
 
+// Where components, blobs, secrets and keys are kept, see adapters::storage for the backends.
+// Paths are relative to the root of the storage, with / as separator. Backends that share their
+// files, like an object store, let several Raikiri nodes serve the same components.
 #[async_trait]
-impl RaikiriEnvironmentFS for RaikiriEnvironment {
+pub trait RaikiriStorage {
+    async fn read(&self, path: &str) -> Result<Vec<u8>, ThreadSafeError>;
+    async fn write(&self, path: &str, content: Vec<u8>) -> Result<(), ThreadSafeError>;
+    async fn remove(&self, path: &str) -> Result<(), ThreadSafeError>;
+    async fn rename(&self, from: &str, to: &str) -> Result<(), ThreadSafeError>;
+    async fn remove_dir(&self, path: &str) -> Result<(), ThreadSafeError>;
+    async fn exists(&self, path: &str) -> bool;
+    async fn metadata(&self, path: &str) -> Result<FileMetadata, ThreadSafeError>;
+    async fn create_dir(&self, path: &str) -> Result<(), ThreadSafeError>;
+    async fn read_dir(&self, path: &str) -> Result<Vec<String>, ThreadSafeError>;
+    // Where path is on local disk, for backends that keep their files there
+    fn local_path(&self, _path: &str) -> Option<PathBuf> { None }
+}
 
-    fn get_path(&self, path: impl AsRef<Path>) -> String {
-        let fs_root = &self.fs_root;
-        let mut result = String::new();
-        result.push_str(fs_root);
-        result.push('/');
-        result.push_str(path.as_ref().to_str().unwrap());
-        result
-    }
+pub type Storage = Arc<dyn RaikiriStorage + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileMetadata {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+fn storage_path(path: impl AsRef<Path>) -> String {
+    path.as_ref().to_str().unwrap().trim_end_matches('/').to_string()
+}
+
+#[async_trait]
+impl RaikiriEnvironmentFS for RaikiriEnvironment {
 
     async fn setup_fs(&self) -> Result<(), ThreadSafeError> {
 
         self.create_dir("").await?;
         self.create_dir("components").await?;
+        self.create_dir("blobs").await?;
         self.create_dir("secrets").await?;
         self.create_dir("keys").await?;
+        self.create_dir("api_gateways").await?;
+        self.create_dir("tenants").await?;
+        self.create_dir("api_keys").await?;
+        self.create_dir("access").await?;
+        self.create_dir("secret_groups").await?;
+        self.create_dir("attached_secret_groups").await?;
 
         Ok(())
     }
     async fn read_file(&self, path: impl AsRef<Path> + Send) -> Result<Vec<u8>, ThreadSafeError> {
-        Ok(tokio::fs::read(self.get_path(path)).await?)
+        self.storage.read(&storage_path(path)).await
     }
     async fn write_file(&self, path: impl AsRef<Path> + Send, content: Vec<u8>) -> Result<(), ThreadSafeError> {
-        Ok(tokio::fs::write(self.get_path(path), content).await?)
+        self.storage.write(&storage_path(path), content).await
     }
     async fn remove_file(&self, path: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError> {
-        Ok(tokio::fs::remove_file(self.get_path(path)).await?)
+        self.storage.remove(&storage_path(path)).await
+    }
+    async fn rename_file(&self, from: impl AsRef<Path> + Send, to: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError> {
+        self.storage.rename(&storage_path(from), &storage_path(to)).await
+    }
+    async fn remove_dir(&self, path: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError> {
+        self.storage.remove_dir(&storage_path(path)).await
     }
     async fn file_exists(&self, path: impl AsRef<Path> + Send) -> bool {
-        tokio::fs::metadata(self.get_path(path)).await.is_ok()
+        self.storage.exists(&storage_path(path)).await
+    }
+    async fn file_metadata(&self, path: impl AsRef<Path> + Send) -> Result<FileMetadata, ThreadSafeError> {
+        self.storage.metadata(&storage_path(path)).await
     }
     async fn create_dir(&self, path: impl AsRef<Path> + Send) -> Result<(), ThreadSafeError> {
-        Ok(tokio::fs::create_dir_all(self.get_path(path)).await?)
+        self.storage.create_dir(&storage_path(path)).await
     }
     async fn read_dir(&self, path: impl AsRef<Path> + Send) -> Result<Vec<String>, ThreadSafeError> {
-        let mut entries = tokio::fs::read_dir(self.get_path(path)).await?;
-        let mut result = Vec::new();
-        while let Some(entry) = entries.next_entry().await? {
-            result.push(entry.file_name().into_string().unwrap());
-        }
-        Ok(result)
+        self.storage.read_dir(&storage_path(path)).await
     }
 }
@@ -4,38 +4,37 @@ use async_trait::async_trait;
 use http::Request;
 use http_body_util::BodyExt;
 use hyper::body::{Body, Bytes};
-use wasmtime::{component::Linker, Store};
-use wasmtime_wasi_http::{bindings::http::types::Scheme, hyper_request_error, types::IncomingResponse, WasiHttpView};
+use wasmtime::{component::Resource, Store};
+use wasmtime_wasi::{pipe::MemoryOutputPipe, IoView};
+use wasmtime_wasi_http::{bindings::http::types::{ErrorCode, Scheme}, body::{HostIncomingBody, HyperIncomingBody}, hyper_request_error, types::{HostIncomingRequest, IncomingResponse}, WasiHttpView};
 
-use crate::{adapters::{wasi_http_view::stream_from_string, context::RaikiriContext}, Wasi};
+use crate::{adapters::{body::LimitedBody, limits::{ComponentLimiter, ResourceLimit}, wasi_http_view::stream_from_string, context::RaikiriContext}, ComponentImports, Wasi};
 
-use super::{raikiri_env::{ComponentEvent, RaikiriEnvironment}, raikiri_env_component::RaikiriComponentStorage};
+use super::{raikiri_env::{ComponentEvent, RaikiriEnvironment}, raikiri_env_component::{registry_key, RaikiriComponentStorage}};
 
 #[async_trait]
 pub trait RaikiriEnvironmentInvoke {
-    async fn invoke_component<T, B>(
+    async fn invoke_component<B>(
         &self,
         username_component_name: String,
         req: Request<B>,
-        wasi: Wasi<T>,
-    ) -> Result<IncomingResponse, wasmtime_wasi_http::bindings::http::types::ErrorCode>
+        wasi: Wasi<ComponentImports>,
+    ) -> Result<IncomingResponse, ErrorCode>
     where
-        T: Send + Clone + RaikiriContext + 'static,
-        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static;
+        B: Body<Data = Bytes, Error = ErrorCode> + Send + Sync + 'static;
 }
 
 #[async_trait]
 impl RaikiriEnvironmentInvoke for RaikiriEnvironment {
     
-    async fn invoke_component<T, B>(
+    async fn invoke_component<B>(
         &self,
         username_component_name: String,
         req: Request<B>,
-        wasi: Wasi<T>,
-    ) -> Result<IncomingResponse, wasmtime_wasi_http::bindings::http::types::ErrorCode>
+        wasi: Wasi<ComponentImports>,
+    ) -> Result<IncomingResponse, ErrorCode>
     where
-        T: Send + Clone + RaikiriContext + 'static,
-        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static,
+        B: Body<Data = Bytes, Error = ErrorCode> + Send + Sync + 'static,
     {
         let start = chrono::Utc::now();
         let data = wasi.data.clone();
@@ -59,39 +58,89 @@ impl RaikiriEnvironmentInvoke for RaikiriEnvironment {
         call_stack.push(username_component_name.clone());
 
         let call_stack_len = call_stack.len();
-        let component_registry = &wasi.data.environment().component_registry;
 
         let (user, name) = username_component_name.split_once('.').unwrap();
-        if !self.component_exists(user.to_string(), name.to_string()).await {
-            return Ok(build_response(
-                404,
-                format!("Component {username_component_name} not found").as_str(),
-            ).await)
-        }
 
-        let component_entry = component_registry
-            .get_entry_by_key_async_build(username_component_name.clone(), async move {
-                self.get_component(user.to_string(), name.to_string()).await.unwrap()
-            })
-            .await;
-        let component = component_entry.read().await;
+        // Component-Version pins a revision, otherwise the current one is invoked
+        let component_entry = match req.headers().get("Component-Version") {
+            Some(version) => {
+                let Some(revision) = version.to_str().ok().and_then(|version| version.parse::<u32>().ok()) else {
+                    return Ok(build_response(400, "Invalid Component-Version header").await)
+                };
+                let revisions = self.list_component_revisions(user.to_string(), name.to_string()).await.unwrap_or_default();
+                let Some(revision) = revisions.into_iter().find(|found| found.revision == revision) else {
+                    return Ok(build_response(
+                        404,
+                        format!("Component {username_component_name} has no revision {revision}").as_str(),
+                    ).await)
+                };
+                let key = registry_key(&username_component_name, revision.revision);
+                match self.component_registry.get_entry(&key).await {
+                    Some(entry) => entry,
+                    None => {
+                        let number = revision.revision;
+                        let registered = match self.get_component_revision(user.to_string(), name.to_string(), number).await {
+                            Ok(component) => self.register_component(component, revision),
+                            Err(e) => Err(e)
+                        };
+                        match registered {
+                            Ok(registered) => self.component_registry.replace_entry(key, registered).await,
+                            Err(e) => {
+                                eprintln!("error loading {username_component_name} revision {number}: {e}");
+                                return Ok(build_response(500, "Component revision could not be loaded").await)
+                            }
+                        }
+                    }
+                }
+            }
+            None => match self.component_registry.get_entry(&username_component_name).await {
+                Some(entry) => entry,
+                // not registered yet, when it was stored without going through this environment
+                None => {
+                    if let Err(e) = self.reload_component(user.to_string(), name.to_string()).await {
+                        eprintln!("error loading {username_component_name}: {e}");
+                    }
+                    match self.component_registry.get_entry(&username_component_name).await {
+                        Some(entry) => entry,
+                        None => return Ok(build_response(
+                            404,
+                            format!("Component {username_component_name} not found").as_str(),
+                        ).await)
+                    }
+                }
+            }
+        };
+        let registered_component = component_entry.read().await;
 
         let stdout = wasi.stdout.clone();
-        let mut store = Store::new(&component.engine(), wasi);
-        let mut linker = Linker::<Wasi<T>>::new(&component.engine());
-        linker.allow_shadowing(true);
-        wasmtime_wasi::add_to_linker_async(&mut linker).unwrap();
-        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).unwrap();
-        let proxy =
-            wasmtime_wasi_http::bindings::Proxy::instantiate_async(&mut store, &component, &linker)
-                .await
-                .unwrap();
+        let body_limits = self.body_limits;
+        if body_limits.request_too_large(req.headers()) {
+            return Ok(build_response(413, "REQUEST BODY TOO LARGE").await)
+        }
+        let environment = data.environment().clone();
+        let mut store = Store::new(registered_component.component.engine(), wasi);
+        store.data_mut().limiter = ComponentLimiter::new(self.limits.for_component(&username_component_name));
+        store.limiter(|wasi| &mut wasi.limiter);
+        // every tick spent running wasm reaches the deadline and is counted against the CPU budget
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(|mut context| context.data_mut().limiter.epoch_tick());
+        let proxy = match registered_component.proxy_pre.instantiate_async(&mut store).await {
+            Ok(proxy) => proxy,
+            // initial memories and tables already count against the limits
+            Err(e) => {
+                let (status, message, limit) = error_response(&e);
+                eprintln!("{message}");
+                send_execution_events(environment, username_component_name, start, Some(stdout), status, limit).await;
+                return Ok(build_response(status, &message).await)
+            }
+        };
+        drop(registered_component);
         let (sender, receiver) = tokio::sync::oneshot::channel();
         let out = store.data_mut().new_response_outparam(sender).unwrap();
-        let req = store
-            .data_mut()
-            .new_incoming_request(Scheme::Http, req)
-            .unwrap();
+        let req = req.map(|body| {
+            LimitedBody::new(body.boxed(), body_limits.max_request_body_size, ErrorCode::HttpRequestBodySize).boxed()
+        });
+        let req = new_incoming_request(store.data_mut(), req).unwrap();
 
         let task = wasmtime_wasi::runtime::spawn(async move {
             proxy
@@ -99,7 +148,9 @@ impl RaikiriEnvironmentInvoke for RaikiriEnvironment {
                 .call_handle(&mut store, req, out)
                 .await
         });
-        if call_stack_len == 1 {
+        // The timeout covers the time until the component sets its response, the body is then
+        // streamed for as long as the component keeps writing it
+        let response = if call_stack_len == 1 {
             let timeout = std::env::var("RAIKIRI_TIMEOUT")
                 .unwrap_or_else(|_| "300".to_string())
                 .parse::<u64>()
@@ -107,58 +158,108 @@ impl RaikiriEnvironmentInvoke for RaikiriEnvironment {
             let timer = tokio::time::timeout(
                 // TODO: make timeout configurable
                 tokio::time::Duration::from_millis(timeout),
-                task,
+                receiver,
             )
             .await;
             match timer {
                 Err(_) => {
                     return Ok(build_response(500, "EXECUTION TIMEOUT").await);
                 }
-                Ok(_) => (),
-            };
+                Ok(response) => response,
+            }
         } else {
-            task.await.unwrap();
-        }
+            receiver.await
+        };
 
-        let resp = match receiver.await {
+        let (status, result, task) = match response {
             Ok(Ok(resp)) => {
+                let status = resp.status().as_u16();
                 let (parts, body) = resp.into_parts();
-                let collected = BodyExt::collect(body).await?;
-                Some(Ok(hyper::Response::from_parts(parts, collected)))
+                let body = LimitedBody::new(body, body_limits.max_response_body_size, ErrorCode::HttpResponseBodySize);
+                (status, build_streaming_response(parts, body.boxed()), Some(task))
             }
-            Ok(Err(e)) => Some(Err(e)),
-            Err(_) => None,
-        }
-        .expect("wasm never called set-response-outparam");
-        let status;
-        let result = match resp {
-            Err(e) => {
+            Ok(Err(e)) => {
                 eprintln!("{e}");
-                status = 500;
-                Ok(build_response(500, &format!("RUNTIME ERROR: {}", e)).await)
+                (500, build_response(500, &format!("RUNTIME ERROR: {}", e)).await, Some(task))
             }
-            Ok(v) => {
-                status = v.status().as_u16();
-                Ok(build_response(
-                    v.status().as_u16(),
-                    std::str::from_utf8(&v.into_body().to_bytes().to_vec()).unwrap(),
-                )
-                .await)
+            // the sender is dropped with the store, so the component has already returned
+            Err(_) => {
+                let (status, message, limit) = match task.await {
+                    Err(e) => error_response(&e),
+                    Ok(_) => (500, "RUNTIME ERROR: wasm never called set-response-outparam".to_string(), None),
+                };
+                eprintln!("{message}");
+                send_execution_events(environment, username_component_name, start, Some(stdout), status, limit).await;
+                return Ok(build_response(status, &message).await)
             }
         };
-        data.environment().event_sender.send(ComponentEvent::Execution {
-            stdout: Some(stdout),
-            username_component_name,
+
+        // the execution only ends when the component returns, which can be after the
+        // response was handed back while its body is still being written
+        tokio::spawn(async move {
+            let mut limit = None;
+            if let Some(task) = task {
+                if let Err(e) = task.await {
+                    eprintln!("{e:?}");
+                    limit = ResourceLimit::from_error(&e);
+                }
+            }
+            send_execution_events(environment, username_component_name, start, Some(stdout), status, limit).await;
+        });
+        Ok(result)
+    }
+}
+
+// Components stopped by one of their limits get that limit's status instead of a plain 500
+fn error_response(error: &wasmtime::Error) -> (u16, String, Option<ResourceLimit>) {
+    match ResourceLimit::from_error(error) {
+        Some(limit) => (limit.status(), format!("RESOURCE LIMIT EXCEEDED: {limit}"), Some(limit)),
+        None => (500, format!("RUNTIME ERROR: {error:?}"), None),
+    }
+}
+
+async fn send_execution_events(
+    environment: RaikiriEnvironment,
+    username_component_name: String,
+    start: chrono::DateTime<chrono::Utc>,
+    stdout: Option<MemoryOutputPipe>,
+    status: u16,
+    limit: Option<ResourceLimit>,
+) {
+    let duration = chrono::Utc::now()
+        .signed_duration_since(start)
+        .num_milliseconds();
+    if let Some(limit) = limit {
+        environment.event_sender.send(ComponentEvent::LimitExceeded {
+            username_component_name: username_component_name.clone(),
+            limit,
             start,
-            duration: chrono::Utc::now()
-                .signed_duration_since(start)
-                .num_milliseconds(),
-            status,
+            duration,
         })
         .await
         .unwrap();
-        result
     }
+    environment.event_sender.send(ComponentEvent::Execution {
+        stdout,
+        username_component_name,
+        start,
+        duration,
+        status,
+    })
+    .await
+    .unwrap();
+}
+
+// Same as WasiHttpView::new_incoming_request, but takes a body that already reports
+// wasi-http error codes, like the outgoing body of a component calling another one
+fn new_incoming_request(
+    view: &mut Wasi<ComponentImports>,
+    req: Request<HyperIncomingBody>,
+) -> wasmtime::Result<Resource<HostIncomingRequest>> {
+    let (parts, body) = req.into_parts();
+    let body = HostIncomingBody::new(body, Duration::from_millis(600 * 1000));
+    let incoming_req = HostIncomingRequest::new(view, parts, Scheme::Http, Some(body))?;
+    Ok(view.table().push(incoming_req)?)
 }
 
 pub async fn build_response(status: u16, body: &str) -> IncomingResponse {
@@ -173,4 +274,14 @@ pub async fn build_response(status: u16, body: &str) -> IncomingResponse {
         worker: None,
         between_bytes_timeout: Duration::new(0, 0),
     }
-}
\ No newline at end of file
+}
+
+// Keeps the status and headers set by the guest and hands its body over as it is written
+pub fn build_streaming_response(parts: http::response::Parts, body: HyperIncomingBody) -> IncomingResponse {
+    let resp = http::Response::from_parts(parts, body);
+    wasmtime_wasi_http::types::IncomingResponse {
+        resp,
+        worker: None,
+        between_bytes_timeout: Duration::new(0, 0),
+    }
+}
@@ -0,0 +1,241 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use yaml_rust2::Yaml;
+
+use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_secrets::{lock_secrets_for_change, parse_secrets, read_secrets, secret_group_file, secrets_from_yaml, write_secrets, RaikiriEnvironmentSecrets}};
+
+// Secret groups hold secrets shared by the components of a tenant, named like components, such as
+// team.db-prod. Groups are listed in secret_groups/{user}.{group}, and the groups a component is
+// attached to are kept in the order they were attached in attached_secret_groups/{user}.{name}.json.
+//
+// A component gets the secrets of its groups in that order, each replacing those of the groups
+// before it, and then its own, which replace those of every group.
+#[async_trait]
+pub trait RaikiriEnvironmentSecretGroups {
+    async fn update_secret_group(&self, user: String, group: String, secrets_content: Vec<u8>) -> Result<(), ThreadSafeError>;
+    async fn get_secret_group(&self, user: String, group: String) -> Result<Vec<(String, String)>, ThreadSafeError>;
+    async fn secret_group_exists(&self, user: &str, group: &str) -> bool;
+    async fn list_secret_groups(&self, user: String) -> Result<Vec<String>, ThreadSafeError>;
+    async fn remove_secret_group(&self, user: String, group: String) -> Result<(), ThreadSafeError>;
+    async fn get_attached_secret_groups(&self, user: String, name: String) -> Result<Vec<String>, ThreadSafeError>;
+    async fn attach_secret_group(&self, user: String, name: String, group: String) -> Result<(), ThreadSafeError>;
+    async fn detach_secret_group(&self, user: String, name: String, group: String) -> Result<(), ThreadSafeError>;
+    async fn remove_attached_secret_groups(&self, user: String, name: String) -> Result<(), ThreadSafeError>;
+    async fn resolve_component_secrets(&self, user: String, name: String) -> Result<Vec<ResolvedSecret>, ThreadSafeError>;
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    Component,
+    Group(String),
+}
+
+// A secret of a component and where its value comes from, as shown by component inspect
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ResolvedSecret {
+    pub key: String,
+    #[serde(skip)]
+    pub value: String,
+    pub source: SecretSource,
+    // the other sources with the same key, their values are replaced by this one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<SecretSource>,
+}
+
+// Groups are named db-prod or user.db-prod, and only the components of user can use them
+fn group_name(user: &str, group: &str) -> Result<String, ThreadSafeError> {
+    let group = match group.split_once('.') {
+        Some((owner, group)) if owner == user => group,
+        Some((owner, _)) => return Err(format!("secret group {owner}.{group} belongs to another tenant").into()),
+        None => group,
+    };
+    let valid = !group.is_empty() && group.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(group.to_string()),
+        false => Err(format!("invalid secret group name {group}, use letters, digits, - and _").into()),
+    }
+}
+
+async fn write_attached_secret_groups(environment: &RaikiriEnvironment, user: &str, name: &str, groups: Vec<String>) -> Result<(), ThreadSafeError> {
+    let attached_path = format!("attached_secret_groups/{user}.{name}.json");
+    match groups.is_empty() {
+        true => if environment.file_exists(&attached_path).await { environment.remove_file(attached_path).await? },
+        false => environment.write_file(attached_path, serde_json::to_vec(&groups)?).await?,
+    }
+    environment.secrets_cache.invalidate(&format!("{user}.{name}")).await;
+    Ok(())
+}
+
+#[async_trait]
+impl RaikiriEnvironmentSecretGroups for RaikiriEnvironment {
+
+    async fn update_secret_group(&self, user: String, group: String, secrets_content: Vec<u8>) -> Result<(), ThreadSafeError> {
+        let group = group_name(&user, &group)?;
+        let secrets = parse_secrets(&secrets_content)?;
+        let secrets = secrets.into_iter().map(|(key, value)| (Yaml::String(key), Yaml::String(value))).collect();
+        write_secrets(self, &user, &secret_group_file(&user, &group), Yaml::Hash(secrets)).await?;
+        self.write_file(format!("secret_groups/{user}.{group}"), chrono::Utc::now().to_rfc3339().into_bytes()).await?;
+        // any component of user may be attached to the group
+        for username_component_name in self.secrets_cache.keys().await {
+            if username_component_name.split_once('.').is_some_and(|(owner, _)| owner == user) {
+                self.secrets_cache.invalidate(&username_component_name).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_secret_group(&self, user: String, group: String) -> Result<Vec<(String, String)>, ThreadSafeError> {
+        let group = group_name(&user, &group)?;
+        secrets_from_yaml(&read_secrets(self, &user, &secret_group_file(&user, &group)).await?)
+    }
+
+    async fn secret_group_exists(&self, user: &str, group: &str) -> bool {
+        match group_name(user, group) {
+            Ok(group) => self.file_exists(format!("secret_groups/{user}.{group}")).await,
+            Err(_) => false,
+        }
+    }
+
+    async fn list_secret_groups(&self, user: String) -> Result<Vec<String>, ThreadSafeError> {
+        let mut groups = self.read_dir("secret_groups").await?
+            .into_iter()
+            .filter_map(|file_name| file_name.strip_prefix(&format!("{user}.")).map(str::to_string))
+            .collect::<Vec<_>>();
+        groups.sort();
+        Ok(groups)
+    }
+
+    // Groups still attached to components are kept
+    async fn remove_secret_group(&self, user: String, group: String) -> Result<(), ThreadSafeError> {
+        let group = group_name(&user, &group)?;
+        if !self.secret_group_exists(&user, &group).await { return Err(format!("secret group {user}.{group} not found").into()) }
+        let mut attached_to = Vec::new();
+        for file_name in self.read_dir("attached_secret_groups").await? {
+            let Some(name) = file_name.strip_prefix(&format!("{user}.")).and_then(|name| name.strip_suffix(".json")) else { continue };
+            if self.get_attached_secret_groups(user.clone(), name.to_string()).await?.contains(&group) {
+                attached_to.push(format!("{user}.{name}"));
+            }
+        }
+        if !attached_to.is_empty() {
+            attached_to.sort();
+            return Err(format!("secret group {user}.{group} is attached to {}", attached_to.join(", ")).into())
+        }
+        let group_file = secret_group_file(&user, &group);
+        if self.file_exists(&group_file).await {
+            let _lock = lock_secrets_for_change(self, &user).await?;
+            self.remove_file(group_file).await?;
+        }
+        self.remove_file(format!("secret_groups/{user}.{group}")).await
+    }
+
+    async fn get_attached_secret_groups(&self, user: String, name: String) -> Result<Vec<String>, ThreadSafeError> {
+        let attached_path = format!("attached_secret_groups/{user}.{name}.json");
+        if !self.file_exists(&attached_path).await { return Ok(Vec::new()) }
+        Ok(serde_json::from_slice(&self.read_file(attached_path).await?)?)
+    }
+
+    // The group is attached last, so its secrets replace those of the groups attached before it
+    async fn attach_secret_group(&self, user: String, name: String, group: String) -> Result<(), ThreadSafeError> {
+        let group = group_name(&user, &group)?;
+        if !self.secret_group_exists(&user, &group).await { return Err(format!("secret group {user}.{group} not found").into()) }
+        let mut groups = self.get_attached_secret_groups(user.clone(), name.clone()).await?;
+        if groups.contains(&group) { return Ok(()) }
+        groups.push(group);
+        write_attached_secret_groups(self, &user, &name, groups).await
+    }
+
+    async fn detach_secret_group(&self, user: String, name: String, group: String) -> Result<(), ThreadSafeError> {
+        let group = group_name(&user, &group)?;
+        let mut groups = self.get_attached_secret_groups(user.clone(), name.clone()).await?;
+        if !groups.contains(&group) { return Err(format!("component {user}.{name} is not attached to secret group {user}.{group}").into()) }
+        groups.retain(|attached| attached != &group);
+        write_attached_secret_groups(self, &user, &name, groups).await
+    }
+
+    async fn remove_attached_secret_groups(&self, user: String, name: String) -> Result<(), ThreadSafeError> {
+        write_attached_secret_groups(self, &user, &name, Vec::new()).await
+    }
+
+    async fn resolve_component_secrets(&self, user: String, name: String) -> Result<Vec<ResolvedSecret>, ThreadSafeError> {
+        let mut sources = Vec::new();
+        for group in self.get_attached_secret_groups(user.clone(), name.clone()).await? {
+            let secrets = self.get_secret_group(user.clone(), group.clone()).await?;
+            sources.push((SecretSource::Group(format!("{user}.{group}")), secrets));
+        }
+        sources.push((SecretSource::Component, secrets_from_yaml(&self.get_component_secrets_yaml(user, name).await?)?));
+
+        let mut resolved: Vec<ResolvedSecret> = Vec::new();
+        for (source, secrets) in sources {
+            for (key, value) in secrets {
+                match resolved.iter_mut().find(|secret| secret.key == key) {
+                    Some(secret) => {
+                        let replaced = std::mem::replace(&mut secret.source, source.clone());
+                        secret.overrides.push(replaced);
+                        secret.value = value;
+                    }
+                    None => resolved.push(ResolvedSecret { key, value, source: source.clone(), overrides: Vec::new() }),
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_secrets::RaikiriEnvironmentSecrets, tests::create_test_env};
+
+    use super::{RaikiriEnvironmentSecretGroups, SecretSource};
+
+    #[tokio::test]
+    async fn test_secret_groups() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<_>>();
+
+        environment.update_secret_group("test".to_string(), "db-prod".to_string(), b"DB_URL: postgres://prod\nDB_USER: app".to_vec()).await.unwrap();
+        environment.update_secret_group("test".to_string(), "test.observability".to_string(), b"DB_USER: metrics\nOTEL_URL: http://otel".to_vec()).await.unwrap();
+        assert_eq!(environment.list_secret_groups("test".to_string()).await.unwrap(), vec!["db-prod", "observability"]);
+        assert!(environment.update_secret_group("test".to_string(), "acme.db-prod".to_string(), b"A: b".to_vec()).await.is_err());
+        assert!(environment.update_secret_group("test".to_string(), "db prod".to_string(), b"A: b".to_vec()).await.is_err());
+        assert!(environment.attach_secret_group("test".to_string(), "hello".to_string(), "missing".to_string()).await.is_err());
+
+        // groups attached later replace the earlier ones, the component replaces them all
+        environment.update_component_secrets("test".to_string(), "hello".to_string(), b"DB_URL: postgres://local".to_vec()).await.unwrap();
+        environment.attach_secret_group("test".to_string(), "hello".to_string(), "db-prod".to_string()).await.unwrap();
+        environment.attach_secret_group("test".to_string(), "hello".to_string(), "test.observability".to_string()).await.unwrap();
+        environment.attach_secret_group("test".to_string(), "hello".to_string(), "db-prod".to_string()).await.unwrap();
+        assert_eq!(environment.get_attached_secret_groups("test".to_string(), "hello".to_string()).await.unwrap(), vec!["db-prod", "observability"]);
+        assert_eq!(environment.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(),
+            pairs(&[("DB_URL", "postgres://local"), ("DB_USER", "metrics"), ("OTEL_URL", "http://otel")]));
+
+        let resolved = environment.resolve_component_secrets("test".to_string(), "hello".to_string()).await.unwrap();
+        assert_eq!(resolved[0].source, SecretSource::Component);
+        assert_eq!(resolved[0].overrides, vec![SecretSource::Group("test.db-prod".to_string())]);
+        assert_eq!(resolved[1].source, SecretSource::Group("test.observability".to_string()));
+        assert!(resolved[2].overrides.is_empty());
+        let inspected = serde_json::to_value(&resolved).unwrap();
+        assert_eq!(inspected[0], serde_json::json!({ "key": "DB_URL", "source": "component", "overrides": [{ "group": "test.db-prod" }] }));
+        assert!(!inspected.to_string().contains("postgres://"));
+
+        // group changes reach the components attached to it
+        environment.secrets_cache.get_entry_by_key_async_build("test.hello".to_string(), async { Vec::new() }).await;
+        environment.update_secret_group("test".to_string(), "observability".to_string(), b"OTEL_URL: http://otel-2".to_vec()).await.unwrap();
+        assert!(environment.secrets_cache.get_entry(&"test.hello".to_string()).await.is_none());
+        assert_eq!(environment.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(),
+            pairs(&[("DB_URL", "postgres://local"), ("DB_USER", "app"), ("OTEL_URL", "http://otel-2")]));
+
+        // groups in use are kept
+        assert!(environment.remove_secret_group("test".to_string(), "db-prod".to_string()).await.is_err());
+        environment.detach_secret_group("test".to_string(), "hello".to_string(), "db-prod".to_string()).await.unwrap();
+        environment.remove_secret_group("test".to_string(), "db-prod".to_string()).await.unwrap();
+        assert_eq!(environment.list_secret_groups("test".to_string()).await.unwrap(), vec!["observability"]);
+        assert!(environment.detach_secret_group("test".to_string(), "hello".to_string(), "db-prod".to_string()).await.is_err());
+
+        // group secrets are encrypted again along with the component's when the key changes
+        environment.update_crypto_key("test".to_string(), vec![9; 32], false).await.unwrap();
+        assert_eq!(environment.get_secret_group("test".to_string(), "observability".to_string()).await.unwrap(), pairs(&[("OTEL_URL", "http://otel-2")]));
+    }
+}
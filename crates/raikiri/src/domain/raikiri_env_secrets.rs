@@ -1,19 +1,179 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedRwLockWriteGuard;
 use yaml_rust2::{Yaml, YamlEmitter, YamlLoader};
 
-use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_fs::RaikiriEnvironmentFS};
+use crate::adapters::{crypto, keys::is_wrapped_key};
+
+use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_secret_groups::RaikiriEnvironmentSecretGroups};
 
 #[async_trait]
 pub trait RaikiriEnvironmentSecrets {
     async fn get_component_secrets_yaml(&self, user: String, name: String) -> Result<Yaml, ThreadSafeError>;
     async fn get_component_secrets(&self, user: String, name: String) -> Result<Vec<(String, String)>, ThreadSafeError>;
+    async fn component_has_secrets(&self, user: String, name: String) -> bool;
     async fn serialize_yaml(yaml: Yaml) -> Result<String, tokio::task::JoinError>;
     async fn get_crypto_key(&self, user: String) -> Result<Vec<u8>, ThreadSafeError>;
     fn gen_new_crypto_key() -> Result<Vec<u8>, ThreadSafeError>;
-    async fn update_crypto_key(&self, username: String, new_key: Vec<u8>) -> Result<(), ThreadSafeError>;
-    async fn update_encrypted_secret(&self, entry: String, username_hash: &String, current_key: &Vec<u8>, new_key: &Vec<u8>) -> Result<(), ThreadSafeError>;
-    async fn remove_all_new_encrypted(&self, username_hash: &String) -> Result<(), ThreadSafeError>;
+    async fn update_crypto_key(&self, username: String, new_key: Vec<u8>, dry_run: bool) -> Result<usize, ThreadSafeError>;
+    async fn update_encrypted_secret(&self, file_name: &str, username_hash: &str, current_key: &[u8], new_key: &[u8]) -> Result<(), ThreadSafeError>;
+    async fn remove_all_new_encrypted(&self, username_hash: &str) -> Result<(), ThreadSafeError>;
+    async fn recover_crypto_key_rotation(&self, username_hash: &str) -> Result<(), ThreadSafeError>;
+    async fn recover_crypto_key_rotations(&self) -> Result<(), ThreadSafeError>;
     async fn update_component_secrets(&self, user: String, name: String, secrets_content: Vec<u8>) -> Result<(), ThreadSafeError>;
+    async fn list_component_secrets(&self, user: String, name: String, show_values: bool) -> Result<Vec<SecretInfo>, ThreadSafeError>;
+    async fn set_component_secrets(&self, user: String, name: String, secrets: Vec<(String, String)>) -> Result<(), ThreadSafeError>;
+    async fn unset_component_secrets(&self, user: String, name: String, keys: Vec<String>) -> Result<Vec<String>, ThreadSafeError>;
+    async fn remove_component_secrets(&self, user: String, name: String) -> Result<(), ThreadSafeError>;
+}
+
+// A secret as listed, its value is only there when asked for
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SecretInfo {
+    pub key: String,
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+// Wraps the data keys secrets are encrypted with, see adapters::keys for the providers.
+// What wrap_key returns is what is stored in keys/{hash}.
+#[async_trait]
+pub trait RaikiriKeyProvider {
+    async fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>, ThreadSafeError>;
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, ThreadSafeError>;
+}
+
+pub type KeyProvider = Arc<dyn RaikiriKeyProvider + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RotationPhase {
+    Prepare,
+    Commit,
+}
+
+// The secrets files being encrypted again, as they were when the rotation started
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RotationJournal {
+    phase: RotationPhase,
+    files: Vec<String>,
+}
+
+// Written to a temporary file first, so the journal is never found half written
+async fn write_journal(environment: &RaikiriEnvironment, username_hash: &str, journal: &RotationJournal) -> Result<(), ThreadSafeError> {
+    let journal_path = format!("keys/{username_hash}.rotation");
+    environment.write_file(format!("{journal_path}.tmp"), serde_json::to_vec(journal)?).await?;
+    environment.rename_file(format!("{journal_path}.tmp"), journal_path).await
+}
+
+// Held while this process changes the secrets or the key of a user
+async fn lock_secrets(environment: &RaikiriEnvironment, username_hash: &str) -> OwnedRwLockWriteGuard<()> {
+    environment.storage_locks.get_entry_by_key(format!("keys/{username_hash}"), || ()).await.write_owned().await
+}
+
+// Secrets written with the key being rotated would be left unreadable, or replaced by what the
+// rotation wrote, so writers wait for a rotation of this process and refuse to run while the
+// journal of one is pending, like one run by another process sharing the storage.
+pub async fn lock_secrets_for_change(environment: &RaikiriEnvironment, user: &str) -> Result<OwnedRwLockWriteGuard<()>, ThreadSafeError> {
+    let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(user.as_bytes())));
+    let lock = lock_secrets(environment, &username_hash).await;
+    if environment.file_exists(format!("keys/{username_hash}.rotation")).await {
+        return Err(format!("the key of {user} is being rotated, secrets can be changed once the rotation is finished").into())
+    }
+    Ok(lock)
+}
+
+// Returns the secrets of file_name and them encrypted with new_key
+async fn reencrypt_secret(environment: &RaikiriEnvironment, username_hash: &str, file_name: &str, current_key: &[u8], new_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ThreadSafeError> {
+    let secrets_path = format!("secrets/{username_hash}/{file_name}");
+    let (decrypted, _) = crypto::decrypt(current_key, &secrets_path, &environment.read_file(&secrets_path).await?)
+        .map_err(|e| format!("{secrets_path} does not decrypt with the current key: {e}"))?;
+    let encrypted_new = crypto::encrypt(environment.cipher_suite, new_key, &secrets_path, &decrypted)?;
+    let (reencrypted, _) = crypto::decrypt(new_key, &secrets_path, &encrypted_new)?;
+    if reencrypted != decrypted { return Err(format!("{secrets_path} does not decrypt with the new key").into()) }
+    Ok((decrypted, encrypted_new))
+}
+
+pub fn secrets_file(user: &str, name: &str) -> String {
+    let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(user.as_bytes())));
+    let username_component_name_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(format!("{user}.{name}").as_bytes())));
+    format!("secrets/{username_hash}/{username_component_name_hash}")
+}
+
+// Groups are kept with the secrets of their tenant, so they are encrypted with its key and rotated
+// along with them. They are hashed as user/group, which no component name hashes to.
+pub fn secret_group_file(user: &str, group: &str) -> String {
+    let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(user.as_bytes())));
+    let group_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(format!("{user}/{group}").as_bytes())));
+    format!("secrets/{username_hash}/{group_hash}")
+}
+
+// Secrets are a YAML mapping of names to string values
+pub fn parse_secrets(secrets_content: &[u8]) -> Result<Vec<(String, String)>, ThreadSafeError> {
+    let secrets = YamlLoader::load_from_str(std::str::from_utf8(secrets_content)?)?;
+    secrets_from_yaml(secrets.first().unwrap_or(&Yaml::Null))
+}
+
+pub fn secrets_from_yaml(secrets: &Yaml) -> Result<Vec<(String, String)>, ThreadSafeError> {
+    if secrets.is_null() { return Ok(Vec::new()) }
+    let mut result_secrets = Vec::new();
+    for (key, value) in secrets.as_hash().ok_or("secrets must be a mapping of names to values")?.iter() {
+        let key = key.as_str().ok_or("secret names must be strings")?;
+        let value = value.as_str().ok_or(format!("secret {key} must be a string"))?;
+        result_secrets.push((key.to_string(), value.to_string()));
+    }
+    Ok(result_secrets)
+}
+
+// The secrets in secrets_path, an empty mapping when there are none
+pub async fn read_secrets(environment: &RaikiriEnvironment, user: &str, secrets_path: &str) -> Result<Yaml, ThreadSafeError> {
+    let (secrets, migrated) = decrypt_secrets(environment, user, secrets_path).await?;
+    // files written with CBC or another suite are encrypted again with the configured one, unless the key is being rotated
+    if let Some(migrated) = migrated {
+        if let Ok(_lock) = lock_secrets_for_change(environment, user).await {
+            if let Err(e) = environment.write_file(secrets_path, migrated).await {
+                eprintln!("error migrating {secrets_path} to {}: {e}", environment.cipher_suite);
+            }
+        }
+    }
+    Ok(secrets)
+}
+
+// Reads secrets_path without taking the lock of user, along with the file encrypted again with the
+// configured suite when it was written with another one
+async fn decrypt_secrets(environment: &RaikiriEnvironment, user: &str, secrets_path: &str) -> Result<(Yaml, Option<Vec<u8>>), ThreadSafeError> {
+
+    if !environment.file_exists(secrets_path).await { return Ok((Yaml::Hash(Default::default()), None)) }
+
+    let encrypted = environment.read_file(secrets_path).await?;
+    let key = &environment.get_crypto_key(user.to_string()).await?;
+
+    let (decrypted, suite) = crypto::decrypt(key, secrets_path, &encrypted)?;
+    let migrated = match suite != Some(environment.cipher_suite) {
+        true => Some(crypto::encrypt(environment.cipher_suite, key, secrets_path, &decrypted)?),
+        false => None,
+    };
+    let decrypted = String::from_utf8(decrypted)?;
+
+    Ok((YamlLoader::load_from_str(&decrypted)?.into_iter().next().unwrap_or(Yaml::Null), migrated))
+}
+
+// Replaces the secrets in secrets_path, encrypted with the key of user
+pub async fn write_secrets(environment: &RaikiriEnvironment, user: &str, secrets_path: &str, secrets: Yaml) -> Result<(), ThreadSafeError> {
+    let lock = lock_secrets_for_change(environment, user).await?;
+    write_locked_secrets(environment, user, secrets_path, secrets, &lock).await
+}
+
+// Like write_secrets, for callers already holding the lock of user from lock_secrets_for_change
+async fn write_locked_secrets(environment: &RaikiriEnvironment, user: &str, secrets_path: &str, secrets: Yaml, _lock: &OwnedRwLockWriteGuard<()>) -> Result<(), ThreadSafeError> {
+    let secret = RaikiriEnvironment::serialize_yaml(secrets).await?;
+    environment.create_dir(secrets_path.rsplit_once('/').unwrap().0).await?;
+    let crypto_key = environment.get_crypto_key(user.to_string()).await?;
+    let encrypted = crypto::encrypt(environment.cipher_suite, &crypto_key, secrets_path, secret.as_bytes())?;
+    environment.write_file(secrets_path, encrypted).await
 }
 
 struct ByteBuf<'a>(&'a [u8]);
@@ -30,34 +190,22 @@ impl<'a> std::fmt::LowerHex for ByteBuf<'a> {
 impl RaikiriEnvironmentSecrets for RaikiriEnvironment {
 
     async fn get_component_secrets_yaml(&self, user: String, name: String) -> Result<Yaml, ThreadSafeError> {
+        read_secrets(self, &user, &secrets_file(&user, &name)).await
+    }
 
-        let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(&user.as_bytes())));
-        let username_component_name_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(&format!("{user}.{name}").as_bytes())));
-        let secrets_path = format!("secrets/{username_hash}/{username_component_name_hash}");
-
-        if !self.file_exists(secrets_path.clone()).await { return Ok(Yaml::from_str("")) }
-
-        let encrypted = self.read_file(secrets_path.clone()).await?;
-        let key = &self.get_crypto_key(user).await?;
-
-        let decrypted = openssl::symm::decrypt(openssl::symm::Cipher::aes_256_cbc(), &key, None, &encrypted)?;
-        let decrypted = String::from_utf8(decrypted)?;
-
-        Ok(YamlLoader::load_from_str(&decrypted)?[0].clone())
+    async fn component_has_secrets(&self, user: String, name: String) -> bool {
 
+        let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(user.as_bytes())));
+        let username_component_name_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(format!("{user}.{name}").as_bytes())));
+        self.file_exists(format!("secrets/{username_hash}/{username_component_name_hash}")).await
     }
 
     async fn get_component_secrets(&self, user: String, name: String) -> Result<Vec<(String, String)>, ThreadSafeError> {
 
-        let secrets = self.get_component_secrets_yaml(user, name).await?;
-        let mut result_secrets = Vec::new();
-        for (key, value) in secrets.as_hash().ok_or("error getting secrets")?.iter() {
-            result_secrets.push((
-                key.as_str().ok_or("error getting key")?.to_string(),
-                value.as_str().ok_or("error getting value")?.to_string()
-            ));
-        }
-        Ok(result_secrets)
+        Ok(self.resolve_component_secrets(user, name).await?
+            .into_iter()
+            .map(|secret| (secret.key, secret.value))
+            .collect())
     }
 
 
@@ -73,11 +221,18 @@ impl RaikiriEnvironmentSecrets for RaikiriEnvironment {
         let hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(&user.as_bytes())));
         let key_path = format!("keys/{hash}");
         if self.file_exists(key_path.clone()).await {
-            self.read_file(key_path).await
+            let stored = self.read_file(&key_path).await?;
+            if is_wrapped_key(&stored) { return self.key_provider.unwrap_key(&stored).await }
+            // keys stored before a key provider was configured are wrapped the first time they are used
+            let wrapped = self.key_provider.wrap_key(&stored).await?;
+            if wrapped != stored {
+                self.write_file(key_path, wrapped).await?;
+            }
+            Ok(stored)
         }
         else {
             let key = Self::gen_new_crypto_key()?;
-            self.write_file(key_path, key.clone()).await?;
+            self.write_file(key_path, self.key_provider.wrap_key(&key).await?).await?;
             Ok(key)
         }
     }
@@ -91,49 +246,71 @@ impl RaikiriEnvironmentSecrets for RaikiriEnvironment {
         }).await
     }
 
-    async fn update_crypto_key(&self, username: String, new_key: Vec<u8>) -> Result<(), ThreadSafeError> {
+    // Rotates the key of username in two phases, recorded in the journal keys/{hash}.rotation:
+    // prepare writes the new key to keys/{hash}.new and every secret encrypted with it next to the
+    // current one as {file}.new, checking each decrypts. Nothing is read from those files until the
+    // journal is marked committed, so a rotation interrupted before that is rolled back and one
+    // interrupted after it is finished, see recover_crypto_key_rotation.
+    async fn update_crypto_key(&self, username: String, new_key: Vec<u8>, dry_run: bool) -> Result<usize, ThreadSafeError> {
 
-        let username_hash: String = format!("{:x}", ByteBuf(&openssl::sha::sha256(&username.as_bytes())));
-        let secrets_path = &format!("secrets/{username_hash}");
-        self.create_dir(secrets_path).await.expect("error creating secrets directory");
-        let entries = self.read_dir(secrets_path).await.expect("error reading secrets directory");
-        let current_key = self.read_file(secrets_path).await.expect("error reading current key");
-    
-        for entry in entries {
-            if self.update_encrypted_secret(entry, &username_hash, &current_key, &new_key).await.is_err() {
-                self.remove_all_new_encrypted(&username_hash).await?;
-                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "error updating encrypted secrets")));
+        if new_key.len() != 32 { return Err(format!("crypto key must have 32 bytes, got {}", new_key.len()).into()) }
+        let username_hash: String = format!("{:x}", ByteBuf(&openssl::sha::sha256(username.as_bytes())));
+        let _lock = lock_secrets(self, &username_hash).await;
+
+        // a dry run changes nothing, an interrupted rotation is only reported
+        if dry_run && self.file_exists(format!("keys/{username_hash}.rotation")).await {
+            return Err(format!("a rotation of the key of {username} was interrupted, it is recovered by the next rotation or when Raikiri starts").into())
+        }
+        self.recover_crypto_key_rotation(&username_hash).await?;
+
+        let secrets_path = format!("secrets/{username_hash}");
+        if !dry_run { self.create_dir(&secrets_path).await? }
+        let mut files = match self.file_exists(&secrets_path).await {
+            true => self.read_dir(&secrets_path).await?,
+            false => Vec::new()
+        };
+        files.retain(|file_name| !file_name.ends_with(".new"));
+        files.sort();
+        let current_key = self.get_crypto_key(username).await?;
+
+        if dry_run {
+            for file_name in &files {
+                reencrypt_secret(self, &username_hash, file_name, &current_key, &new_key).await?;
             }
+            return Ok(files.len())
         }
-    
-        let entries = self.read_dir(secrets_path.to_string()).await?;
-    
-        for file_name in entries {
-            if file_name.ends_with(".new") { continue }
-            let new_content = self.read_file(format!("secrets/{username_hash}/{file_name}.new")).await?;
-            self.write_file(format!("secrets/{username_hash}/{file_name}"), new_content).await?;
-            self.remove_file(format!("secrets/{username_hash}/{file_name}.new")).await?;
+
+        let mut journal = RotationJournal { phase: RotationPhase::Prepare, files };
+        write_journal(self, &username_hash, &journal).await?;
+        self.write_file(format!("keys/{username_hash}.new"), self.key_provider.wrap_key(&new_key).await?).await?;
+        for file_name in &journal.files {
+            if let Err(e) = self.update_encrypted_secret(file_name, &username_hash, &current_key, &new_key).await {
+                self.recover_crypto_key_rotation(&username_hash).await?;
+                return Err(format!("error updating encrypted secret {file_name}, the key was not changed: {e}").into())
+            }
         }
-        self.remove_all_new_encrypted(&username_hash).await?;
-    
-        self.write_file(format!("keys/{username_hash}"), new_key).await
+
+        journal.phase = RotationPhase::Commit;
+        write_journal(self, &username_hash, &journal).await?;
+        self.recover_crypto_key_rotation(&username_hash).await?;
+        Ok(journal.files.len())
     }
 
-    async fn update_encrypted_secret(&self, file_name: String, username_hash: &String, current_key: &Vec<u8>, new_key: &Vec<u8>) -> Result<(), ThreadSafeError> {
-        let encrypted = self.read_file(format!("secrets/{username_hash}/{file_name}")).await?;
-        let decrypted = openssl::symm::decrypt(openssl::symm::Cipher::aes_256_cbc(), &current_key, None, &encrypted)?;
-        let encrypted_new = openssl::symm::encrypt(openssl::symm::Cipher::aes_256_cbc(), &new_key, None, &decrypted)?;
-        self.write_file(format!("secrets/{file_name}.new"), encrypted_new).await?;
-    
+    // Writes {file}.new, encrypted with new_key, and checks it decrypts to the same secrets
+    async fn update_encrypted_secret(&self, file_name: &str, username_hash: &str, current_key: &[u8], new_key: &[u8]) -> Result<(), ThreadSafeError> {
+        let (decrypted, encrypted_new) = reencrypt_secret(self, username_hash, file_name, current_key, new_key).await?;
+        let new_path = format!("secrets/{username_hash}/{file_name}.new");
+        self.write_file(&new_path, encrypted_new).await?;
+        let (written, _) = crypto::decrypt(new_key, &format!("secrets/{username_hash}/{file_name}"), &self.read_file(&new_path).await?)?;
+        if written != decrypted { return Err(format!("{new_path} does not hold the same secrets").into()) }
         Ok(())
     }
 
-    async fn remove_all_new_encrypted(&self, username: &String) -> Result<(), ThreadSafeError> {
+    async fn remove_all_new_encrypted(&self, username_hash: &str) -> Result<(), ThreadSafeError> {
 
-        let username_hash: String = format!("{:x}", ByteBuf(&openssl::sha::sha256(&username.as_bytes())));
         let secrets_path = format!("secrets/{username_hash}");
-        let entries = self.read_dir(secrets_path).await.expect("error reading secrets directory");
-        for file_name in entries {
+        if !self.file_exists(&secrets_path).await { return Ok(()) }
+        for file_name in self.read_dir(secrets_path).await? {
             if file_name.ends_with(".new") {
                 self.remove_file(format!("secrets/{username_hash}/{file_name}")).await?;
             }
@@ -142,22 +319,344 @@ impl RaikiriEnvironmentSecrets for RaikiriEnvironment {
         Ok(())
     }
 
+    // Rolls back a rotation of username_hash that was not committed, or finishes a committed one.
+    // Every step can be run again, so a crash while recovering is recovered the next time. That
+    // includes renames that left their source behind, as they do on storage that copies objects.
+    async fn recover_crypto_key_rotation(&self, username_hash: &str) -> Result<(), ThreadSafeError> {
+        let journal_path = format!("keys/{username_hash}.rotation");
+        if self.file_exists(format!("{journal_path}.tmp")).await {
+            self.remove_file(format!("{journal_path}.tmp")).await?;
+        }
+        if !self.file_exists(&journal_path).await { return Ok(()) }
+        let journal: RotationJournal = serde_json::from_slice(&self.read_file(&journal_path).await?)?;
+        let new_key_path = format!("keys/{username_hash}.new");
+
+        if journal.phase == RotationPhase::Commit {
+            if self.file_exists(&new_key_path).await {
+                self.rename_file(&new_key_path, format!("keys/{username_hash}")).await?;
+            }
+            for file_name in &journal.files {
+                let new_path = format!("secrets/{username_hash}/{file_name}.new");
+                if self.file_exists(&new_path).await {
+                    self.rename_file(new_path, format!("secrets/{username_hash}/{file_name}")).await?;
+                }
+            }
+        }
+        else if self.file_exists(&new_key_path).await {
+            self.remove_file(&new_key_path).await?;
+        }
+        self.remove_all_new_encrypted(username_hash).await?;
+        self.remove_file(journal_path).await
+    }
+
+    async fn recover_crypto_key_rotations(&self) -> Result<(), ThreadSafeError> {
+        if !self.file_exists("keys").await { return Ok(()) }
+        for file_name in self.read_dir("keys").await? {
+            if let Some(username_hash) = file_name.strip_suffix(".rotation") {
+                let _lock = lock_secrets(self, username_hash).await;
+                self.recover_crypto_key_rotation(username_hash).await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn update_component_secrets(&self, user: String, name: String, secrets_content: Vec<u8>) -> Result<(), ThreadSafeError> {
+        let secrets = parse_secrets(&secrets_content)?;
+        let secrets = secrets.into_iter().map(|(key, value)| (Yaml::String(key), Yaml::String(value))).collect();
+        write_secrets(self, &user, &secrets_file(&user, &name), Yaml::Hash(secrets)).await?;
+        self.secrets_cache.invalidate(&format!("{user}.{name}")).await;
+        Ok(())
+    }
 
-        let secrets = YamlLoader::load_from_str(&String::from_utf8(secrets_content)?)?;
-        let secret = Self::serialize_yaml(secrets[0].clone()).await?;
-    
-        let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(&user.as_bytes())));
-        let username_component_name = format!("{user}.{name}");
-        let username_component_name_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(&username_component_name.as_bytes())));
-        let secrets_path = format!("secrets/{username_hash}");
-        self.create_dir(&secrets_path).await?;
-    
-        let crypto_key = self.get_crypto_key(user.to_string()).await?;
-    
-        let encrypted = openssl::symm::encrypt(openssl::symm::Cipher::aes_256_cbc(), &crypto_key, None, &secret.as_bytes())?;
-        self.write_file(format!("secrets/{username_hash}/{username_component_name_hash}"), encrypted).await?;
-    
+    async fn list_component_secrets(&self, user: String, name: String, show_values: bool) -> Result<Vec<SecretInfo>, ThreadSafeError> {
+        Ok(secrets_from_yaml(&self.get_component_secrets_yaml(user, name).await?)?
+            .into_iter()
+            .map(|(key, value)| SecretInfo { key, size: value.len(), value: show_values.then_some(value) })
+            .collect())
+    }
+
+    // Adds secrets to the ones of user.name, replacing those with the same names. The lock is held
+    // from the read to the write, so concurrent changes don't drop each other's keys.
+    async fn set_component_secrets(&self, user: String, name: String, secrets: Vec<(String, String)>) -> Result<(), ThreadSafeError> {
+        if secrets.iter().any(|(key, _)| key.is_empty()) { return Err("secret names can't be empty".into()) }
+        let secrets_file = secrets_file(&user, &name);
+        let lock = lock_secrets_for_change(self, &user).await?;
+        let mut current = decrypt_secrets(self, &user, &secrets_file).await?.0.into_hash().unwrap_or_default();
+        for (key, value) in secrets {
+            current.insert(Yaml::String(key), Yaml::String(value));
+        }
+        write_locked_secrets(self, &user, &secrets_file, Yaml::Hash(current), &lock).await?;
+        self.secrets_cache.invalidate(&format!("{user}.{name}")).await;
+        Ok(())
+    }
+
+    // Returns the keys that were removed, the secrets file goes away with the last of them
+    async fn unset_component_secrets(&self, user: String, name: String, keys: Vec<String>) -> Result<Vec<String>, ThreadSafeError> {
+        let secrets_file = secrets_file(&user, &name);
+        let lock = lock_secrets_for_change(self, &user).await?;
+        let mut current = decrypt_secrets(self, &user, &secrets_file).await?.0.into_hash().unwrap_or_default();
+        let removed = keys.into_iter()
+            .filter(|key| current.remove(&Yaml::String(key.clone())).is_some())
+            .collect::<Vec<_>>();
+        if removed.is_empty() { return Ok(removed) }
+        match current.is_empty() {
+            true => self.remove_file(&secrets_file).await?,
+            false => write_locked_secrets(self, &user, &secrets_file, Yaml::Hash(current), &lock).await?,
+        }
+        self.secrets_cache.invalidate(&format!("{user}.{name}")).await;
+        Ok(removed)
+    }
+
+    async fn remove_component_secrets(&self, user: String, name: String) -> Result<(), ThreadSafeError> {
+        let secrets_file = secrets_file(&user, &name);
+        if self.file_exists(&secrets_file).await {
+            let _lock = lock_secrets_for_change(self, &user).await?;
+            self.remove_file(secrets_file).await?;
+        }
+        self.secrets_cache.invalidate(&format!("{user}.{name}")).await;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use std::sync::Arc;
+
+    use crate::{adapters::{crypto::CipherSuite, keys::{is_wrapped_key, kms::{tests::start_kms, KmsConf}, passphrase::PassphraseKeyProvider, KeyProviderConf}}, domain::{raikiri_env_fs::RaikiriEnvironmentFS, tests::create_test_env}};
+
+    use super::{write_journal, ByteBuf, RaikiriEnvironmentSecrets, RotationJournal, RotationPhase};
+
+    #[tokio::test]
+    async fn test_secrets_encryption() {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(b"test")));
+        let secrets_file = |name: &str| format!("secrets/{username_hash}/{:x}", ByteBuf(&openssl::sha::sha256(format!("test.{name}").as_bytes())));
+
+        // the same secrets are encrypted differently for every file
+        environment.update_component_secrets("test".to_string(), "first".to_string(), b"KEY: value".to_vec()).await.unwrap();
+        environment.update_component_secrets("test".to_string(), "second".to_string(), b"KEY: value".to_vec()).await.unwrap();
+        let first = environment.read_file(secrets_file("first")).await.unwrap();
+        assert!(first.starts_with(b"RKS"));
+        assert_ne!(first, environment.read_file(secrets_file("second")).await.unwrap());
+
+        // files can't be moved to another component
+        environment.write_file(secrets_file("second"), first).await.unwrap();
+        assert!(environment.get_component_secrets("test".to_string(), "second".to_string()).await.is_err());
+
+        // files written by earlier versions are migrated when they are read
+        let key = environment.get_crypto_key("test".to_string()).await.unwrap();
+        let legacy = openssl::symm::encrypt(Cipher::aes_256_cbc(), &key, None, b"LEGACY: value").unwrap();
+        environment.write_file(secrets_file("legacy"), legacy).await.unwrap();
+        let expected = vec![("LEGACY".to_string(), "value".to_string())];
+        assert_eq!(environment.get_component_secrets("test".to_string(), "legacy".to_string()).await.unwrap(), expected);
+        assert!(environment.read_file(secrets_file("legacy")).await.unwrap().starts_with(b"RKS\x01\x01"));
+
+        // and so are the files of another suite
+        let environment = environment.with_cipher_suite(CipherSuite::ChaCha20Poly1305);
+        assert_eq!(environment.get_component_secrets("test".to_string(), "legacy".to_string()).await.unwrap(), expected);
+        assert!(environment.read_file(secrets_file("legacy")).await.unwrap().starts_with(b"RKS\x01\x02"));
+    }
+
+    async fn rotation_env() -> (crate::domain::raikiri_env::RaikiriEnvironment, String, Vec<String>) {
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        for name in ["first", "second", "third"] {
+            environment.update_component_secrets("test".to_string(), name.to_string(), format!("NAME: {name}").into_bytes()).await.unwrap();
+        }
+        let username_hash = format!("{:x}", ByteBuf(&openssl::sha::sha256(b"test")));
+        let mut files = environment.read_dir(format!("secrets/{username_hash}")).await.unwrap();
+        files.sort();
+        (environment, username_hash, files)
+    }
+
+    async fn assert_rotation_done(environment: &crate::domain::raikiri_env::RaikiriEnvironment, username_hash: &str, key: &[u8]) {
+        assert_eq!(environment.get_crypto_key("test".to_string()).await.unwrap(), key);
+        for name in ["first", "second", "third"] {
+            let secrets = environment.get_component_secrets("test".to_string(), name.to_string()).await.unwrap();
+            assert_eq!(secrets, vec![("NAME".to_string(), name.to_string())]);
+        }
+        let mut left = environment.read_dir(format!("secrets/{username_hash}")).await.unwrap();
+        left.extend(environment.read_dir("keys").await.unwrap());
+        assert!(left.iter().all(|file_name| !file_name.contains('.')), "{left:?}");
+    }
+
+    #[tokio::test]
+    async fn test_update_crypto_key() {
+
+        let (environment, username_hash, files) = rotation_env().await;
+        let current_key = environment.get_crypto_key("test".to_string()).await.unwrap();
+        let new_key = vec![9; 32];
+
+        assert!(environment.update_crypto_key("test".to_string(), vec![9; 16], false).await.is_err());
+
+        let encrypted = environment.read_file(format!("secrets/{username_hash}/{}", files[0])).await.unwrap();
+        assert_eq!(environment.update_crypto_key("test".to_string(), new_key.clone(), true).await.unwrap(), 3);
+        assert_eq!(environment.read_file(format!("secrets/{username_hash}/{}", files[0])).await.unwrap(), encrypted);
+        assert_rotation_done(&environment, &username_hash, &current_key).await;
+
+        assert_eq!(environment.update_crypto_key("test".to_string(), new_key.clone(), false).await.unwrap(), 3);
+        assert_rotation_done(&environment, &username_hash, &new_key).await;
+
+        // a secret that does not decrypt stops the rotation before anything changes
+        environment.write_file(format!("secrets/{username_hash}/{}", files[1]), b"garbage".to_vec()).await.unwrap();
+        assert!(environment.update_crypto_key("test".to_string(), vec![10; 32], true).await.is_err());
+        assert!(environment.update_crypto_key("test".to_string(), vec![10; 32], false).await.is_err());
+        assert_eq!(environment.get_crypto_key("test".to_string()).await.unwrap(), new_key);
+        assert!(!environment.file_exists(format!("keys/{username_hash}.rotation")).await);
+        assert!(!environment.file_exists(format!("secrets/{username_hash}/{}.new", files[0])).await);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_crypto_key_rotation() {
+
+        // stopped while preparing, the rotation is rolled back
+        let (environment, username_hash, files) = rotation_env().await;
+        let current_key = environment.get_crypto_key("test".to_string()).await.unwrap();
+        let new_key = vec![9; 32];
+
+        write_journal(&environment, &username_hash, &RotationJournal { phase: RotationPhase::Prepare, files: files.clone() }).await.unwrap();
+        environment.write_file(format!("keys/{username_hash}.new"), new_key.clone()).await.unwrap();
+        environment.update_encrypted_secret(&files[0], &username_hash, &current_key, &new_key).await.unwrap();
+        environment.write_file(format!("secrets/{username_hash}/{}.new", files[1]), b"half written".to_vec()).await.unwrap();
+
+        environment.recover_crypto_key_rotations().await.unwrap();
+        assert_rotation_done(&environment, &username_hash, &current_key).await;
+
+        // stopped while committing, the rotation is finished
+        let (environment, username_hash, files) = rotation_env().await;
+        let current_key = environment.get_crypto_key("test".to_string()).await.unwrap();
+
+        write_journal(&environment, &username_hash, &RotationJournal { phase: RotationPhase::Prepare, files: files.clone() }).await.unwrap();
+        environment.write_file(format!("keys/{username_hash}.new"), new_key.clone()).await.unwrap();
+        for file_name in &files {
+            environment.update_encrypted_secret(file_name, &username_hash, &current_key, &new_key).await.unwrap();
+        }
+        write_journal(&environment, &username_hash, &RotationJournal { phase: RotationPhase::Commit, files: files.clone() }).await.unwrap();
+        environment.rename_file(format!("keys/{username_hash}.new"), format!("keys/{username_hash}")).await.unwrap();
+        environment.rename_file(format!("secrets/{username_hash}/{}.new", files[0]), format!("secrets/{username_hash}/{}", files[0])).await.unwrap();
+
+        // secrets are not changed while it is pending, and a dry run only reports it
+        assert!(environment.set_component_secrets("test".to_string(), "first".to_string(), vec![("OTHER".to_string(), "value".to_string())]).await.is_err());
+        assert!(environment.remove_component_secrets("test".to_string(), "second".to_string()).await.is_err());
+        assert!(environment.update_crypto_key("test".to_string(), vec![10; 32], true).await.err().unwrap().to_string().contains("interrupted"));
+        assert!(environment.file_exists(format!("keys/{username_hash}.rotation")).await);
+
+        // the next rotation finishes it first
+        let newer_key = vec![10; 32];
+        assert_eq!(environment.update_crypto_key("test".to_string(), newer_key.clone(), false).await.unwrap(), 3);
+        assert_rotation_done(&environment, &username_hash, &newer_key).await;
+
+        // a change made while a rotation runs waits for it, instead of being written with the old key
+        let (rotated, changed) = tokio::join!(
+            environment.update_crypto_key("test".to_string(), vec![11; 32], false),
+            environment.set_component_secrets("test".to_string(), "first".to_string(), vec![("OTHER".to_string(), "value".to_string())]),
+        );
+        assert_eq!(rotated.unwrap(), 3);
+        changed.unwrap();
+        assert_eq!(environment.get_component_secrets("test".to_string(), "first".to_string()).await.unwrap().len(), 2);
+
+        // renames that left their source behind are done again
+        let (environment, username_hash, files) = rotation_env().await;
+        let current_key = environment.get_crypto_key("test".to_string()).await.unwrap();
+        for file_name in &files {
+            environment.update_encrypted_secret(file_name, &username_hash, &current_key, &new_key).await.unwrap();
+        }
+        environment.write_file(format!("keys/{username_hash}.new"), new_key.clone()).await.unwrap();
+        write_journal(&environment, &username_hash, &RotationJournal { phase: RotationPhase::Commit, files: files.clone() }).await.unwrap();
+        environment.write_file(format!("keys/{username_hash}.rotation.tmp"), b"{}".to_vec()).await.unwrap();
+        environment.write_file(format!("keys/{username_hash}"), new_key.clone()).await.unwrap();
+        let copied = environment.read_file(format!("secrets/{username_hash}/{}.new", files[0])).await.unwrap();
+        environment.write_file(format!("secrets/{username_hash}/{}", files[0]), copied).await.unwrap();
+        environment.recover_crypto_key_rotations().await.unwrap();
+        assert_rotation_done(&environment, &username_hash, &new_key).await;
+    }
+
+    // Data keys are wrapped by the configured provider, plaintext ones the first time they are used
+    #[tokio::test]
+    async fn test_wrapped_crypto_keys() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        environment.update_component_secrets("test".to_string(), "hello".to_string(), b"KEY: value".to_vec()).await.unwrap();
+        let data_key = environment.get_crypto_key("test".to_string()).await.unwrap();
+        let key_path = format!("keys/{:x}", ByteBuf(&openssl::sha::sha256(b"test")));
+        assert_eq!(environment.read_file(&key_path).await.unwrap(), data_key);
+
+        let endpoint = start_kms().await;
+        let environment = environment.clone().with_key_provider(KeyProviderConf::Kms(KmsConf::for_test(&endpoint)).build().unwrap());
+        let expected = vec![("KEY".to_string(), "value".to_string())];
+        assert_eq!(environment.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(), expected);
+        let wrapped = environment.read_file(&key_path).await.unwrap();
+        assert!(is_wrapped_key(&wrapped));
+        assert!(!wrapped.windows(data_key.len()).any(|window| window == data_key));
+        assert_eq!(environment.get_crypto_key("test".to_string()).await.unwrap(), data_key);
+
+        // rotated keys are wrapped as well
+        environment.update_crypto_key("test".to_string(), vec![9; 32], false).await.unwrap();
+        assert!(is_wrapped_key(&environment.read_file(&key_path).await.unwrap()));
+        assert_eq!(environment.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(), expected);
+
+        // new keys are never written unwrapped
+        let environment = environment.clone().with_key_provider(Arc::new(PassphraseKeyProvider::new("passphrase").unwrap()));
+        environment.update_component_secrets("test".to_string(), "other".to_string(), b"KEY: value".to_vec()).await.unwrap_err();
+        environment.update_component_secrets("other".to_string(), "hello".to_string(), b"KEY: value".to_vec()).await.unwrap();
+        let other_key_path = format!("keys/{:x}", ByteBuf(&openssl::sha::sha256(b"other")));
+        assert!(is_wrapped_key(&environment.read_file(other_key_path).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_component_secrets_commands() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let secrets = || environment.get_component_secrets("test".to_string(), "hello".to_string());
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<_>>();
+        assert_eq!(secrets().await.unwrap(), vec![]);
+
+        environment.update_component_secrets("test".to_string(), "hello".to_string(), b"A: one\nB: two".to_vec()).await.unwrap();
+        environment.set_component_secrets("test".to_string(), "hello".to_string(), pairs(&[("B", "changed"), ("C", "three")])).await.unwrap();
+        assert_eq!(secrets().await.unwrap(), pairs(&[("A", "one"), ("B", "changed"), ("C", "three")]));
+        assert!(environment.set_component_secrets("test".to_string(), "hello".to_string(), pairs(&[("", "empty")])).await.is_err());
+        assert!(environment.update_component_secrets("test".to_string(), "hello".to_string(), b"PORT: 5432".to_vec()).await.is_err());
+
+        let listed = environment.list_component_secrets("test".to_string(), "hello".to_string(), false).await.unwrap();
+        assert_eq!(listed.iter().map(|secret| (secret.key.as_str(), secret.size)).collect::<Vec<_>>(), vec![("A", 3), ("B", 7), ("C", 5)]);
+        assert!(listed.iter().all(|secret| secret.value.is_none()));
+        assert!(!serde_json::to_string(&listed).unwrap().contains("changed"));
+        let listed = environment.list_component_secrets("test".to_string(), "hello".to_string(), true).await.unwrap();
+        assert_eq!(listed[1].value.as_deref(), Some("changed"));
+
+        // changes drop the values cached for invocations
+        environment.secrets_cache.get_entry_by_key_async_build("test.hello".to_string(), async { secrets().await.unwrap() }).await;
+        let removed = environment.unset_component_secrets("test".to_string(), "hello".to_string(), vec!["A".to_string(), "MISSING".to_string()]).await.unwrap();
+        assert_eq!(removed, vec!["A".to_string()]);
+        assert!(environment.secrets_cache.get_entry(&"test.hello".to_string()).await.is_none());
+        assert_eq!(secrets().await.unwrap(), pairs(&[("B", "changed"), ("C", "three")]));
+
+        // the file goes away with the last secret
+        environment.unset_component_secrets("test".to_string(), "hello".to_string(), vec!["B".to_string(), "C".to_string()]).await.unwrap();
+        assert!(!environment.component_has_secrets("test".to_string(), "hello".to_string()).await);
+
+        environment.set_component_secrets("test".to_string(), "hello".to_string(), pairs(&[("A", "one")])).await.unwrap();
+        environment.secrets_cache.get_entry_by_key_async_build("test.hello".to_string(), async { secrets().await.unwrap() }).await;
+        environment.remove_component_secrets("test".to_string(), "hello".to_string()).await.unwrap();
+        assert!(!environment.component_has_secrets("test".to_string(), "hello".to_string()).await);
+        assert!(environment.secrets_cache.get_entry(&"test.hello".to_string()).await.is_none());
+
+        // concurrent changes keep each other's keys
+        let keys = (0..8).map(|i| format!("KEY_{i}")).collect::<Vec<_>>();
+        futures::future::join_all(keys.iter().map(|key| {
+            environment.set_component_secrets("test".to_string(), "hello".to_string(), vec![(key.clone(), "value".to_string())])
+        })).await.into_iter().for_each(|set| set.unwrap());
+        assert_eq!(secrets().await.unwrap().len(), keys.len());
+        futures::future::join_all(keys[..4].iter().map(|key| {
+            environment.unset_component_secrets("test".to_string(), "hello".to_string(), vec![key.clone()])
+        })).await.into_iter().for_each(|unset| assert_eq!(unset.unwrap().len(), 1));
+        assert_eq!(secrets().await.unwrap().into_iter().map(|(key, _)| key).collect::<Vec<_>>(), keys[4..].to_vec());
+    }
+}
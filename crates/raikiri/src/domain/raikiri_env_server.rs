@@ -1,17 +1,19 @@
-use std::net::SocketAddr;
+use std::{convert::Infallible, future::Future, net::{IpAddr, SocketAddr}, str::FromStr, time::Duration};
 
 use async_trait::async_trait;
 use futures::stream;
-use http::{Request, Response};
-use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
-use hyper::{body::{Body, Bytes, Frame, Incoming}, server::conn::http1, service::service_fn};
-use tokio::net::TcpListener;
-use wasmtime_wasi_http::{bindings::http::types::ErrorCode, io::TokioIo};
+use http::{HeaderName, HeaderValue, Request, Response, Uri};
+use http_body_util::{combinators::BoxBody, BodyExt, LengthLimitError, Limited, StreamBody};
+use hyper::{body::{Body, Bytes, Frame, Incoming}, service::service_fn};
+use hyper_util::{rt::TokioExecutor, server::conn::auto};
+use openssl::ssl::SslAcceptor;
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, UnixListener}, signal::unix::{signal, SignalKind}, sync::{mpsc, watch}};
+use wasmtime_wasi_http::{bindings::http::types::ErrorCode, hyper_request_error, io::TokioIo};
 
 
-use crate::{ComponentImports, Wasi};
+use crate::{adapters::{api_gateway::{ApiGateway, CorsConf}, router::{RouteMatch, Router}, tls::tls_accept, validation::ComponentValidationError}, ComponentImports, Wasi};
 
-use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_component::RaikiriComponentStorage, raikiri_env_invoke::RaikiriEnvironmentInvoke, raikiri_env_secrets::RaikiriEnvironmentSecrets};
+use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_access::RaikiriEnvironmentAccess, raikiri_env_api_gateway::RaikiriEnvironmentApiGateway, raikiri_env_component::RaikiriComponentStorage, raikiri_env_invoke::RaikiriEnvironmentInvoke, raikiri_env_secret_groups::RaikiriEnvironmentSecretGroups, raikiri_env_secrets::{parse_secrets, RaikiriEnvironmentSecrets}, raikiri_env_tenants::{ApiKeyScope, Principal, RaikiriEnvironmentTenants}};
 
 #[async_trait]
 pub trait RaikiriEnvironmentServer {
@@ -23,6 +25,9 @@ pub trait RaikiriEnvironmentServer {
     where
         E: Send + Sync + 'static;
     async fn run_server(&self) -> Result<(), ThreadSafeError>;
+    async fn run_server_until<F>(&self, shutdown: F) -> Result<(), ThreadSafeError>
+    where
+        F: Future<Output = ()> + Send;
 }
 
 #[async_trait]
@@ -55,26 +60,170 @@ impl RaikiriEnvironmentServer for RaikiriEnvironment {
     }
 
     async fn run_server(&self) -> Result<(), ThreadSafeError> {
-        let self = self.clone();
-        tokio::spawn(async move {
-            let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-            let listener = TcpListener::bind(addr).await.unwrap();
-            loop {
-                let (stream, _) = listener.accept().await.unwrap(); 
-                let io = TokioIo::new(stream);
-                let self = self.clone();
-        
-                tokio::task::spawn(async move {
-                    if let Err(err) = http1::Builder::new()
-                        .serve_connection(io, service_fn(|req| handle_request::<Incoming>(&self, req)))
-                        .await
-                    {
-                        eprintln!("Error serving connection: {:?}", err);
+        self.run_server_until(shutdown_signal()).await
+    }
+
+    // Binds to self.bind_address and self.port and serves requests until shutdown resolves.
+    // Then it stops accepting, waits up to self.drain_timeout for open connections to finish
+    // their in-flight requests and flushes pending component events.
+    async fn run_server_until<F>(&self, shutdown: F) -> Result<(), ThreadSafeError>
+    where
+        F: Future<Output = ()> + Send,
+    {
+        let listener = Listener::bind(&self.bind_address, self.port).await?;
+        let tls_acceptor = match &self.tls {
+            Some(tls) => Some((tls.build_acceptor()?, tls.handshake_timeout)),
+            None => None
+        };
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        // every connection holds a sender, recv returns None once all of them are closed
+        let (drain_sender, mut drain_receiver) = mpsc::channel::<()>(1);
+        let _component_watcher = self.watch_components()?;
+
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok(io) => spawn_connection(self.clone(), io, tls_acceptor.clone(), shutdown_receiver.clone(), drain_sender.clone()),
+                Err(err) => eprintln!("Error accepting connection: {:?}", err),
+            }
+        }
+
+        println!("Shutting down Raikiri server, draining connections...");
+        listener.close().await;
+        shutdown_sender.send_replace(true);
+        drop(drain_sender);
+        if tokio::time::timeout(self.drain_timeout, drain_receiver.recv()).await.is_err() {
+            eprintln!("Drain deadline of {}s reached, dropping remaining connections", self.drain_timeout.as_secs());
+        }
+        self.flush_events().await;
+        println!("Raikiri server stopped");
+        Ok(())
+    }
+}
+
+pub async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("could not listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T> Connection for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    async fn bind(bind_address: &BindAddress, port: u16) -> Result<Listener, ThreadSafeError> {
+        match bind_address {
+            BindAddress::Tcp(ip) => {
+                let listener = TcpListener::bind(SocketAddr::new(*ip, port)).await?;
+                println!("Raikiri server listening on {}", listener.local_addr()?);
+                Ok(Listener::Tcp(listener))
+            }
+            BindAddress::Unix(path) => {
+                // a socket file left behind by a previous run would make bind fail
+                _ = tokio::fs::remove_file(path).await;
+                let listener = UnixListener::bind(path)?;
+                println!("Raikiri server listening on unix:{path}");
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<Box<dyn Connection>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            Listener::Unix(listener, _) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+
+    async fn close(self) {
+        if let Listener::Unix(listener, path) = self {
+            drop(listener);
+            _ = tokio::fs::remove_file(path).await;
+        }
+    }
+}
+
+// Serves HTTP/1.1 and HTTP/2 on the same listener. Without TLS, HTTP/2 is detected from the
+// connection preface (h2c with prior knowledge), with TLS it is negotiated through ALPN.
+fn spawn_connection(environment: RaikiriEnvironment, io: Box<dyn Connection>, tls_acceptor: Option<(SslAcceptor, Duration)>, mut shutdown: watch::Receiver<bool>, drain: mpsc::Sender<()>) {
+    tokio::task::spawn(async move {
+        let io: Box<dyn Connection> = match tls_acceptor {
+            Some((tls_acceptor, handshake_timeout)) => match tls_accept(&tls_acceptor, io, handshake_timeout).await {
+                Ok(stream) => Box::new(stream),
+                Err(err) => {
+                    eprintln!("Error during TLS handshake: {:?}", err);
+                    return
+                }
+            },
+            None => io
+        };
+        let builder = auto::Builder::new(TokioExecutor::new());
+        // HTTP/2 streams are spawned as tasks, so each request gets its own handle to the environment
+        let service = service_fn(move |req| {
+            let environment = environment.clone();
+            async move {
+                match handle_request::<Incoming>(&environment, req).await {
+                    Ok(response) => Ok::<_, Infallible>(response),
+                    Err(err) => {
+                        eprintln!("Error handling request: {:?}", err);
+                        Ok(Response::builder()
+                            .status(500)
+                            .body(RaikiriEnvironment::response_body("").await)
+                            .unwrap())
                     }
-                });
+                }
             }
         });
-        Ok(())
+        let connection = builder.serve_connection(TokioIo::new(io), service);
+        tokio::pin!(connection);
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            _ = async { _ = shutdown.wait_for(|shutdown| *shutdown).await; } => {
+                // lets the requests being served finish, then closes the connection
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("Error serving connection: {:?}", err);
+        }
+        drop(drain);
+    });
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BindAddress {
+    Tcp(IpAddr),
+    Unix(String),
+}
+
+impl Default for BindAddress {
+    fn default() -> Self {
+        BindAddress::Tcp(IpAddr::from([127, 0, 0, 1]))
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = ThreadSafeError;
+
+    // Accepts an IPv4 or IPv6 address (optionally in brackets), or unix:{path} for a Unix socket.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(BindAddress::Unix(path.to_string())),
+            Some(_) => Err("unix bind address is missing the socket path".into()),
+            None => Ok(BindAddress::Tcp(s.trim_start_matches('[').trim_end_matches(']').parse()?)),
+        }
     }
 }
 
@@ -83,18 +232,37 @@ pub async fn handle_request<B>(_self: &RaikiriEnvironment, request: Request<B>)
     where
         B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static
 {
-    let command = request.headers()
-        .get("Platform-Command").unwrap()
-        .to_str().unwrap();
+    let command = match request.headers().get("Platform-Command") {
+        Some(command) => command.to_str().unwrap().to_string(),
+        None => return handle_routed_request(_self, request).await
+    };
+    let principal = match authenticate_request(_self, &request, required_scope(&command)).await {
+        Ok(principal) => principal,
+        Err(response) => return Ok(response)
+    };
 
-    match command {
+    match command.as_str() {
         "Put-Component" => {
             let component_name = request.headers().get("Component-Id").unwrap()
                 .to_str().unwrap().to_string();
-            let component_bytes = BoxBody::new(request.into_body()).collect().await.unwrap().to_bytes().to_vec();
-            _self.add_component(_self.username.clone(), component_name, component_bytes).await.unwrap();
+            let component_bytes = match read_request_body(_self, request).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response)
+            };
+            let revision = match _self.add_component(principal.tenant.clone(), component_name, component_bytes).await {
+                Ok(revision) => revision,
+                Err(e) => match e.downcast_ref::<ComponentValidationError>() {
+                    Some(validation_error) => return Ok(Response::builder()
+                        .status(validation_error.status())
+                        .body(RaikiriEnvironment::response_body(validation_error.to_string()).await)
+                        .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                        .unwrap()),
+                    None => return Err(e)
+                }
+            };
             Ok(Response::builder()
                 .status(200)
+                .header("Component-Version", revision.to_string())
                 .body(RaikiriEnvironment::response_body("").await)
                 .map_err(|_| ErrorCode::ConnectionReadTimeout)
                 .unwrap())
@@ -107,43 +275,291 @@ pub async fn handle_request<B>(_self: &RaikiriEnvironment, request: Request<B>)
                 .to_str()
                 .unwrap()
                 .to_string();
-            
-            let secrets_entry = _self.secrets_cache
-                .get_entry_by_key_async_build(username_component_name.clone(), async {
-                    let (username, component_name) = username_component_name.split_once('.').unwrap();
-                    _self.get_component_secrets(username.to_string(), component_name.to_string())
-                        .await
-                        .unwrap_or_else(|_| Vec::new())
-                })
-                .await;
-            let secrets = secrets_entry.read().await;
-            let component_imports = ComponentImports {
-                call_stack: vec![username_component_name.clone()],
-                environment: _self.clone(),
-                db_connections: Default::default()
-            };
-            let response = _self.invoke_component(
-                username_component_name.clone(),
-                request,
-                Wasi::new(component_imports, secrets.to_vec()),
-            )
-            .await
-            .unwrap();
+            // the owner can always run its components, other tenants only the ones reachable over HTTP
+            if username_component_name.split_once('.').map(|(owner, _)| owner) != Some(principal.tenant.as_str()) {
+                if let Err(reason) = _self.check_component_access(&username_component_name, None).await {
+                    return Ok(Response::builder()
+                        .status(403)
+                        .body(RaikiriEnvironment::response_body(format!("Component {username_component_name} belongs to another tenant: {reason}")).await)
+                        .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                        .unwrap())
+                }
+            }
 
-            let (parts, body) = response.resp.into_parts();
-            Ok(hyper::Response::from_parts(parts, body))
+            invoke_with_secrets(_self, username_component_name, request).await
         }
         "Update-Component-Secrets" => {
             let component_name = request.headers().get("Component-Id").unwrap()
                 .to_str().unwrap().to_string();
-            let secrets_content = BoxBody::new(request.into_body()).collect().await.unwrap().to_bytes().to_vec();
-            _self.update_component_secrets(_self.username.clone(), component_name, secrets_content).await.unwrap();
+            let secrets_content = match read_request_body(_self, request).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response)
+            };
+            let (status, body) = match _self.update_component_secrets(principal.tenant.clone(), component_name, secrets_content).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        // values are only returned to admin keys asking for them with Show-Secret-Values: true
+        "List-Component-Secrets" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            let show_values = request.headers().get("Show-Secret-Values").is_some_and(|show_values| show_values == "true");
+            if show_values && !principal.scope.allows(ApiKeyScope::Admin) {
+                return Ok(Response::builder()
+                    .status(403)
+                    .body(RaikiriEnvironment::response_body("Showing secret values needs an admin api key").await)
+                    .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                    .unwrap())
+            }
+            let secrets = _self.list_component_secrets(principal.tenant.clone(), component_name, show_values).await?;
+            Ok(json_response(200, serde_json::to_vec(&secrets)?).await)
+        }
+        // the body is a YAML mapping merged into the current secrets
+        "Set-Component-Secrets" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            let secrets_content = match read_request_body(_self, request).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response)
+            };
+            let result = match parse_secrets(&secrets_content) {
+                Ok(secrets) => _self.set_component_secrets(principal.tenant.clone(), component_name, secrets).await,
+                Err(e) => Err(e)
+            };
+            let (status, body) = match result {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        // Secret-Keys is a comma separated list, the keys that were removed are returned
+        "Unset-Component-Secrets" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            let keys = header_value(&request, "Secret-Keys")?
+                .split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect();
+            let removed = _self.unset_component_secrets(principal.tenant.clone(), component_name, keys).await?;
+            Ok(json_response(200, serde_json::to_vec(&removed)?).await)
+        }
+        "Delete-Component-Secrets" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            _self.remove_component_secrets(principal.tenant.clone(), component_name).await?;
             Ok(Response::builder()
                 .status(200)
                 .body(RaikiriEnvironment::response_body("").await)
                 .map_err(|_| ErrorCode::ConnectionReadTimeout)
                 .unwrap())
         }
+        // group values are never returned, the body is the YAML mapping replacing them
+        "Update-Secret-Group" => {
+            let group = header_value(&request, "Secret-Group-Id")?;
+            let secrets_content = match read_request_body(_self, request).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response)
+            };
+            let (status, body) = match _self.update_secret_group(principal.tenant.clone(), group, secrets_content).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        "List-Secret-Groups" => {
+            let groups = _self.list_secret_groups(principal.tenant.clone()).await?
+                .into_iter()
+                .map(|group| format!("{}.{group}", principal.tenant))
+                .collect::<Vec<_>>();
+            Ok(json_response(200, serde_json::to_vec(&groups)?).await)
+        }
+        "Delete-Secret-Group" => {
+            let group = header_value(&request, "Secret-Group-Id")?;
+            let (status, body) = match _self.remove_secret_group(principal.tenant.clone(), group).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        "Attach-Secret-Group" | "Detach-Secret-Group" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            let group = header_value(&request, "Secret-Group-Id")?;
+            let result = match command.as_str() {
+                "Attach-Secret-Group" => _self.attach_secret_group(principal.tenant.clone(), component_name, group).await,
+                _ => _self.detach_secret_group(principal.tenant.clone(), component_name, group).await,
+            };
+            let (status, body) = match result {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        "Update-Component-Access" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            let access_content = match read_request_body(_self, request).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response)
+            };
+            let (status, body) = match _self.update_component_access(principal.tenant.clone(), component_name, access_content).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        "Rollback-Component" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            let revision = component_version(&request)?;
+            let (status, body) = match _self.set_current_revision(principal.tenant.clone(), component_name, revision).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (404, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        // platform keys see the components of every tenant
+        "List-Components" => {
+            let components = _self.list_components().await?
+                .into_iter()
+                .filter(|component| principal.can_manage(&component.owner))
+                .collect::<Vec<_>>();
+            Ok(json_response(200, serde_json::to_vec(&components)?).await)
+        }
+        "Inspect-Component" => {
+            let component_name = header_value(&request, "Component-Id")?;
+            if !_self.component_exists(principal.tenant.clone(), component_name.clone()).await {
+                return Ok(Response::builder()
+                    .status(404)
+                    .body(RaikiriEnvironment::response_body(format!("Component {}.{component_name} not found", principal.tenant)).await)
+                    .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                    .unwrap())
+            }
+            let inspection = _self.inspect_component(principal.tenant.clone(), component_name).await?;
+            Ok(json_response(200, serde_json::to_vec(&inspection)?).await)
+        }
+        "Put-Api-Gateway" => {
+            let version = api_gateway_version(&request)?;
+            let yml_bytes = match read_request_body(_self, request).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response)
+            };
+            let result = match _self.put_api_gateway(version, yml_bytes).await {
+                Ok(_) => _self.activate_api_gateway(version).await,
+                Err(e) => Err(e)
+            };
+            let (status, body) = match result {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        "Activate-Api-Gateway" => {
+            let version = api_gateway_version(&request)?;
+            let (status, body) = match _self.activate_api_gateway(version).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (404, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        "Create-Tenant" => {
+            let tenant = header_value(&request, "Tenant-Id")?;
+            let (status, body) = match _self.create_tenant(tenant).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (400, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+        // the key is only returned here, it is stored hashed
+        "Create-Api-Key" => {
+            let tenant = header_value(&request, "Tenant-Id")?;
+            let scope = header_value(&request, "Api-Key-Scope")?.parse::<ApiKeyScope>()?;
+            if !principal.can_manage(&tenant) {
+                return Ok(forbidden(format!("Tenant {tenant} is not the tenant of the api key")).await)
+            }
+            if !principal.scope.allows(scope) {
+                return Ok(forbidden(format!("A {} api key can't create {scope} api keys", principal.scope)).await)
+            }
+            match _self.create_api_key(tenant, scope).await {
+                Ok((api_key, key)) => {
+                    let mut body = serde_json::to_value(&api_key)?;
+                    body["key"] = serde_json::Value::String(key);
+                    Ok(json_response(200, serde_json::to_vec(&body)?).await)
+                }
+                Err(e) => Ok(Response::builder()
+                    .status(404)
+                    .body(RaikiriEnvironment::response_body(e.to_string()).await)
+                    .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                    .unwrap())
+            }
+        }
+        "List-Api-Keys" => {
+            let tenant = match principal.scope {
+                ApiKeyScope::Platform => None,
+                _ => Some(principal.tenant.clone()),
+            };
+            let api_keys = _self.list_api_keys(tenant).await?;
+            Ok(json_response(200, serde_json::to_vec(&api_keys)?).await)
+        }
+        "Revoke-Api-Key" => {
+            let id = header_value(&request, "Api-Key-Id")?;
+            match _self.get_api_key(&id).await {
+                Ok(Some(api_key)) if !principal.can_manage(&api_key.tenant) => {
+                    return Ok(forbidden(format!("Api key {id} belongs to another tenant")).await)
+                }
+                Ok(_) => {}
+                Err(e) => return Ok(Response::builder()
+                    .status(409)
+                    .body(RaikiriEnvironment::response_body(e.to_string()).await)
+                    .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                    .unwrap())
+            }
+            let (status, body) = match _self.revoke_api_key(id).await {
+                Ok(_) => (200, String::new()),
+                Err(e) => (404, e.to_string())
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(RaikiriEnvironment::response_body(body).await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
         _ => {
             return Ok(Response::builder()
                 .status(404)
@@ -154,14 +570,253 @@ pub async fn handle_request<B>(_self: &RaikiriEnvironment, request: Request<B>)
     }
 }
 
+// Requests without a Platform-Command header are matched against the active api gateway,
+// or against the routes from raikiri.yaml when no gateway was activated.
+// The part of the path matched by a trailing wildcard is forwarded to the component,
+// and path parameters are passed as Raikiri-Path-Param-{name} headers.
+pub async fn handle_routed_request<B>(_self: &RaikiriEnvironment, request: Request<B>) ->
+    Result<Response<BoxBody<Bytes, ErrorCode>>, ThreadSafeError>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static
+{
+    let api_gateway = _self.api_gateway.read().await.clone();
+    if let Some(api_gateway) = api_gateway {
+        return handle_api_gateway_request(_self, &api_gateway, request).await
+    }
+
+    let Some(route) = match_route(&_self.router, &request) else {
+        return Ok(Response::builder()
+            .status(404)
+            .body(RaikiriEnvironment::response_body("").await)
+            .map_err(|_| ErrorCode::ConnectionReadTimeout)
+            .unwrap())
+    };
+    let username_component_name = route.username_component_name.clone();
+    if let Err(reason) = _self.check_component_access(&username_component_name, None).await {
+        return Ok(forbidden(reason).await)
+    }
+    invoke_with_secrets(_self, username_component_name, forward_request(request, route)?).await
+}
+
+async fn handle_api_gateway_request<B>(_self: &RaikiriEnvironment, api_gateway: &ApiGateway, request: Request<B>) ->
+    Result<Response<BoxBody<Bytes, ErrorCode>>, ThreadSafeError>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static
+{
+    if let Some(cors) = &api_gateway.cors {
+        if CorsConf::is_preflight(request.method(), request.headers()) {
+            let mut response = Response::builder()
+                .status(204)
+                .body(RaikiriEnvironment::response_body("").await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap();
+            if !cors.apply_preflight(request.headers(), response.headers_mut()) {
+                *response.status_mut() = http::StatusCode::FORBIDDEN;
+            }
+            return Ok(response)
+        }
+    }
+
+    let Some(route) = match_route(&api_gateway.router, &request) else {
+        return Ok(Response::builder()
+            .status(404)
+            .body(RaikiriEnvironment::response_body("").await)
+            .map_err(|_| ErrorCode::ConnectionReadTimeout)
+            .unwrap())
+    };
+    let gateway_route = &api_gateway.routes[route.index];
+    let origin = request.headers().get(http::header::ORIGIN).cloned();
+    let username_component_name = route.username_component_name.clone();
+    if let Err(reason) = _self.check_component_access(&username_component_name, None).await {
+        return Ok(forbidden(reason).await)
+    }
+
+    let mut request = forward_request(request, route)?;
+    gateway_route.request_headers.apply(request.headers_mut());
+
+    let mut response = invoke_with_secrets(_self, username_component_name, request).await?;
+    gateway_route.response_headers.apply(response.headers_mut());
+    response.headers_mut().insert("Api-Gateway-Version", HeaderValue::from(api_gateway.version));
+    if let Some(cors) = &api_gateway.cors {
+        cors.apply(origin.as_ref(), response.headers_mut());
+    }
+    Ok(response)
+}
+
+async fn forbidden(reason: String) -> Response<BoxBody<Bytes, ErrorCode>> {
+    Response::builder()
+        .status(403)
+        .body(RaikiriEnvironment::response_body(reason).await)
+        .map_err(|_| ErrorCode::ConnectionReadTimeout)
+        .unwrap()
+}
+
+fn match_route<B>(router: &Router, request: &Request<B>) -> Option<RouteMatch> {
+    let host = request.headers().get(http::header::HOST)
+        .and_then(|host| host.to_str().ok());
+    router.match_request(request.method(), request.uri(), host)
+}
+
+fn forward_request<B>(request: Request<B>, route: RouteMatch) -> Result<Request<B>, ThreadSafeError> {
+    let (mut parts, body) = request.into_parts();
+    let path_and_query = match parts.uri.query() {
+        Some(query) => format!("{}?{query}", route.forwarded_path),
+        None => route.forwarded_path.clone()
+    };
+    let mut uri_parts = parts.uri.into_parts();
+    uri_parts.path_and_query = Some(path_and_query.parse()?);
+    parts.uri = Uri::from_parts(uri_parts)?;
+    for (name, value) in route.params {
+        parts.headers.insert(
+            HeaderName::from_bytes(format!("Raikiri-Path-Param-{name}").as_bytes())?,
+            HeaderValue::from_str(&value)?
+        );
+    }
+    Ok(Request::from_parts(parts, body))
+}
+
+// Admin commands need an api key, sent as Authorization: Bearer {key}. They run for the tenant
+// the key belongs to, so callers only deploy to and invoke components of their own tenant.
+async fn authenticate_request<B>(_self: &RaikiriEnvironment, request: &Request<B>, required: ApiKeyScope) ->
+    Result<Principal, Response<BoxBody<Bytes, ErrorCode>>>
+{
+    let key = request.headers().get(http::header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    let (status, body) = match key {
+        None => (401, "Missing api key".to_string()),
+        Some(key) => match _self.authenticate(key.trim()).await {
+            Ok(Some(principal)) if principal.scope.allows(required) => return Ok(principal),
+            Ok(Some(principal)) => (403, format!("Api key {} can't run {required} commands", principal.key_id)),
+            Ok(None) => (401, "Invalid api key".to_string()),
+            Err(e) => {
+                eprintln!("error authenticating request: {e}");
+                (500, String::new())
+            }
+        }
+    };
+    Err(Response::builder()
+        .status(status)
+        .header(http::header::WWW_AUTHENTICATE, "Bearer")
+        .body(RaikiriEnvironment::response_body(body).await)
+        .map_err(|_| ErrorCode::ConnectionReadTimeout)
+        .unwrap())
+}
+
+fn required_scope(command: &str) -> ApiKeyScope {
+    match command {
+        "Put-Api-Gateway" | "Activate-Api-Gateway" | "Create-Tenant" => ApiKeyScope::Platform,
+        "Create-Api-Key" | "List-Api-Keys" | "Revoke-Api-Key" => ApiKeyScope::Admin,
+        _ => ApiKeyScope::Deploy,
+    }
+}
+
+fn header_value<B>(request: &Request<B>, name: &str) -> Result<String, ThreadSafeError> {
+    Ok(request.headers().get(name)
+        .ok_or(format!("missing {name} header"))?
+        .to_str()?
+        .to_string())
+}
+
+fn api_gateway_version<B>(request: &Request<B>) -> Result<i32, ThreadSafeError> {
+    Ok(request.headers().get("Api-Gateway-Version")
+        .ok_or("missing Api-Gateway-Version header")?
+        .to_str()?
+        .parse::<i32>()?)
+}
+
+async fn json_response(status: u16, body: Vec<u8>) -> Response<BoxBody<Bytes, ErrorCode>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(RaikiriEnvironment::response_body_bytes(body).await)
+        .unwrap()
+}
+
+fn component_version<B>(request: &Request<B>) -> Result<u32, ThreadSafeError> {
+    Ok(request.headers().get("Component-Version")
+        .ok_or("missing Component-Version header")?
+        .to_str()?
+        .parse::<u32>()?)
+}
+
+// Admin payloads are stored whole so they are collected in memory, up to the max request body size.
+async fn read_request_body<B>(_self: &RaikiriEnvironment, request: Request<B>) ->
+    Result<Vec<u8>, Response<BoxBody<Bytes, ErrorCode>>>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static
+{
+    let limit = _self.body_limits.max_request_body_size.unwrap_or(u64::MAX);
+    let status = if _self.body_limits.request_too_large(request.headers()) {
+        413
+    }
+    else {
+        match Limited::new(request.into_body(), limit.try_into().unwrap_or(usize::MAX)).collect().await {
+            Ok(body) => return Ok(body.to_bytes().to_vec()),
+            Err(e) if e.is::<LengthLimitError>() => 413,
+            Err(_) => 400
+        }
+    };
+    Err(Response::builder()
+        .status(status)
+        .body(RaikiriEnvironment::response_body("").await)
+        .map_err(|_| ErrorCode::ConnectionReadTimeout)
+        .unwrap())
+}
+
+async fn invoke_with_secrets<B>(_self: &RaikiriEnvironment, username_component_name: String, request: Request<B>) ->
+    Result<Response<BoxBody<Bytes, ErrorCode>>, ThreadSafeError>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static
+{
+    let secrets_entry = _self.secrets_cache
+        .get_entry_by_key_async_build(username_component_name.clone(), async {
+            let (username, component_name) = username_component_name.split_once('.').unwrap();
+            _self.get_component_secrets(username.to_string(), component_name.to_string())
+                .await
+                .unwrap_or_else(|_| Vec::new())
+        })
+        .await;
+    let secrets = secrets_entry.read().await;
+    let component_imports = ComponentImports {
+        call_stack: vec![username_component_name.clone()],
+        environment: _self.clone(),
+        db_connections: Default::default()
+    };
+    let response = match _self.invoke_component(
+        username_component_name.clone(),
+        request.map(|body| body.map_err(hyper_request_error)),
+        Wasi::new(component_imports, secrets.to_vec()),
+    ).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("error invoking {username_component_name}: {e}");
+            return Ok(Response::builder()
+                .status(500)
+                .body(RaikiriEnvironment::response_body("Internal Server Error").await)
+                .map_err(|_| ErrorCode::ConnectionReadTimeout)
+                .unwrap())
+        }
+    };
+
+    let (parts, body) = response.resp.into_parts();
+    Ok(hyper::Response::from_parts(parts, body))
+}
+
 #[cfg(test)]
 mod tests {
 
     use anyhow::{Ok, Result};
     use http::StatusCode;
-    use http_body_util::BodyExt;
+    use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+    use hyper::body::{Bytes, Frame};
+    use hyper_util::rt::TokioExecutor;
+    use openssl::ssl::{SslConnector, SslMethod};
+    use wasmtime_wasi_http::io::TokioIo;
+
+    use wasmtime_wasi_http::bindings::http::types::ErrorCode;
 
-    use crate::domain::{raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_server::handle_request, tests::{create_test_env, make_invoke_component_request, make_put_component_request}};
+    use crate::{adapters::{access::Visibility, body::BodyLimits, limits::{ComponentLimits, LimitsConf, ResourceLimit}, router::{Route, Router}, tls::TlsConf}, domain::{raikiri_env::{ComponentEvent, RaikiriEnvironment}, raikiri_env_access::RaikiriEnvironmentAccess, raikiri_env_component::RaikiriComponentStorage, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_secret_groups::RaikiriEnvironmentSecretGroups, raikiri_env_secrets::RaikiriEnvironmentSecrets, raikiri_env_server::{handle_request, BindAddress, RaikiriEnvironmentServer}, raikiri_env_tenants::{ApiKeyScope, RaikiriEnvironmentTenants}, tests::{create_test_certificate, create_test_env, make_invoke_component_request, make_put_api_gateway_request, make_put_component_request, TEST_API_KEY}}};
 
     #[tokio::test]
     async fn test_start_server() -> Result<()> {
@@ -203,26 +858,1070 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_invoke_hello() -> Result<(), wasmtime::Error> {
+    async fn test_component_revisions() -> Result<(), wasmtime::Error> {
 
         let environment = create_test_env();
         environment.setup_fs().await.unwrap();
 
-        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "hello").await;
-        let res = handle_request(&environment, req).await;
+        let invoke = |version: Option<&'static str>| {
+            let environment = environment.clone();
+            async move {
+                let mut req = make_invoke_component_request("test.hello", "GET", "").await;
+                if let Some(version) = version {
+                    req.headers_mut().insert("Component-Version", version.parse().unwrap());
+                }
+                let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+                let body = body.collect().await.unwrap().to_bytes();
+                (parts.status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
 
-        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        for (component, revision) in [
+            (test_programs_artifacts::API_PROXY_COMPONENT, "1"),
+            (test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "2"),
+            (test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "2"),
+        ] {
+            let req = make_put_component_request(component, "hello").await;
+            let res = handle_request(&environment, req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("Component-Version").unwrap(), revision);
+        }
 
-        let req = make_invoke_component_request("test.hello", "GET", "").await;
-        let res = handle_request(&environment, req).await;
-        let (parts, body) = res.unwrap().into_parts();
+        assert_eq!(invoke(None).await, (StatusCode::OK, "Hello World!".to_string()));
+        assert_eq!(invoke(Some("1")).await, (StatusCode::OK, "hello, world!".to_string()));
+        assert_eq!(invoke(Some("3")).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(invoke(Some("latest")).await.0, StatusCode::BAD_REQUEST);
 
-        let body = body.collect().await.unwrap();
-        let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+        let rollback = |version: &'static str| {
+            let environment = environment.clone();
+            async move {
+                let req = hyper::Request::builder()
+                    .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+                    .header("Platform-Command", "Rollback-Component")
+                    .header("Component-Id", "hello")
+                    .header("Component-Version", version)
+                    .body(BoxBody::new(http_body_util::Empty::<Bytes>::new().map_err(|never| match never {})))
+                    .unwrap();
+                handle_request(&environment, req).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(rollback("9").await, StatusCode::NOT_FOUND);
+        let req = hyper::Request::builder()
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Rollback-Component")
+            .header("Component-Version", "1")
+            .body(BoxBody::new(http_body_util::Empty::<Bytes>::new().map_err(|never| match never {})))
+            .unwrap();
+        assert!(handle_request(&environment, req).await.err().unwrap().to_string().contains("missing Component-Id header"));
+        assert_eq!(rollback("1").await, StatusCode::OK);
+        assert_eq!(invoke(None).await, (StatusCode::OK, "hello, world!".to_string()));
+
+        // a pinned revision whose blob is gone is an error of ours, not a missing revision
+        let revisions = environment.list_component_revisions("test".to_string(), "hello".to_string()).await.unwrap();
+        environment.remove_dir(format!("blobs/{}", revisions[1].hash)).await.unwrap();
+        assert_eq!(invoke(Some("2")).await.0, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // concurrent uploads each get a revision of their own
+        let echo = std::fs::read(test_programs_artifacts::API_PROXY_ECHO_COMPONENT).unwrap();
+        let binary = std::fs::read(test_programs_artifacts::API_RAIKIRI_BINARY_COMPONENT).unwrap();
+        let (first, second) = tokio::join!(
+            environment.add_component("test".to_string(), "hello".to_string(), echo),
+            environment.add_component("test".to_string(), "hello".to_string(), binary),
+        );
+        let mut added = vec![first.unwrap(), second.unwrap()];
+        added.sort();
+        assert_eq!(added, vec![3, 4]);
+
+        // a rollback during an upload is done before or after it, the registry serves the revision left current
+        let hello = std::fs::read(test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT).unwrap();
+        let (rolled_back, uploaded) = tokio::join!(
+            environment.set_current_revision("test".to_string(), "hello".to_string(), 1),
+            environment.add_component("test".to_string(), "hello".to_string(), hello),
+        );
+        rolled_back.unwrap();
+        assert_eq!(uploaded.unwrap(), 5);
+        let current = environment.get_current_revision("test".to_string(), "hello".to_string()).await.unwrap();
+        let registered = environment.component_registry.get_entry(&"test.hello".to_string()).await.unwrap();
+        assert_eq!(Some(registered.read().await.revision.revision), current);
+
+        // a component that can't be read is listed with its error, after the others
+        environment.create_dir("components/test.broken").await.unwrap();
+        environment.write_file("components/test.broken/current", b"7".to_vec()).await.unwrap();
+        let components = environment.list_components().await.unwrap();
+        assert_eq!(components.first().unwrap().name, "hello");
+        assert_eq!(components.last().unwrap().name, "broken");
+        assert!(components.last().unwrap().error.as_ref().unwrap().contains("has no revision 7"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_api_key_authentication() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let command = |command: &'static str, key: Option<String>, headers: Vec<(&'static str, String)>| {
+            let environment = environment.clone();
+            async move {
+                let mut req = hyper::Request::builder().header("Platform-Command", command);
+                if let Some(key) = key { req = req.header("Authorization", format!("Bearer {key}")) }
+                for (name, value) in headers { req = req.header(name, value) }
+                let req = req.body(BoxBody::new(http_body_util::Empty::<Bytes>::new().map_err(|never| match never {}))).unwrap();
+                let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+                (parts.status, body.collect().await.unwrap().to_bytes().to_vec())
+            }
+        };
+
+        assert_eq!(command("List-Components", None, vec![]).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(command("List-Components", Some("rk_unknown".to_string()), vec![]).await.0, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(command("Create-Tenant", Some(TEST_API_KEY.to_string()), vec![("Tenant-Id", "acme".to_string())]).await.0, StatusCode::OK);
+        let (status, body) = command("Create-Api-Key", Some(TEST_API_KEY.to_string()), vec![("Tenant-Id", "acme".to_string()), ("Api-Key-Scope", "deploy".to_string())]).await;
+        assert_eq!(status, StatusCode::OK);
+        let acme_key = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["key"].as_str().unwrap().to_string();
+
+        // deploy keys can't run admin commands
+        assert_eq!(command("Create-Tenant", Some(acme_key.clone()), vec![("Tenant-Id", "other".to_string())]).await.0, StatusCode::FORBIDDEN);
+
+        // components are deployed under the tenant of the key
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY_COMPONENT, "hello").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+        let mut req = make_put_component_request(test_programs_artifacts::API_PROXY_COMPONENT, "hello").await;
+        req.headers_mut().insert("Authorization", format!("Bearer {acme_key}").parse().unwrap());
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+        assert!(environment.component_exists("acme".to_string(), "hello".to_string()).await);
+
+        let (_, body) = command("List-Components", Some(acme_key.clone()), vec![]).await;
+        let owners = serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap().iter().map(|component| component["owner"].clone()).collect::<Vec<_>>();
+        assert_eq!(owners, vec!["acme"]);
+
+        // tenants can invoke each other's public components only
+        assert_eq!(command("Invoke-Component", Some(acme_key.clone()), vec![("Component-Id", "test.hello".to_string()), ("Host", "localhost".to_string())]).await.0, StatusCode::OK);
+        assert_eq!(command("Invoke-Component", Some(acme_key.clone()), vec![("Component-Id", "acme.hello".to_string()), ("Host", "localhost".to_string())]).await.0, StatusCode::OK);
+        environment.update_component_access("test".to_string(), "hello".to_string(), b"visibility: internal".to_vec()).await.unwrap();
+        environment.update_component_access("acme".to_string(), "hello".to_string(), b"visibility: owner".to_vec()).await.unwrap();
+        assert_eq!(command("Invoke-Component", Some(acme_key.clone()), vec![("Component-Id", "test.hello".to_string()), ("Host", "localhost".to_string())]).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(command("Invoke-Component", Some(acme_key.clone()), vec![("Component-Id", "acme.hello".to_string()), ("Host", "localhost".to_string())]).await.0, StatusCode::OK);
+        assert_eq!(command("Invoke-Component", Some(TEST_API_KEY.to_string()), vec![("Component-Id", "acme.hello".to_string()), ("Host", "localhost".to_string())]).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(command("Invoke-Component", Some(TEST_API_KEY.to_string()), vec![("Component-Id", "test.hello".to_string()), ("Host", "localhost".to_string())]).await.0, StatusCode::OK);
+
+        // admin keys are limited to their own tenant
+        let (_, acme_admin) = environment.create_api_key("acme".to_string(), ApiKeyScope::Admin).await.unwrap();
+        environment.create_tenant("globex".to_string()).await.unwrap();
+        let (globex_key, _) = environment.create_api_key("globex".to_string(), ApiKeyScope::Deploy).await.unwrap();
+        let admin = Some(acme_admin.clone());
+        assert_eq!(command("Create-Tenant", admin.clone(), vec![("Tenant-Id", "other".to_string())]).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(command("Create-Api-Key", admin.clone(), vec![("Tenant-Id", "globex".to_string()), ("Api-Key-Scope", "deploy".to_string())]).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(command("Create-Api-Key", admin.clone(), vec![("Tenant-Id", "acme".to_string()), ("Api-Key-Scope", "platform".to_string())]).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(command("Create-Api-Key", admin.clone(), vec![("Tenant-Id", "acme".to_string()), ("Api-Key-Scope", "deploy".to_string())]).await.0, StatusCode::OK);
+        assert_eq!(command("Revoke-Api-Key", admin.clone(), vec![("Api-Key-Id", globex_key.id.clone())]).await.0, StatusCode::FORBIDDEN);
+        assert!(environment.get_api_key(&globex_key.id).await.unwrap().is_some());
+        let (status, body) = command("List-Api-Keys", admin.clone(), vec![]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap().iter().all(|api_key| api_key["tenant"] == "acme"));
+        let (_, body) = command("List-Components", admin.clone(), vec![]).await;
+        let owners = serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap().iter().map(|component| component["owner"].clone()).collect::<Vec<_>>();
+        assert_eq!(owners, vec!["acme"]);
+        let (_, body) = command("List-Components", Some(TEST_API_KEY.to_string()), vec![]).await;
+        assert_eq!(serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap().len(), 2);
+
+        let id = environment.authenticate(&acme_key).await.unwrap().unwrap().key_id;
+        assert_eq!(command("Revoke-Api-Key", Some(TEST_API_KEY.to_string()), vec![("Api-Key-Id", id)]).await.0, StatusCode::OK);
+        assert_eq!(command("List-Components", Some(acme_key), vec![]).await.0, StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_core_module() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        // built for wasm32-wasip1, the component is made on upload
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY, "hello").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+
+        let req = make_invoke_component_request("test.hello", "GET", "").await;
+        let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
 
         assert_eq!(parts.status, StatusCode::OK);
-        assert_eq!(body, "Hello World!");
+        assert_eq!(body, "hello, world!");
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_put_component_validation() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let put = |path: String| {
+            let environment = environment.clone();
+            async move {
+                let req = make_put_component_request(&path, "invalid").await;
+                let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+                let body = body.collect().await.unwrap().to_bytes();
+                (parts.status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (status, error) = put(test_programs_artifacts::API_MISSING_IMPORTS_COMPONENT.to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(error.contains("raikiri:missing/greeter"), "{error}");
+        assert!(error.contains("raikiri:missing/counter"), "{error}");
+
+        let (status, error) = put(test_programs_artifacts::API_COMMAND_COMPONENT.to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(error.contains("wasi:http/incoming-handler"), "{error}");
+
+        let not_wasm = format!("{}/not_wasm.wasm", environment.fs_root);
+        std::fs::write(&not_wasm, b"not wasm").unwrap();
+        let (status, error) = put(not_wasm).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error.starts_with("invalid component"), "{error}");
+
+        assert!(!environment.component_exists("test".to_string(), "invalid".to_string()).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_and_inspect_components() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        for component in [test_programs_artifacts::API_PROXY_COMPONENT, test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT] {
+            let req = make_put_component_request(component, "hello").await;
+            assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+        }
+
+        let command = |command: &'static str| {
+            let environment = environment.clone();
+            async move {
+                let req = hyper::Request::builder()
+                    .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+                    .header("Platform-Command", command)
+                    .header("Component-Id", "hello")
+                    .body(BoxBody::new(http_body_util::Empty::<Bytes>::new().map_err(|never| match never {})))
+                    .unwrap();
+                let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+                let body = body.collect().await.unwrap().to_bytes();
+                (parts.status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, list) = command("List-Components").await;
+        assert_eq!(status, StatusCode::OK);
+        let list = list.as_array().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0]["owner"], "test");
+        assert_eq!(list[0]["name"], "hello");
+        assert_eq!(list[0]["revision"], 2);
+        assert_eq!(list[0]["hash"].as_str().unwrap().len(), 64);
+        assert!(list[0]["size"].as_u64().unwrap() > 0);
+
+        let (status, inspection) = command("Inspect-Component").await;
+        assert_eq!(status, StatusCode::OK);
+        let req = hyper::Request::builder()
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Inspect-Component")
+            .body(BoxBody::new(http_body_util::Empty::<Bytes>::new().map_err(|never| match never {})))
+            .unwrap();
+        assert!(handle_request(&environment, req).await.err().unwrap().to_string().contains("missing Component-Id header"));
+        assert_eq!(inspection["revisions"], serde_json::json!([1, 2]));
+        assert!(inspection["exports"].as_array().unwrap().iter().any(|export| export.as_str().unwrap().starts_with("wasi:http/incoming-handler")));
+        assert!(inspection["imports"].as_array().unwrap().iter().any(|import| import.as_str().unwrap().starts_with("wasi:http/types")));
+        assert_eq!(inspection["wasmtime_version"], "31.0.0");
+        assert_eq!(inspection["has_secrets"], false);
+
+        environment.update_component_secrets("test".to_string(), "hello".to_string(), b"KEY: value".to_vec()).await.unwrap();
+        assert_eq!(command("Inspect-Component").await.1["has_secrets"], true);
+
+        environment.update_secret_group("test".to_string(), "db-prod".to_string(), b"KEY: shared\nDB_URL: postgres://prod".to_vec()).await.unwrap();
+        environment.attach_secret_group("test".to_string(), "hello".to_string(), "db-prod".to_string()).await.unwrap();
+        let inspection = command("Inspect-Component").await.1;
+        assert_eq!(inspection["secret_groups"], serde_json::json!(["test.db-prod"]));
+        assert_eq!(inspection["secrets"], serde_json::json!([
+            { "key": "KEY", "source": "component", "overrides": [{ "group": "test.db-prod" }] },
+            { "key": "DB_URL", "source": { "group": "test.db-prod" } },
+        ]));
+
+        environment.remove_component("test".to_string(), "hello".to_string()).await.unwrap();
+        assert_eq!(command("Inspect-Component").await.0, StatusCode::NOT_FOUND);
+        assert!(!environment.component_has_secrets("test".to_string(), "hello".to_string()).await);
+        assert!(environment.get_attached_secret_groups("test".to_string(), "hello".to_string()).await.unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_component_secrets_commands() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let (_, deploy_key) = environment.create_api_key("test".to_string(), ApiKeyScope::Deploy).await.unwrap();
+
+        let command = |command: &'static str, key: String, headers: Vec<(&'static str, &'static str)>, body: &'static str| {
+            let environment = environment.clone();
+            async move {
+                let mut req = hyper::Request::builder()
+                    .header("Platform-Command", command)
+                    .header("Authorization", format!("Bearer {key}"))
+                    .header("Component-Id", "hello");
+                for (name, value) in headers { req = req.header(name, value) }
+                let req = req.body(BoxBody::new(http_body_util::Full::new(Bytes::from(body)).map_err(|never| match never {}))).unwrap();
+                let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+                (parts.status, serde_json::from_slice::<serde_json::Value>(&body.collect().await.unwrap().to_bytes()).unwrap_or_default())
+            }
+        };
+
+        assert_eq!(command("Set-Component-Secrets", deploy_key.clone(), vec![], "A: one\nB: two").await.0, StatusCode::OK);
+        assert_eq!(command("Set-Component-Secrets", deploy_key.clone(), vec![], "- not a mapping").await.0, StatusCode::BAD_REQUEST);
+
+        let (status, secrets) = command("List-Component-Secrets", deploy_key.clone(), vec![], "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(secrets, serde_json::json!([{ "key": "A", "size": 3 }, { "key": "B", "size": 3 }]));
+
+        // only admin keys see values
+        let show_values = vec![("Show-Secret-Values", "true")];
+        assert_eq!(command("List-Component-Secrets", deploy_key.clone(), show_values.clone(), "").await.0, StatusCode::FORBIDDEN);
+        let (_, secrets) = command("List-Component-Secrets", TEST_API_KEY.to_string(), show_values, "").await;
+        assert_eq!(secrets[0]["value"], "one");
+
+        let (_, removed) = command("Unset-Component-Secrets", deploy_key.clone(), vec![("Secret-Keys", "A, MISSING")], "").await;
+        assert_eq!(removed, serde_json::json!(["A"]));
+        assert_eq!(environment.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(), vec![("B".to_string(), "two".to_string())]);
+
+        assert_eq!(command("Delete-Component-Secrets", deploy_key.clone(), vec![], "").await.0, StatusCode::OK);
+        assert!(!environment.component_has_secrets("test".to_string(), "hello".to_string()).await);
+
+        let group = vec![("Secret-Group-Id", "db-prod")];
+        assert_eq!(command("Update-Secret-Group", deploy_key.clone(), group.clone(), "DB_URL: postgres://prod").await.0, StatusCode::OK);
+        assert_eq!(command("List-Secret-Groups", deploy_key.clone(), vec![], "").await.1, serde_json::json!(["test.db-prod"]));
+        assert_eq!(command("Attach-Secret-Group", deploy_key.clone(), vec![("Secret-Group-Id", "acme.db-prod")], "").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(command("Attach-Secret-Group", deploy_key.clone(), group.clone(), "").await.0, StatusCode::OK);
+        assert_eq!(environment.get_component_secrets("test".to_string(), "hello".to_string()).await.unwrap(), vec![("DB_URL".to_string(), "postgres://prod".to_string())]);
+        assert_eq!(command("Delete-Secret-Group", deploy_key.clone(), group.clone(), "").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(command("Detach-Secret-Group", deploy_key.clone(), group.clone(), "").await.0, StatusCode::OK);
+        assert_eq!(command("Delete-Secret-Group", deploy_key, group, "").await.0, StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hot_reload() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let _watcher = environment.watch_components().unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY_ECHO_COMPONENT, "app").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+
+        let (mut chunks, receiver) = futures::channel::mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(1);
+        let req = http::Request::builder()
+            .uri("https://localhost:8080")
+            .method("POST")
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Invoke-Component")
+            .header("Component-Id", "test.app")
+            .body(StreamBody::new(receiver))
+            .unwrap();
+        let mut in_flight = handle_request(&environment, req).await.unwrap().into_body();
+        chunks.try_send(std::result::Result::Ok(Frame::data(Bytes::from("hello")))).unwrap();
+        assert_eq!(in_flight.frame().await.unwrap().unwrap().into_data().unwrap(), "hello");
+
+        let invoke = || async {
+            let req = make_invoke_component_request("test.app", "POST", "ping").await;
+            let (parts, body) = handle_request(&environment, req).await.unwrap().into_parts();
+            let body = body.collect().await.unwrap().to_bytes();
+            (parts.status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        // new requests see the update right away, the one in flight finishes on the echo component
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "app").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(invoke().await, (StatusCode::OK, "Hello World!".to_string()));
+
+        chunks.try_send(std::result::Result::Ok(Frame::data(Bytes::from(", world!")))).unwrap();
+        assert_eq!(in_flight.frame().await.unwrap().unwrap().into_data().unwrap(), ", world!");
+        drop(chunks);
+        assert!(in_flight.frame().await.is_none());
+
+        // changes made on disk by another process are picked up by the watcher
+        let other = RaikiriEnvironment::new()
+            .with_username("test".to_string())
+            .with_fs_root(environment.fs_root.clone());
+        let eventually = |expected: (StatusCode, String)| async move {
+            for _ in 0..100 {
+                if invoke().await == expected { return true }
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            false
+        };
+        other.set_current_revision("test".to_string(), "app".to_string(), 1).await.unwrap();
+        assert!(eventually((StatusCode::OK, "ping".to_string())).await);
+
+        // as are changes to its access
+        assert!(environment.check_component_access("test.app", None).await.is_ok());
+        other.update_component_access("test".to_string(), "app".to_string(), b"visibility: internal".to_vec()).await.unwrap();
+        let mut denied = false;
+        for _ in 0..100 {
+            if environment.check_component_access("test.app", None).await.is_err() { denied = true; break }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(denied);
+        other.remove_component("test".to_string(), "app".to_string()).await.unwrap();
+        assert!(eventually((StatusCode::NOT_FOUND, "Component test.app not found".to_string())).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invoke_hello() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "hello").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+        let req = make_invoke_component_request("test.hello", "GET", "").await;
+        let res = handle_request(&environment, req).await;
+        let (parts, body) = res.unwrap().into_parts();
+
+        let body = body.collect().await.unwrap();
+        let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, "Hello World!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invoke_routed() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env()
+            .with_router(Router {
+                routes: vec![Route::new("/hello/*", "test.hello".to_string(), &["GET".to_string()], None).unwrap()]
+            });
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "hello").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+        let req = http::Request::builder()
+            .uri("http://localhost:8080/hello/world?name=raikiri")
+            .method("GET")
+            .body(RaikiriEnvironment::response_body("").await)
+            .unwrap();
+        let res = handle_request(&environment, req).await;
+        let (parts, body) = res.unwrap().into_parts();
+
+        let body = body.collect().await.unwrap();
+        let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body, "Hello World!");
+
+        let req = http::Request::builder()
+            .uri("http://localhost:8080/goodbye")
+            .method("GET")
+            .body(RaikiriEnvironment::response_body("").await)
+            .unwrap();
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invoke_api_gateway() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_HELLO_COMPONENT, "hello").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+        let req = make_put_api_gateway_request(1, "
+cors:
+  allow_origins: ['*']
+routes:
+  - path: /v1/hello
+    methods: [GET]
+    component: test.hello
+    response_headers:
+      set:
+        X-Api-Version: v1
+").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+        let req = http::Request::builder()
+            .uri("http://localhost:8080/v1/hello")
+            .method("GET")
+            .header("Origin", "https://app.example.com")
+            .body(RaikiriEnvironment::response_body("").await)
+            .unwrap();
+        let res = handle_request(&environment, req).await;
+        let (parts, body) = res.unwrap().into_parts();
+
+        let body = body.collect().await.unwrap();
+        let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers.get("x-api-version").unwrap(), "v1");
+        assert_eq!(parts.headers.get("api-gateway-version").unwrap(), "1");
+        assert_eq!(parts.headers.get("access-control-allow-origin").unwrap(), "*");
+        assert_eq!(body, "Hello World!");
+
+        let req = make_put_api_gateway_request(2, "
+routes:
+  - path: /v2/hello
+    component: test.hello
+").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+        let req = http::Request::builder()
+            .uri("http://localhost:8080/v1/hello")
+            .method("GET")
+            .body(RaikiriEnvironment::response_body("").await)
+            .unwrap();
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::NOT_FOUND);
+
+        let req = make_put_api_gateway_request(3, "routes: oops").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bind_address() {
+        assert_eq!("0.0.0.0".parse::<BindAddress>().unwrap(), BindAddress::Tcp([0, 0, 0, 0].into()));
+        assert_eq!("[::]".parse::<BindAddress>().unwrap(), BindAddress::Tcp(std::net::Ipv6Addr::UNSPECIFIED.into()));
+        assert_eq!("unix:/tmp/raikiri.sock".parse::<BindAddress>().unwrap(), BindAddress::Unix("/tmp/raikiri.sock".to_string()));
+        assert!("unix:".parse::<BindAddress>().is_err());
+        assert!("localhost:8080".parse::<BindAddress>().is_err());
+    }
+
+    async fn connect_unix_stream(socket_path: &str) -> tokio::net::UnixStream {
+        for _ in 0..50 {
+            if let std::result::Result::Ok(stream) = tokio::net::UnixStream::connect(socket_path).await {
+                return stream
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("server did not start listening on {socket_path}")
+    }
+
+    async fn connect_unix(socket_path: &str) -> hyper::client::conn::http1::SendRequest<BoxBody<Bytes, hyper::Error>> {
+        let stream = connect_unix_stream(socket_path).await;
+        let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+        sender
+    }
+
+    async fn connect_tls(socket_path: &str, tls: &TlsConf, alpn: &[u8]) -> tokio_openssl::SslStream<tokio::net::UnixStream> {
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_ca_file(&tls.cert_path).unwrap();
+        connector.set_alpn_protos(alpn).unwrap();
+        let ssl = connector.build().configure().unwrap().into_ssl("localhost").unwrap();
+        let mut stream = tokio_openssl::SslStream::new(ssl, connect_unix_stream(socket_path).await).unwrap();
+        std::pin::Pin::new(&mut stream).connect().await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_run_server_unix_socket() -> Result<()> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let socket_path = format!("{}/raikiri.sock", environment.fs_root);
+        let environment = environment.with_bind_address(BindAddress::Unix(socket_path.clone()));
+
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let server = environment.clone();
+        let server = tokio::spawn(async move {
+            server.run_server_until(async { _ = shutdown_receiver.await; }).await.unwrap()
+        });
+
+        let mut sender = connect_unix(&socket_path).await;
+        let req = make_invoke_component_request("test.hello404", "GET", "").await;
+        let res = sender.send_request(req).await?;
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        shutdown_sender.send(()).unwrap();
+        server.await?;
+
+        assert!(tokio::net::UnixStream::connect(&socket_path).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_drains_in_flight_requests() -> Result<()> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let socket_path = format!("{}/raikiri.sock", environment.fs_root);
+        let environment = environment.with_bind_address(BindAddress::Unix(socket_path.clone()));
+
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let server = environment.clone();
+        let server = tokio::spawn(async move {
+            server.run_server_until(async { _ = shutdown_receiver.await; }).await.unwrap()
+        });
+
+        // the request body is streamed slowly so the request is still in flight at shutdown
+        let component = tokio::fs::read(test_programs_artifacts::API_PROXY_COMPONENT).await?;
+        let (chunk_sender, chunk_receiver) = tokio::sync::mpsc::channel::<Bytes>(1);
+        let body = StreamBody::new(futures::stream::unfold(chunk_receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (std::result::Result::Ok::<_, hyper::Error>(Frame::data(chunk)), receiver))
+        }));
+        let req = http::Request::builder()
+            .uri("/")
+            .method("POST")
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Put-Component")
+            .header("Component-Id", "hello")
+            .body(BoxBody::new(body))
+            .unwrap();
+
+        let mut sender = connect_unix(&socket_path).await;
+        let res = tokio::spawn(async move { sender.send_request(req).await });
+
+        let (first, rest) = component.split_at(component.len() / 2);
+        chunk_sender.send(Bytes::copy_from_slice(first)).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        shutdown_sender.send(()).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!server.is_finished());
+
+        chunk_sender.send(Bytes::copy_from_slice(rest)).await?;
+        drop(chunk_sender);
+
+        assert_eq!(res.await??.status(), StatusCode::OK);
+        server.await?;
+
+        Ok(())
+    }
+
+    static SHUTDOWN_EVENTS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+    fn record_shutdown_events(event: ComponentEvent) {
+        if let ComponentEvent::AccessDenied { username_component_name, .. } = event {
+            SHUTDOWN_EVENTS.lock().unwrap().push(username_component_name);
+        }
+    }
+
+    // init starts the event loop, which must not keep shutdown from flushing the events
+    #[tokio::test]
+    async fn test_shutdown_after_init() -> Result<()> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        environment.with_event_handler(record_shutdown_events);
+        environment.init().await.unwrap();
+        let socket_path = format!("{}/raikiri.sock", environment.fs_root);
+        let environment = environment.with_bind_address(BindAddress::Unix(socket_path));
+
+        for name in ["test.first", "test.second"] {
+            environment.event_sender.send(ComponentEvent::AccessDenied {
+                username_component_name: name.to_string(),
+                caller: None,
+                reason: String::new(),
+            }).await.unwrap();
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(5), environment.run_server_until(std::future::ready(()))).await
+            .expect("shutdown did not finish")
+            .unwrap();
+        assert_eq!(*SHUTDOWN_EVENTS.lock().unwrap(), vec!["test.first", "test.second"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_server_h2c() -> Result<()> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let socket_path = format!("{}/raikiri.sock", environment.fs_root);
+        let environment = environment.with_bind_address(BindAddress::Unix(socket_path.clone()));
+
+        let server = environment.clone();
+        tokio::spawn(async move { server.run_server_until(std::future::pending()).await.unwrap() });
+
+        let stream = connect_unix_stream(&socket_path).await;
+        let (mut sender, connection) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+
+        let req = make_invoke_component_request("test.hello404", "GET", "").await;
+        let res = sender.send_request(req).await?;
+
+        assert_eq!(res.version(), http::Version::HTTP_2);
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_server_tls() -> Result<()> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let socket_path = format!("{}/raikiri.sock", environment.fs_root);
+        let tls = create_test_certificate(&environment.fs_root);
+        let environment = environment
+            .with_bind_address(BindAddress::Unix(socket_path.clone()))
+            .with_tls(Some(tls.clone()));
+
+        let server = environment.clone();
+        tokio::spawn(async move { server.run_server_until(std::future::pending()).await.unwrap() });
+
+        let stream = connect_tls(&socket_path, &tls, b"\x02h2\x08http/1.1").await;
+        assert_eq!(stream.ssl().selected_alpn_protocol(), Some(b"h2".as_slice()));
+        let (mut sender, connection) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+
+        let req = make_invoke_component_request("test.hello404", "GET", "").await;
+        let res = sender.send_request(req).await?;
+
+        assert_eq!(res.version(), http::Version::HTTP_2);
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let stream = connect_tls(&socket_path, &tls, b"\x08http/1.1").await;
+        assert_eq!(stream.ssl().selected_alpn_protocol(), Some(b"http/1.1".as_slice()));
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+
+        let req = make_invoke_component_request("test.hello404", "GET", "").await;
+        let res = sender.send_request(req).await?;
+
+        assert_eq!(res.version(), http::Version::HTTP_11);
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_timeout() -> Result<()> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        let socket_path = format!("{}/raikiri.sock", environment.fs_root);
+        let tls = create_test_certificate(&environment.fs_root).with_handshake_timeout(std::time::Duration::from_millis(200));
+        let environment = environment
+            .with_bind_address(BindAddress::Unix(socket_path.clone()))
+            .with_tls(Some(tls));
+
+        let server = environment.clone();
+        tokio::spawn(async move { server.run_server_until(std::future::pending()).await.unwrap() });
+
+        // a client that never starts the handshake is disconnected
+        let mut stream = connect_unix_stream(&socket_path).await;
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), tokio::io::AsyncReadExt::read(&mut stream, &mut buf)).await;
+        assert!(matches!(read, std::result::Result::Ok(std::result::Result::Ok(0))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invoke_preserves_headers_and_binary_body() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_BINARY_COMPONENT, "binary").await;
+        let res = handle_request(&environment, req).await;
+
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+
+        let req = make_invoke_component_request("test.binary", "GET", "").await;
+        let res = handle_request(&environment, req).await;
+        let (parts, body) = res.unwrap().into_parts();
+
+        let body = body.collect().await.unwrap().to_bytes();
+
+        assert_eq!(parts.status, StatusCode::CREATED);
+        assert_eq!(parts.headers.get("content-type").unwrap(), "application/octet-stream");
+        assert_eq!(parts.headers.get("set-cookie").unwrap(), "session=raikiri");
+        assert_eq!(parts.headers.get("location").unwrap(), "/files/1");
+        assert_eq!(body.to_vec(), vec![0x00, 0xff, 0xfe, 0x80, 0x7f]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invoke_component_from_component_preserves_headers_and_binary_body() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_BINARY_COMPONENT, "binary").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+        let req = make_put_component_request(test_programs_artifacts::API_RAIKIRI_RELAY_COMPONENT, "relay").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+
+        let req = make_invoke_component_request("test.relay", "GET", "").await;
+        let res = handle_request(&environment, req).await;
+        let (parts, body) = res.unwrap().into_parts();
+
+        let body = body.collect().await.unwrap().to_bytes();
+
+        assert_eq!(parts.status, StatusCode::CREATED);
+        assert_eq!(parts.headers.get("content-type").unwrap(), "application/octet-stream");
+        assert_eq!(parts.headers.get("set-cookie").unwrap(), "session=raikiri");
+        assert_eq!(body.to_vec(), vec![0x00, 0xff, 0xfe, 0x80, 0x7f]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invoke_streams_request_and_response_bodies() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY_ECHO_COMPONENT, "echo").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+
+        let (mut chunks, receiver) = futures::channel::mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(1);
+        let req = http::Request::builder()
+            .uri("https://localhost:8080")
+            .method("POST")
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Invoke-Component")
+            .header("Component-Id", "test.echo")
+            .body(StreamBody::new(receiver))
+            .unwrap();
+
+        // the response starts before the request body is complete, and each chunk
+        // comes back before the next one is sent
+        let mut body = handle_request(&environment, req).await.unwrap().into_body();
+        for chunk in ["hello", ", ", "world!"] {
+            chunks.try_send(std::result::Result::Ok(Frame::data(Bytes::from(chunk)))).unwrap();
+            let frame = body.frame().await.unwrap().unwrap();
+            assert_eq!(frame.into_data().unwrap(), chunk);
+        }
+        drop(chunks);
+        assert!(body.frame().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_body_sizes() -> Result<(), wasmtime::Error> {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY_ECHO_COMPONENT, "echo").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+
+        let limited = environment.clone().with_body_limits(BodyLimits::new(Some(8), Some(4)));
+
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY_ECHO_COMPONENT, "echo").await;
+        assert_eq!(handle_request(&limited, req).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let mut req = make_invoke_component_request("test.echo", "POST", "hello, world!").await;
+        req.headers_mut().insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(13));
+        assert_eq!(handle_request(&limited, req).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = make_invoke_component_request("test.echo", "POST", "hello").await;
+        let res = handle_request(&limited, req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await;
+        assert!(matches!(body, Err(ErrorCode::HttpResponseBodySize(Some(4)))));
+
+        Ok(())
+    }
+
+    static LIMIT_EVENTS: std::sync::Mutex<Vec<(String, ResourceLimit)>> = std::sync::Mutex::new(Vec::new());
+
+    fn record_limit_events(event: ComponentEvent) {
+        if let ComponentEvent::LimitExceeded { username_component_name, limit, .. } = event {
+            LIMIT_EVENTS.lock().unwrap().push((username_component_name, limit));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_component_limits() -> Result<(), wasmtime::Error> {
+
+        let mut environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+        environment.with_event_handler(record_limit_events);
+
+        let req = make_put_component_request(test_programs_artifacts::API_PROXY_HOG_COMPONENT, "hog").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+
+        let limits = ComponentLimits {
+            memory_size: Some(16 << 20),
+            cpu_time: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let environment = environment.with_limits(LimitsConf {
+            default: ComponentLimits::default(),
+            components: [("test.hog".to_string(), limits)].into(),
+        });
+
+        let invoke = |path: &'static str| {
+            let environment = environment.clone();
+            async move {
+                let mut req = make_invoke_component_request("test.hog", "GET", "").await;
+                *req.uri_mut() = format!("https://localhost:8080{path}").parse().unwrap();
+                handle_request(&environment, req).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(invoke("/").await, StatusCode::OK);
+        assert_eq!(invoke("/spin").await, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(invoke("/alloc").await, StatusCode::INSUFFICIENT_STORAGE);
+
+        environment.flush_events().await;
+        let events = LIMIT_EVENTS.lock().unwrap().clone();
+        assert!(events.contains(&("test.hog".to_string(), ResourceLimit::CpuTime)));
+        assert!(events.contains(&("test.hog".to_string(), ResourceLimit::Memory)));
+
+        Ok(())
+    }
+
+    static ACCESS_EVENTS: std::sync::Mutex<Vec<(String, Option<String>)>> = std::sync::Mutex::new(Vec::new());
+
+    fn record_access_events(event: ComponentEvent) {
+        if let ComponentEvent::AccessDenied { username_component_name, caller, .. } = event {
+            ACCESS_EVENTS.lock().unwrap().push((username_component_name, caller));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_component_access() -> Result<(), wasmtime::Error> {
+
+        let mut environment = create_test_env()
+            .with_router(Router {
+                routes: vec![Route::new("/binary/*", "test.binary".to_string(), &["GET".to_string()], None).unwrap()]
+            });
+        environment.setup_fs().await.unwrap();
+        environment.with_event_handler(record_access_events);
+
+        environment.create_tenant("acme".to_string()).await.unwrap();
+        let (_, acme_key) = environment.create_api_key("acme".to_string(), ApiKeyScope::Deploy).await.unwrap();
+
+        for (component, name, key) in [
+            (test_programs_artifacts::API_RAIKIRI_BINARY_COMPONENT, "binary", TEST_API_KEY.to_string()),
+            (test_programs_artifacts::API_RAIKIRI_RELAY_COMPONENT, "relay", TEST_API_KEY.to_string()),
+            (test_programs_artifacts::API_RAIKIRI_RELAY_COMPONENT, "relay", acme_key.clone()),
+        ] {
+            let mut req = make_put_component_request(component, name).await;
+            req.headers_mut().insert("Authorization", format!("Bearer {key}").parse().unwrap());
+            assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::OK);
+        }
+
+        let update_access = |access: &'static str| {
+            let environment = environment.clone();
+            async move {
+                let req = hyper::Request::builder()
+                    .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+                    .header("Platform-Command", "Update-Component-Access")
+                    .header("Component-Id", "binary")
+                    .body(RaikiriEnvironment::response_body(access).await)
+                    .unwrap();
+                handle_request(&environment, req).await.unwrap().status()
+            }
+        };
+        let routed = || {
+            let environment = environment.clone();
+            async move {
+                let req = http::Request::builder()
+                    .uri("http://localhost:8080/binary/")
+                    .body(RaikiriEnvironment::response_body("").await)
+                    .unwrap();
+                handle_request(&environment, req).await.unwrap().status()
+            }
+        };
+        // the relays answer with the status of test.binary
+        let relay = |key: String, relay: &'static str| {
+            let environment = environment.clone();
+            async move {
+                let mut req = make_invoke_component_request(relay, "GET", "").await;
+                req.headers_mut().insert("Authorization", format!("Bearer {key}").parse().unwrap());
+                handle_request(&environment, req).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(routed().await, StatusCode::CREATED);
+        assert_eq!(relay(acme_key.clone(), "acme.relay").await, StatusCode::CREATED);
+
+        assert_eq!(update_access("visibility: public\nallowed_callers: [relay]").await, StatusCode::BAD_REQUEST);
+        let req = hyper::Request::builder()
+            .header("Authorization", format!("Bearer {TEST_API_KEY}"))
+            .header("Platform-Command", "Update-Component-Access")
+            .body(RaikiriEnvironment::response_body("visibility: owner").await)
+            .unwrap();
+        assert!(handle_request(&environment, req).await.err().unwrap().to_string().contains("missing Component-Id header"));
+        assert_eq!(update_access("visibility: owner\nallowed_callers: [test.relay, acme.*]").await, StatusCode::OK);
+        assert_eq!(routed().await, StatusCode::FORBIDDEN);
+        assert_eq!(relay(TEST_API_KEY.to_string(), "test.relay").await, StatusCode::CREATED);
+        assert_eq!(relay(acme_key.clone(), "acme.relay").await, StatusCode::FORBIDDEN);
+
+        assert_eq!(update_access("visibility: internal\nallowed_callers: [acme.*]").await, StatusCode::OK);
+        assert_eq!(routed().await, StatusCode::FORBIDDEN);
+        assert_eq!(relay(TEST_API_KEY.to_string(), "test.relay").await, StatusCode::FORBIDDEN);
+        assert_eq!(relay(acme_key.clone(), "acme.relay").await, StatusCode::CREATED);
+
+        // acme.relay -> test.relay -> test.binary, test.binary is called by test.relay and not by acme.relay
+        let nested = || {
+            let environment = environment.clone();
+            let acme_key = acme_key.clone();
+            async move {
+                let mut req = make_invoke_component_request("acme.relay", "GET", "").await;
+                req.headers_mut().insert("Authorization", format!("Bearer {acme_key}").parse().unwrap());
+                req.headers_mut().insert("Relay-To", "test.relay".parse().unwrap());
+                handle_request(&environment, req).await.unwrap().status()
+            }
+        };
+        assert_eq!(nested().await, StatusCode::FORBIDDEN);
+        assert_eq!(update_access("visibility: internal\nallowed_callers: [test.relay]").await, StatusCode::OK);
+        assert_eq!(nested().await, StatusCode::CREATED);
+        assert_eq!(relay(acme_key.clone(), "acme.relay").await, StatusCode::FORBIDDEN);
+
+        // the owner can still run it
+        let req = make_invoke_component_request("test.binary", "GET", "").await;
+        assert_eq!(handle_request(&environment, req).await.unwrap().status(), StatusCode::CREATED);
+
+        let inspection = environment.inspect_component("test".to_string(), "binary".to_string()).await.unwrap();
+        assert_eq!(inspection.access.visibility, Visibility::Internal);
+
+        environment.flush_events().await;
+        let events = ACCESS_EVENTS.lock().unwrap().clone();
+        assert!(events.contains(&("test.binary".to_string(), None)));
+        assert!(events.contains(&("test.binary".to_string(), Some("acme.relay".to_string()))));
+        assert!(events.contains(&("test.binary".to_string(), Some("test.relay".to_string()))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use std::{fmt::Display, str::FromStr};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_blobs::sha256_hex, raikiri_env_fs::RaikiriEnvironmentFS};
+
+// What an api key allows. Deploy keys manage and invoke the components of their tenant, admin
+// keys can also manage the api keys of their tenant. Platform keys act on every tenant: they
+// create tenants, manage the keys and list the components of any of them, and change the api gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    Platform,
+    Admin,
+    Deploy,
+}
+
+impl Display for ApiKeyScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiKeyScope::Platform => write!(f, "platform"),
+            ApiKeyScope::Admin => write!(f, "admin"),
+            ApiKeyScope::Deploy => write!(f, "deploy"),
+        }
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = ThreadSafeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "platform" => Ok(ApiKeyScope::Platform),
+            "admin" => Ok(ApiKeyScope::Admin),
+            "deploy" => Ok(ApiKeyScope::Deploy),
+            _ => Err(format!("unknown api key scope {s}, expected platform, admin or deploy").into())
+        }
+    }
+}
+
+impl ApiKeyScope {
+    fn rank(&self) -> u8 {
+        match self {
+            ApiKeyScope::Platform => 2,
+            ApiKeyScope::Admin => 1,
+            ApiKeyScope::Deploy => 0,
+        }
+    }
+
+    pub fn allows(&self, required: ApiKeyScope) -> bool {
+        self.rank() >= required.rank()
+    }
+}
+
+const API_KEY_ID_LEN: usize = 12;
+
+// An api key as it is stored. The key itself is only shown when it is created, keys are
+// found by their SHA-256 and listed or revoked by id, the first characters of the hash.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub tenant: String,
+    pub scope: ApiKeyScope,
+    pub created_at: String,
+}
+
+impl ApiKey {
+    pub fn new(key: &str, tenant: String, scope: ApiKeyScope) -> ApiKey {
+        ApiKey {
+            id: sha256_hex(key.as_bytes())[..API_KEY_ID_LEN].to_string(),
+            tenant,
+            scope,
+            created_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+pub fn api_key_path(key: &str) -> String {
+    format!("api_keys/{}", sha256_hex(key.as_bytes()))
+}
+
+// Who sent an admin command, the components it deploys and invokes are the ones of this tenant
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub tenant: String,
+    pub scope: ApiKeyScope,
+    pub key_id: String,
+}
+
+impl Principal {
+    // Only platform keys act on tenants other than their own
+    pub fn can_manage(&self, tenant: &str) -> bool {
+        self.scope == ApiKeyScope::Platform || self.tenant == tenant
+    }
+}
+
+// Tenants own the components deployed with their api keys, under the {tenant}.{component} names:
+//
+//     tenants/{tenant}
+//     api_keys/{sha256(key)}
+#[async_trait]
+pub trait RaikiriEnvironmentTenants {
+    async fn create_tenant(&self, tenant: String) -> Result<(), ThreadSafeError>;
+    async fn tenant_exists(&self, tenant: &str) -> bool;
+    async fn list_tenants(&self) -> Result<Vec<String>, ThreadSafeError>;
+    async fn create_api_key(&self, tenant: String, scope: ApiKeyScope) -> Result<(ApiKey, String), ThreadSafeError>;
+    async fn list_api_keys(&self, tenant: Option<String>) -> Result<Vec<ApiKey>, ThreadSafeError>;
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>, ThreadSafeError>;
+    async fn revoke_api_key(&self, id: String) -> Result<(), ThreadSafeError>;
+    async fn authenticate(&self, key: &str) -> Result<Option<Principal>, ThreadSafeError>;
+}
+
+// Tenant names become the first part of component names, so they can't hold dots
+fn validate_tenant_name(tenant: &str) -> Result<(), ThreadSafeError> {
+    let valid = !tenant.is_empty() && tenant.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid tenant name {tenant}, use letters, digits, - and _").into())
+    }
+}
+
+// The hash of the key with exactly this id. Ids are a prefix of the hash, so two keys could
+// share one, in which case neither is picked.
+async fn api_key_hash(environment: &RaikiriEnvironment, id: &str) -> Result<Option<String>, ThreadSafeError> {
+    let matching = environment.read_dir("api_keys").await?
+        .into_iter()
+        .filter(|hash| hash.get(..API_KEY_ID_LEN) == Some(id))
+        .collect::<Vec<_>>();
+    match matching.len() {
+        0 | 1 => Ok(matching.into_iter().next()),
+        _ => Err(format!("api key id {id} matches more than one key").into()),
+    }
+}
+
+#[async_trait]
+impl RaikiriEnvironmentTenants for RaikiriEnvironment {
+
+    async fn create_tenant(&self, tenant: String) -> Result<(), ThreadSafeError> {
+        validate_tenant_name(&tenant)?;
+        if self.tenant_exists(&tenant).await { return Err(format!("tenant {tenant} already exists").into()) }
+        self.write_file(format!("tenants/{tenant}"), chrono::Utc::now().to_rfc3339().into_bytes()).await
+    }
+
+    async fn tenant_exists(&self, tenant: &str) -> bool {
+        validate_tenant_name(tenant).is_ok() && self.file_exists(format!("tenants/{tenant}")).await
+    }
+
+    async fn list_tenants(&self) -> Result<Vec<String>, ThreadSafeError> {
+        let mut tenants = self.read_dir("tenants").await?;
+        tenants.sort();
+        Ok(tenants)
+    }
+
+    // Returns the stored key and the key itself, which can't be recovered afterwards
+    async fn create_api_key(&self, tenant: String, scope: ApiKeyScope) -> Result<(ApiKey, String), ThreadSafeError> {
+        if !self.tenant_exists(&tenant).await { return Err(format!("tenant {tenant} not found").into()) }
+        let mut random = [0; 32];
+        openssl::rand::rand_bytes(&mut random)?;
+        let key = format!("rk_{}", random.iter().map(|byte| format!("{byte:02x}")).collect::<String>());
+        let api_key = ApiKey::new(&key, tenant, scope);
+        self.write_file(api_key_path(&key), serde_json::to_vec(&api_key)?).await?;
+        Ok((api_key, key))
+    }
+
+    async fn list_api_keys(&self, tenant: Option<String>) -> Result<Vec<ApiKey>, ThreadSafeError> {
+        let mut api_keys = Vec::new();
+        for hash in self.read_dir("api_keys").await? {
+            let api_key: ApiKey = serde_json::from_slice(&self.read_file(format!("api_keys/{hash}")).await?)?;
+            if tenant.as_ref().is_none_or(|tenant| *tenant == api_key.tenant) {
+                api_keys.push(api_key);
+            }
+        }
+        api_keys.sort_by(|a, b| (&a.tenant, &a.created_at).cmp(&(&b.tenant, &b.created_at)));
+        Ok(api_keys)
+    }
+
+    async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>, ThreadSafeError> {
+        match api_key_hash(self, id).await? {
+            Some(hash) => Ok(Some(serde_json::from_slice(&self.read_file(format!("api_keys/{hash}")).await?)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn revoke_api_key(&self, id: String) -> Result<(), ThreadSafeError> {
+        let hash = api_key_hash(self, &id).await?.ok_or(format!("api key {id} not found"))?;
+        self.remove_file(format!("api_keys/{hash}")).await
+    }
+
+    async fn authenticate(&self, key: &str) -> Result<Option<Principal>, ThreadSafeError> {
+        let path = api_key_path(key);
+        if !self.file_exists(&path).await { return Ok(None) }
+        let api_key: ApiKey = serde_json::from_slice(&self.read_file(path).await?)?;
+        Ok(Some(Principal { tenant: api_key.tenant, scope: api_key.scope, key_id: api_key.id }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{raikiri_env_fs::RaikiriEnvironmentFS, tests::create_test_env};
+
+    use super::{ApiKey, ApiKeyScope, RaikiriEnvironmentTenants};
+
+    #[tokio::test]
+    async fn test_api_keys() {
+
+        let environment = create_test_env();
+        environment.setup_fs().await.unwrap();
+
+        assert!(environment.create_api_key("acme".to_string(), ApiKeyScope::Deploy).await.is_err());
+        assert!(environment.create_tenant("acme.corp".to_string()).await.is_err());
+        environment.create_tenant("acme".to_string()).await.unwrap();
+        assert!(environment.create_tenant("acme".to_string()).await.is_err());
+
+        let (api_key, key) = environment.create_api_key("acme".to_string(), ApiKeyScope::Deploy).await.unwrap();
+        let principal = environment.authenticate(&key).await.unwrap().unwrap();
+        assert_eq!((principal.tenant.as_str(), principal.scope), ("acme", ApiKeyScope::Deploy));
+        assert!(environment.authenticate("rk_unknown").await.unwrap().is_none());
+
+        // only the hash of the key is stored
+        for hash in environment.read_dir("api_keys").await.unwrap() {
+            let stored = environment.read_file(format!("api_keys/{hash}")).await.unwrap();
+            assert!(!String::from_utf8(stored).unwrap().contains(&key));
+            assert!(!hash.contains(&key));
+        }
+
+        assert_eq!(environment.list_api_keys(Some("acme".to_string())).await.unwrap(), vec![api_key.clone()]);
+        // ids are matched exactly, never by prefix
+        assert!(environment.revoke_api_key(api_key.id[..4].to_string()).await.is_err());
+        assert!(environment.revoke_api_key(String::new()).await.is_err());
+        assert!(environment.authenticate(&key).await.unwrap().is_some());
+        assert_eq!(environment.get_api_key(&api_key.id).await.unwrap(), Some(api_key.clone()));
+        environment.revoke_api_key(api_key.id).await.unwrap();
+        assert!(environment.authenticate(&key).await.unwrap().is_none());
+
+        // an id shared by two keys revokes neither
+        let stored = serde_json::to_vec(&ApiKey::new("rk_other", "acme".to_string(), ApiKeyScope::Deploy)).unwrap();
+        for hash in ["abcdef012345aaaa", "abcdef012345bbbb"] {
+            environment.write_file(format!("api_keys/{hash}"), stored.clone()).await.unwrap();
+        }
+        assert!(environment.revoke_api_key("abcdef012345".to_string()).await.is_err());
+        assert_eq!(environment.read_dir("api_keys").await.unwrap().iter().filter(|hash| hash.starts_with("abcdef012345")).count(), 2);
+    }
+}
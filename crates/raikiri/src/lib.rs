@@ -4,6 +4,7 @@ mod sdk;
 
 pub use adapters::cache::new_empty_cache;
 pub use adapters::component_imports::ComponentImports;
+pub use adapters::engine::EngineSettings;
 pub use adapters::context::RaikiriContext;
 pub use adapters::wasi_view::Wasi;
 pub use domain::raikiri_env;
@@ -1,8 +1,12 @@
-use adapters::{cache::new_empty_cache, component_imports::ComponentImports, wasi_view::Wasi};
-use clap::{Parser, Subcommand};
-use domain::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_component::RaikiriComponentStorage, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_invoke::RaikiriEnvironmentInvoke, raikiri_env_secrets::RaikiriEnvironmentSecrets, raikiri_env_server::RaikiriEnvironmentServer};
-use http_body_util::BodyExt;
+use std::time::Duration;
+
+use adapters::{body::BodyLimits, cache::new_empty_cache, engine::EngineSettings, component_imports::ComponentImports, tls::TlsConf, wasi_view::Wasi};
+use clap::{Args, Parser, Subcommand};
+use domain::{raikiri_env::{RaikiriEnvironment, ThreadSafeError}, raikiri_env_access::RaikiriEnvironmentAccess, raikiri_env_api_gateway::RaikiriEnvironmentApiGateway, raikiri_env_component::RaikiriComponentStorage, raikiri_env_fs::RaikiriEnvironmentFS, raikiri_env_invoke::RaikiriEnvironmentInvoke, raikiri_env_secret_groups::RaikiriEnvironmentSecretGroups, raikiri_env_secrets::{parse_secrets, RaikiriEnvironmentSecrets}, raikiri_env_server::{BindAddress, RaikiriEnvironmentServer}, raikiri_env_tenants::{ApiKeyScope, RaikiriEnvironmentTenants}};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::Bytes;
 use types::InvokeRequest;
+use wasmtime_wasi_http::hyper_request_error;
 
 mod adapters;
 mod types;
@@ -13,7 +17,41 @@ mod domain;
 #[command(name = "raikiri")]
 struct Cli {
     #[command(subcommand)]
-    command: Commands
+    command: Commands,
+    #[command(flatten)]
+    engine: EngineArgs
+}
+
+// Overrides the engine section of raikiri.yaml
+#[derive(Debug, Args)]
+struct EngineArgs {
+    /// Show wasm source locations in backtraces
+    #[arg(long, global = true)]
+    wasm_backtraces: Option<bool>,
+    /// Enable the wasm SIMD proposal
+    #[arg(long, global = true)]
+    simd: Option<bool>,
+    /// Cache compiled code on disk
+    #[arg(long, global = true)]
+    cache: Option<bool>,
+    /// Wasmtime cache config file, which sets the cache directory
+    #[arg(long, global = true)]
+    cache_config: Option<String>,
+    /// Reserve instance slots up front instead of allocating them per request
+    #[arg(long, global = true)]
+    pooling_allocator: Option<bool>
+}
+
+impl EngineArgs {
+    fn apply(self, settings: EngineSettings) -> EngineSettings {
+        EngineSettings {
+            wasm_backtraces: self.wasm_backtraces.unwrap_or(settings.wasm_backtraces),
+            simd: self.simd.unwrap_or(settings.simd),
+            cache: self.cache.unwrap_or(settings.cache),
+            cache_config: self.cache_config.or(settings.cache_config),
+            pooling_allocator: self.pooling_allocator.unwrap_or(settings.pooling_allocator),
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -29,13 +67,36 @@ enum Commands {
         command: ComponentSubcommand
     },
     #[command(arg_required_else_help = true)]
+    ApiGateway {
+        #[command(subcommand)]
+        command: ApiGatewaySubcommand
+    },
+    #[command(arg_required_else_help = true)]
+    Tenant {
+        #[command(subcommand)]
+        command: TenantSubcommand
+    },
+    #[command(arg_required_else_help = true)]
+    ApiKey {
+        #[command(subcommand)]
+        command: ApiKeySubcommand
+    },
+    #[command(arg_required_else_help = true)]
+    SecretGroup {
+        #[command(subcommand)]
+        command: SecretGroupSubcommand
+    },
+    #[command(arg_required_else_help = true)]
     Cloud {
         #[command(subcommand)]
         command: CloudSubcommand
     },
     UpdateCryptoKey {
         #[arg(short, long)]
-        path: String
+        path: String,
+        /// Check every secret decrypts and can be encrypted with the new key, without changing anything
+        #[arg(long)]
+        dry_run: bool
     },
 }
 
@@ -43,7 +104,28 @@ enum Commands {
 enum ServerSubcommand {
     Start {
         #[arg(short, long)]
-        port: String
+        port: u16,
+        /// IP address to listen on, or unix:{path} for a Unix socket
+        #[arg(short, long, default_value = "127.0.0.1")]
+        bind: String,
+        /// Seconds to wait for in-flight requests on shutdown
+        #[arg(short, long, default_value_t = 30)]
+        drain_timeout: u64,
+        /// PEM certificate chain, enables TLS together with --tls-key
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<String>,
+        /// PEM private key, enables TLS together with --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<String>,
+        /// Seconds a client has to finish the TLS handshake, overrides raikiri.yaml
+        #[arg(long)]
+        tls_handshake_timeout: Option<u64>,
+        /// Largest request body in bytes a component is sent, overrides raikiri.yaml
+        #[arg(long)]
+        max_request_body_size: Option<u64>,
+        /// Largest response body in bytes a component can stream back, overrides raikiri.yaml
+        #[arg(long)]
+        max_response_body_size: Option<u64>
     }
 }
 
@@ -63,11 +145,145 @@ enum ComponentSubcommand {
         #[arg(short, long)]
         conf: Option<String>,
     },
+    /// Show every stored component with its current revision
+    List,
+    /// Show the imports, exports and compilation details of a component
+    Inspect {
+        #[arg(short, long)]
+        name: String,
+    },
+    /// Make an earlier revision of a component the current one
+    Rollback {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        to: u32,
+    },
     UpdateSecret {
         #[arg(short, long)]
         component_name: String,
         #[arg(short, long)]
         secrets_path: String,
+    },
+    /// Set the visibility and allowed callers of a component from a YAML file
+    UpdateAccess {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        path: String,
+    },
+    #[command(arg_required_else_help = true)]
+    Secrets {
+        #[command(subcommand)]
+        command: SecretsSubcommand
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum SecretsSubcommand {
+    /// Show the names of the secrets of a component
+    List {
+        #[arg(short, long)]
+        name: String,
+        /// Also print the secret values
+        #[arg(long)]
+        show_values: bool
+    },
+    /// Add or change secrets of a component, keeping the others
+    Set {
+        #[arg(short, long)]
+        name: String,
+        /// KEY=VALUE pairs
+        #[arg(required_unless_present = "path")]
+        secrets: Vec<String>,
+        /// YAML file of secrets to merge
+        #[arg(short, long)]
+        path: Option<String>
+    },
+    /// Remove secrets of a component
+    Unset {
+        #[arg(short, long)]
+        name: String,
+        #[arg(required = true)]
+        keys: Vec<String>
+    },
+    /// Remove every secret of a component
+    Delete {
+        #[arg(short, long)]
+        name: String
+    },
+    /// Give a component the secrets of a group, its own secrets take precedence
+    Attach {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        group: String
+    },
+    Detach {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        group: String
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum SecretGroupSubcommand {
+    /// Replace the secrets of a group, such as db-prod, from a YAML file
+    Update {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long)]
+        path: String
+    },
+    List,
+    /// Remove a group no component is attached to
+    Delete {
+        #[arg(short, long)]
+        name: String
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum ApiGatewaySubcommand {
+    Create {
+        #[arg(short, long)]
+        path: String,
+        #[arg(short, long)]
+        version: String
+    },
+    Activate {
+        #[arg(short, long)]
+        version: String
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum TenantSubcommand {
+    Create {
+        #[arg(short, long)]
+        name: String
+    },
+    List
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum ApiKeySubcommand {
+    /// Create an api key for the admin commands of a server, it is only shown once
+    Create {
+        #[arg(short, long)]
+        tenant: String,
+        /// platform, admin or deploy
+        #[arg(short, long, default_value = "deploy")]
+        scope: ApiKeyScope
+    },
+    List {
+        #[arg(short, long)]
+        tenant: Option<String>
+    },
+    Revoke {
+        #[arg(short, long)]
+        id: String
     }
 }
 
@@ -92,14 +308,34 @@ enum CloudSubcommand {
 
 #[tokio::main]
 async fn main() -> Result<(), ThreadSafeError> {
-    let mut environment = RaikiriEnvironment::new();
+    let cli = Cli::parse();
+    let mut environment = RaikiriEnvironment::try_new()?;
+    let engine_settings = cli.engine.apply(environment.engine_settings.clone());
+    if engine_settings != environment.engine_settings {
+        environment = environment.with_engine_settings(engine_settings);
+    }
     environment.init().await?;
     environment.setup_fs().await?;
     let username = environment.username.clone();
-    match Cli::parse().command {
+    match cli.command {
         Commands::Server { command } => {
             match command {
-                ServerSubcommand::Start { port } => {
+                ServerSubcommand::Start { port, bind, drain_timeout, tls_cert, tls_key, tls_handshake_timeout, max_request_body_size, max_response_body_size } => {
+                    let bind_address = bind.parse::<BindAddress>()?;
+                    let body_limits = BodyLimits::new(
+                        max_request_body_size.or(environment.body_limits.max_request_body_size),
+                        max_response_body_size.or(environment.body_limits.max_response_body_size),
+                    );
+                    let mut environment = environment.with_port(port)
+                        .with_bind_address(bind_address)
+                        .with_drain_timeout(Duration::from_secs(drain_timeout))
+                        .with_body_limits(body_limits);
+                    if let (Some(tls_cert), Some(tls_key)) = (tls_cert, tls_key) {
+                        environment = environment.with_tls(Some(TlsConf::new(tls_cert, tls_key)));
+                    }
+                    if let (Some(timeout), Some(mut tls)) = (tls_handshake_timeout, environment.tls.clone()) {
+                        environment = environment.with_tls(Some(tls.with_handshake_timeout(Duration::from_secs(timeout))));
+                    }
                     println!("starting Raikiri server at port: {port}");
                     environment.run_server().await?;
                 }
@@ -110,8 +346,8 @@ async fn main() -> Result<(), ThreadSafeError> {
                 ComponentSubcommand::Add { name, path } => {
                     let username_component_name = format!("{username}.{name}");
                     let component_bytes = tokio::fs::read(path.clone()).await?;
-                    environment.add_component(username, name.clone(), component_bytes).await?;
-                    println!("Successfully added component {username_component_name}");
+                    let revision = environment.add_component(username, name.clone(), component_bytes).await?;
+                    println!("Successfully added component {username_component_name} revision {revision}");
                 },
                 ComponentSubcommand::Run { conf } => {
                     let conf_file = adapters::conf_file::ConfFile::build()?;
@@ -122,14 +358,39 @@ async fn main() -> Result<(), ThreadSafeError> {
                     let conf = conf_file.run_confs.get(&conf).unwrap();
                     let request = InvokeRequest::new(conf.component.clone(), conf.method.clone(), conf.headers.clone(), conf.body.as_bytes().to_vec());
                     let username_component_name = request.username_component_name.clone();
-                    let environment = RaikiriEnvironment::new();
-                    let component_imports = ComponentImports::default();
+                    // runs on the shared engine, so nested calls to raikiri.components reuse the same registry
+                    let component_imports = ComponentImports {
+                        call_stack: vec![username_component_name.clone()],
+                        environment: environment.clone(),
+                        db_connections: Default::default()
+                    };
                     let (username, component_name) = username_component_name.split_once('.').unwrap();
                     let secrets = environment.get_component_secrets(username.to_string(), component_name.to_string()).await?;
-                    let response = environment.invoke_component(username_component_name.clone(), request.into(), Wasi::new(component_imports, secrets)).await?;
+                    let request: hyper::Request<BoxBody<Bytes, hyper::Error>> = request.into();
+                    let response = environment.invoke_component(username_component_name.clone(), request.map(|body| body.map_err(hyper_request_error)), Wasi::new(component_imports, secrets)).await?;
                     println!("Successfully invoked {username_component_name}");
                     let resp_body = BodyExt::collect(response.resp.into_body()).await?.to_bytes().to_vec();
-                    println!("Response: {}", String::from_utf8(resp_body)?);
+                    println!("Response: {}", String::from_utf8_lossy(&resp_body));
+                },
+                ComponentSubcommand::List => {
+                    println!("{:<32} {:<16} {:>8} {:>12} {:<20} HASH", "NAME", "OWNER", "REVISION", "SIZE", "UPLOADED");
+                    for component in environment.list_components().await? {
+                        if let Some(error) = component.error {
+                            println!("{:<32} {:<16} error: {error}", component.name, component.owner);
+                            continue
+                        }
+                        println!("{:<32} {:<16} {:>8} {:>12} {:<20} {}",
+                            component.name, component.owner, component.revision, component.size, component.uploaded_at, component.hash);
+                    }
+                },
+                ComponentSubcommand::Inspect { name } => {
+                    let inspection = environment.inspect_component(username, name).await?;
+                    println!("{}", serde_json::to_string_pretty(&inspection)?);
+                },
+                ComponentSubcommand::Rollback { name, to } => {
+                    let username_component_name = format!("{username}.{name}");
+                    environment.set_current_revision(username, name, to).await?;
+                    println!("Successfully rolled back component {username_component_name} to revision {to}");
                 },
                 ComponentSubcommand::Remove { name } => {
                     let username_component_name = format!("{username}.{name}");
@@ -142,13 +403,140 @@ async fn main() -> Result<(), ThreadSafeError> {
                     let secrets_content = tokio::fs::read(secrets_path).await?;
                     environment.update_component_secrets(username, component_name, secrets_content).await?;
                     println!("Successfully updated secret for component {username_component_name}");
+                },
+                ComponentSubcommand::UpdateAccess { name, path } => {
+                    let username_component_name = format!("{username}.{name}");
+                    let access_content = tokio::fs::read(path).await?;
+                    environment.update_component_access(username, name, access_content).await?;
+                    println!("Successfully updated access for component {username_component_name}");
+                },
+                ComponentSubcommand::Secrets { command } => match command {
+                    SecretsSubcommand::List { name, show_values } => {
+                        let secrets = environment.list_component_secrets(username, name, show_values).await?;
+                        match show_values {
+                            true => println!("{:<32} {:>8} VALUE", "KEY", "SIZE"),
+                            false => println!("{:<32} {:>8}", "KEY", "SIZE"),
+                        }
+                        for secret in secrets {
+                            match secret.value {
+                                Some(value) => println!("{:<32} {:>8} {value}", secret.key, secret.size),
+                                None => println!("{:<32} {:>8}", secret.key, secret.size),
+                            }
+                        }
+                    },
+                    SecretsSubcommand::Set { name, secrets, path } => {
+                        let username_component_name = format!("{username}.{name}");
+                        let mut pairs = match path {
+                            Some(path) => parse_secrets(&tokio::fs::read(path).await?)?,
+                            None => Vec::new(),
+                        };
+                        for secret in secrets {
+                            let (key, value) = secret.split_once('=').ok_or(format!("expected KEY=VALUE, got {secret}"))?;
+                            pairs.push((key.to_string(), value.to_string()));
+                        }
+                        let keys = pairs.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>().join(", ");
+                        environment.set_component_secrets(username, name, pairs).await?;
+                        println!("Successfully set secrets {keys} for component {username_component_name}");
+                    },
+                    SecretsSubcommand::Unset { name, keys } => {
+                        let username_component_name = format!("{username}.{name}");
+                        let removed = environment.unset_component_secrets(username, name, keys).await?;
+                        match removed.is_empty() {
+                            true => println!("Component {username_component_name} has none of these secrets"),
+                            false => println!("Successfully unset secrets {} for component {username_component_name}", removed.join(", ")),
+                        }
+                    },
+                    SecretsSubcommand::Delete { name } => {
+                        let username_component_name = format!("{username}.{name}");
+                        environment.remove_component_secrets(username, name).await?;
+                        println!("Successfully deleted secrets for component {username_component_name}");
+                    },
+                    SecretsSubcommand::Attach { name, group } => {
+                        let username_component_name = format!("{username}.{name}");
+                        environment.attach_secret_group(username, name, group.clone()).await?;
+                        println!("Successfully attached secret group {group} to component {username_component_name}");
+                    },
+                    SecretsSubcommand::Detach { name, group } => {
+                        let username_component_name = format!("{username}.{name}");
+                        environment.detach_secret_group(username, name, group.clone()).await?;
+                        println!("Successfully detached secret group {group} from component {username_component_name}");
+                    }
+                }
+            }
+        },
+        Commands::ApiGateway { command } => {
+            match command {
+                ApiGatewaySubcommand::Create { path, version } => {
+                    let yml_bytes = tokio::fs::read(path).await?;
+                    let version = version.parse::<i32>()?;
+                    environment.put_api_gateway(version, yml_bytes).await?;
+                    environment.activate_api_gateway(version).await?;
+                    println!("Successfully created api gateway version {version}");
+                },
+                ApiGatewaySubcommand::Activate { version } => {
+                    let version = version.parse::<i32>()?;
+                    environment.activate_api_gateway(version).await?;
+                    println!("Successfully activated api gateway version {version}");
+                }
+            }
+        },
+        Commands::Tenant { command } => {
+            match command {
+                TenantSubcommand::Create { name } => {
+                    environment.create_tenant(name.clone()).await?;
+                    println!("Successfully created tenant {name}");
+                },
+                TenantSubcommand::List => {
+                    for tenant in environment.list_tenants().await? {
+                        println!("{tenant}");
+                    }
+                }
+            }
+        },
+        Commands::ApiKey { command } => {
+            match command {
+                ApiKeySubcommand::Create { tenant, scope } => {
+                    let (api_key, key) = environment.create_api_key(tenant, scope).await?;
+                    println!("Successfully created {scope} api key {} for tenant {}", api_key.id, api_key.tenant);
+                    println!("{key}");
+                },
+                ApiKeySubcommand::List { tenant } => {
+                    println!("{:<14} {:<16} {:<8} CREATED", "ID", "TENANT", "SCOPE");
+                    for api_key in environment.list_api_keys(tenant).await? {
+                        println!("{:<14} {:<16} {:<8} {}", api_key.id, api_key.tenant, api_key.scope.to_string(), api_key.created_at);
+                    }
+                },
+                ApiKeySubcommand::Revoke { id } => {
+                    environment.revoke_api_key(id.clone()).await?;
+                    println!("Successfully revoked api key {id}");
+                }
+            }
+        },
+        Commands::SecretGroup { command } => {
+            match command {
+                SecretGroupSubcommand::Update { name, path } => {
+                    let secrets_content = tokio::fs::read(path).await?;
+                    environment.update_secret_group(username, name.clone(), secrets_content).await?;
+                    println!("Successfully updated secret group {name}");
+                },
+                SecretGroupSubcommand::List => {
+                    for group in environment.list_secret_groups(username.clone()).await? {
+                        println!("{username}.{group}");
+                    }
+                },
+                SecretGroupSubcommand::Delete { name } => {
+                    environment.remove_secret_group(username, name.clone()).await?;
+                    println!("Successfully deleted secret group {name}");
                 }
             }
         },
-        Commands::UpdateCryptoKey { path } => {
+        Commands::UpdateCryptoKey { path, dry_run } => {
             let key_bytes = tokio::fs::read(path).await?;
-            environment.update_crypto_key(username, key_bytes).await?;
-            println!("Successfully updated crypto key");
+            let secrets = environment.update_crypto_key(username, key_bytes, dry_run).await?;
+            match dry_run {
+                true => println!("All {secrets} secrets can be encrypted with the new key, nothing was changed"),
+                false => println!("Successfully updated crypto key, {secrets} secrets were encrypted again"),
+            }
         },
         Commands::Cloud { command } => {
             match command {
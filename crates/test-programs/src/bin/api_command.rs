@@ -0,0 +1,4 @@
+// A command component, it exports wasi:cli/run instead of wasi:http/incoming-handler
+fn main() {
+    println!("Hello from a command");
+}
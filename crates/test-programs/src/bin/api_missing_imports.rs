@@ -0,0 +1,24 @@
+wit_bindgen::generate!({
+    inline: "
+        package raikiri:missing;
+
+        interface greeter {
+            greet: func() -> string;
+        }
+
+        interface counter {
+            next: func() -> u32;
+        }
+
+        world missing {
+            import greeter;
+            import counter;
+        }
+    ",
+    world: "raikiri:missing/missing",
+});
+
+// Imports interfaces no host provides
+fn main() {
+    println!("{} {}", raikiri::missing::greeter::greet(), raikiri::missing::counter::next());
+}
@@ -0,0 +1,39 @@
+use test_programs::wasi::{http::types::{
+    Headers, IncomingRequest, OutgoingBody, OutgoingResponse, ResponseOutparam,
+}, io::streams::StreamError};
+
+struct T;
+
+test_programs::proxy::export!(T);
+
+// Writes every chunk of the request body back as soon as it is read
+impl test_programs::proxy::exports::wasi::http::incoming_handler::Guest for T {
+    fn handle(request: IncomingRequest, outparam: ResponseOutparam) {
+        let resp = OutgoingResponse::new(Headers::new());
+        let body = resp.body().expect("outgoing response");
+        ResponseOutparam::set(outparam, Ok(resp));
+
+        let incoming = request.consume().expect("incoming body");
+        let input = incoming.stream().expect("incoming stream");
+        let out = body.write().expect("outgoing stream");
+        loop {
+            match input.blocking_read(4096) {
+                Ok(chunk) => {
+                    if out.blocking_write_and_flush(&chunk).is_err() {
+                        break
+                    }
+                }
+                Err(StreamError::Closed) => break,
+                Err(StreamError::LastOperationFailed(e)) => panic!("{}", e.to_debug_string()),
+            }
+        }
+
+        drop(out);
+        drop(input);
+        OutgoingBody::finish(body, None).expect("outgoing-body.finish");
+    }
+}
+
+// Technically this should not be here for a proxy, but given the current
+// framework for tests it's required since this file is built as a `bin`
+fn main() {}
@@ -0,0 +1,35 @@
+use test_programs::wasi::http::types::{
+    Headers, IncomingRequest, OutgoingBody, OutgoingResponse, ResponseOutparam,
+};
+
+struct T;
+
+test_programs::proxy::export!(T);
+
+// /spin never returns and /alloc grows the memory by 64MiB, anything else answers right away
+impl test_programs::proxy::exports::wasi::http::incoming_handler::Guest for T {
+    fn handle(request: IncomingRequest, outparam: ResponseOutparam) {
+        match request.path_with_query().as_deref() {
+            Some("/spin") => {
+                let mut i: u64 = 0;
+                loop {
+                    i = std::hint::black_box(i.wrapping_add(1));
+                }
+            }
+            Some("/alloc") => {
+                let memory = vec![1u8; 64 << 20];
+                std::hint::black_box(&memory);
+            }
+            _ => (),
+        }
+
+        let resp = OutgoingResponse::new(Headers::new());
+        let body = resp.body().expect("outgoing response");
+        ResponseOutparam::set(outparam, Ok(resp));
+        OutgoingBody::finish(body, None).expect("outgoing-body.finish");
+    }
+}
+
+// Technically this should not be here for a proxy, but given the current
+// framework for tests it's required since this file is built as a `bin`
+fn main() {}
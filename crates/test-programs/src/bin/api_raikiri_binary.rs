@@ -0,0 +1,14 @@
+use waki::{handler, ErrorCode, Request, Response};
+
+#[handler]
+fn binary(_req: Request) -> Result<Response, ErrorCode> {
+    Response::builder()
+        .status_code(201)
+        .header("Content-Type", "application/octet-stream")
+        .header("Set-Cookie", "session=raikiri")
+        .header("Location", "/files/1")
+        .body([0x00, 0xff, 0xfe, 0x80, 0x7f].to_vec())
+        .build()
+}
+
+fn main() {}
@@ -0,0 +1,25 @@
+use waki::{handler, Client, ErrorCode, Request, Response};
+
+// Calls the component named in the Relay-To header through raikiri.components
+// and answers with its status, headers and body.
+#[handler]
+fn relay(req: Request) -> Result<Response, ErrorCode> {
+    let target = req.header("Relay-To")
+        .and_then(|target| target.to_str().ok())
+        .unwrap_or("test.binary")
+        .to_string();
+
+    let response = Client::new()
+        .get(&format!("http://raikiri.components/{target}"))
+        .send()
+        .map_err(|e| ErrorCode::InternalError(Some(e.to_string())))?;
+
+    let mut builder = Response::builder().status_code(response.status_code());
+    for (key, value) in response.headers() {
+        builder = builder.header(key, value);
+    }
+    let body = response.body().map_err(|e| ErrorCode::InternalError(Some(e.to_string())))?;
+    builder.body(body).build()
+}
+
+fn main() {}